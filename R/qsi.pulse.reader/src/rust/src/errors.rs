@@ -0,0 +1,57 @@
+use extendr_api::prelude::*;
+use extendr_api::SEXP;
+use qsi_pulse_reader::pulse_reader::error::PulseError;
+
+extern "C" {
+    fn Rf_protect(s: SEXP) -> SEXP;
+    fn Rf_eval(expr: SEXP, env: SEXP) -> SEXP;
+}
+
+/// The R condition class matching an error from the Rust library, if it has one
+fn condition_class(err: &anyhow::Error) -> Option<&'static str> {
+    err.downcast_ref::<PulseError>().map(|e| match e {
+        PulseError::BadMagic { .. } => "qsi_bad_magic",
+        PulseError::UnsupportedEncoding { .. } => "qsi_unsupported_encoding",
+        PulseError::IndexMagicMismatch { .. } => "qsi_index_magic_mismatch",
+        PulseError::ApertureNotFound { .. } => "qsi_aperture_not_found",
+        PulseError::Truncated { .. } => "qsi_truncated_file",
+        PulseError::MetadataField { .. } => "qsi_metadata_field",
+        PulseError::InvalidMetadata { .. } => "qsi_invalid_metadata",
+        PulseError::InvalidArgument { .. } => "qsi_invalid_argument",
+        PulseError::IncompatibleInputs { .. } => "qsi_incompatible_inputs",
+        PulseError::Io(_) => "qsi_io_error",
+    })
+}
+
+/// Signal an error from the Rust library as an R condition
+///
+/// Errors that originate from a `PulseError` are signalled as conditions inheriting from
+/// the matching class and from `qsi_pulse_error`, so that they can be handled with
+/// `tryCatch()`, e.g. `tryCatch(reader$get_pulses(0), qsi_aperture_not_found = ...)`.
+/// Other errors are signalled as plain R errors.
+///
+/// Like `throw_r_error`, this never returns: R unwinds straight past the calling Rust
+/// frames, so it must only be called from the R main thread, and values owned by the
+/// callers are not dropped. The return type only lets it be used with `map_err`.
+pub(crate) fn to_r_error(err: anyhow::Error) -> Error {
+    let message = format!("{:#}", err);
+    let class = condition_class(&err);
+    drop(err);
+    let Some(class) = class else {
+        throw_r_error(&message);
+    };
+
+    let mut condition = list!(message = message.as_str(), call = ());
+    if let Err(err) = condition.set_class([class, "qsi_pulse_error", "error", "condition"]) {
+        throw_r_error(err.to_string());
+    }
+    let stop = lang!("stop", condition);
+    unsafe {
+        // R resets its protect stack as it unwinds, so the call is released by the error
+        let call = Rf_protect(stop.get());
+        drop(stop);
+        drop(message);
+        Rf_eval(call, base_env().get());
+    }
+    unreachable!("stop() returned without signalling an error")
+}
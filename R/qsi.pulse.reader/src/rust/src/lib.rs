@@ -3,6 +3,7 @@
 //! Provides the following functionality:
 //! - `PulseFile`, a pulses.bin reader
 
+mod errors;
 mod pulse_reader;
 mod records;
 
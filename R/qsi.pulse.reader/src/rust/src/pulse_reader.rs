@@ -1,6 +1,12 @@
-use crate::records::{FormattedRecordR, NormalizedPulseR};
+use crate::errors::to_r_error;
+use crate::records::{ApertureHeaderR, ApertureSummaryR, FormattedRecordR, NormalizedPulseR};
 use extendr_api::prelude::*;
+use qsi_pulse_reader::pulse_filter::PulseFilter;
 use qsi_pulse_reader::pulse_reader::headers::ApertureHeader;
+use qsi_pulse_reader::pulse_reader::sample::{
+    ApertureSample, PulseSample, SampleSize, SamplingStrategy,
+};
+use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
 use qsi_pulse_reader::pulse_reader::PulseReader as RustPulseReader;
 
 /// Pulses.bin reader
@@ -29,6 +35,93 @@ impl PulseReader {
     }
 }
 
+/// Build a pulse filter from the filter arguments, or `None` if none of them are given
+#[allow(clippy::too_many_arguments)]
+fn pulse_filter(
+    min_dur_f: Nullable<u32>,
+    min_dur_s: Nullable<f32>,
+    max_dur_s: Nullable<f32>,
+    min_snr: Nullable<f32>,
+    min_intensity: Nullable<f32>,
+    min_binratio: Nullable<f32>,
+    max_binratio: Nullable<f32>,
+    start_m: Nullable<f32>,
+    end_m: Nullable<f32>,
+    mask_s: Nullable<Vec<f32>>,
+    recalc_ipd: bool,
+) -> Result<Option<PulseFilter>> {
+    let mask_s = match Option::from(mask_s) {
+        Some(mask_s) => match mask_s[..] {
+            [start, end] => Some((start, end)),
+            _ => return Err("mask_s must be a vector of a start and an end time".into()),
+        },
+        None => None,
+    };
+    let pulse_filter = PulseFilter::new(
+        min_dur_f.into(),
+        min_dur_s.into(),
+        max_dur_s.into(),
+        min_snr.into(),
+        min_intensity.into(),
+        min_binratio.into(),
+        max_binratio.into(),
+        start_m.into(),
+        end_m.into(),
+        mask_s,
+        recalc_ipd,
+    );
+    let is_set = pulse_filter.min_dur_f.is_some()
+        || pulse_filter.min_dur_s.is_some()
+        || pulse_filter.max_dur_s.is_some()
+        || pulse_filter.min_snr.is_some()
+        || pulse_filter.min_intensity.is_some()
+        || pulse_filter.min_binratio.is_some()
+        || pulse_filter.max_binratio.is_some()
+        || pulse_filter.start_m.is_some()
+        || pulse_filter.end_m.is_some()
+        || pulse_filter.mask_s.is_some()
+        || pulse_filter.recalc_ipd;
+    Ok(is_set.then_some(pulse_filter))
+}
+
+/// The largest seed that an R number can hold exactly, 2^53
+const MAX_SEED: f64 = 9_007_199_254_740_992.0;
+
+/// Convert a seed given from R to the seed of the random number generator
+///
+/// The seed is taken as a double, since R has no 64-bit integers, so it must be a whole
+/// number from 0 to 2^53. Larger seeds would be silently rounded to a different seed.
+fn seed_from_r(seed: f64) -> Result<u64> {
+    if !(0.0..=MAX_SEED).contains(&seed) || seed.fract() != 0.0 {
+        return Err(format!("seed must be a whole number from 0 to 2^53, got {}", seed).into());
+    }
+    Ok(seed as u64)
+}
+
+/// Build an aperture sample from the arguments of the sampling methods
+fn aperture_sample(
+    n: Nullable<usize>,
+    fraction: Nullable<f64>,
+    seed: f64,
+    num_strata: Nullable<usize>,
+) -> Result<ApertureSample> {
+    let seed = seed_from_r(seed)?;
+    let size = match (Option::from(n), Option::from(fraction)) {
+        (Some(n), None) => SampleSize::Count(n),
+        (None, Some(fraction)) => SampleSize::Fraction(fraction),
+        _ => return Err("Exactly one of n or fraction must be given".into()),
+    };
+    let strategy = match Option::from(num_strata) {
+        Some(num_strata) => SamplingStrategy::Stratified { num_strata },
+        None => SamplingStrategy::Uniform,
+    };
+    Ok(ApertureSample {
+        size,
+        strategy,
+        seed,
+    })
+}
+
 #[extendr]
 impl PulseReader {
     /// Open a pulses.bin file and return a PulseReader object
@@ -49,7 +142,7 @@ impl PulseReader {
     /// ```
     pub(crate) fn new(file_name: &str) -> Result<Self> {
         let pulse_reader =
-            RustPulseReader::open(file_name.to_string()).map_err(|e| e.to_string())?;
+            RustPulseReader::open(file_name.to_string()).map_err(to_r_error)?;
         let source = "pulses.bin".to_string();
         let analysis_id = file_name
             .split('.')
@@ -57,8 +150,9 @@ impl PulseReader {
             .ok_or_else(|| "Invalid file name".to_string())?
             .to_string();
         let frame_dur_s = 1.0 / pulse_reader.fps;
-        let run_dur_s = pulse_reader.metadata["duration"]
-            .as_f64()
+        let run_dur_s = pulse_reader
+            .run_metadata
+            .duration
             .ok_or_else(|| "Missing or invalid 'duration' field in metadata".to_string())?
             as f32;
         let run_dur_f = (run_dur_s * pulse_reader.fps).ceil() as u64;
@@ -87,13 +181,13 @@ impl PulseReader {
     /// records <- reader$get_records(12345)
     /// ```
     pub(crate) fn get_all_records(
-        &mut self,
+        &self,
         aperture_index: usize,
     ) -> Result<Dataframe<FormattedRecordR>> {
         let (records, header) = self
             .pulse_reader
             .get_all_records(aperture_index)
-            .map_err(|e| e.to_string())?;
+            .map_err(to_r_error)?;
         let r_records = records
             .iter()
             .map(|record| FormattedRecordR::from_record(record))
@@ -117,13 +211,13 @@ impl PulseReader {
     /// pulses <- reader$get_normalized_pulses(12345)
     /// ```
     pub(crate) fn get_pulses(
-        &mut self,
+        &self,
         aperture_index: usize,
     ) -> Result<Dataframe<NormalizedPulseR>> {
         let (records, header) = self
             .pulse_reader
             .get_pulses(aperture_index, None)
-            .map_err(|e| e.to_string())?;
+            .map_err(to_r_error)?;
         let r_records = records
             .iter()
             .map(|record| NormalizedPulseR::from_pulse(record))
@@ -132,6 +226,210 @@ impl PulseReader {
         self.set_df_attributes(&mut df, &header)?;
         Ok(df)
     }
+
+    /// Get the header of every aperture in the file, without reading their records
+    ///
+    /// This is much faster than reading the records of every aperture, and is useful for
+    /// choosing which apertures to analyze.
+    ///
+    /// # Returns
+    /// A Data Frame with one row per aperture, in index order, and the columns
+    /// `aperture_index`, `x`, `y`, `well_id`, `num_pulses` and `byte_loc`
+    ///
+    /// # Examples
+    /// ```R
+    /// reader <- PulseReader$new("pulses.bin")
+    /// headers <- reader$aperture_headers()
+    /// busiest <- head(headers[order(-headers$num_pulses), "aperture_index"], 100)
+    /// ```
+    pub(crate) fn aperture_headers(&self) -> Result<Dataframe<ApertureHeaderR>> {
+        let headers = self.pulse_reader.aperture_headers().map_err(to_r_error)?;
+        let r_headers = headers
+            .iter()
+            .map(|(aperture_index, header)| ApertureHeaderR::from_header(aperture_index, &header))
+            .collect::<Vec<_>>();
+        Dataframe::try_from_values(r_headers)
+    }
+
+    /// Compute summary statistics of the pulses and records of each aperture
+    ///
+    /// The statistics of every aperture in the file are computed in a single pass over the
+    /// file, unless `aperture_index` is given. Statistics of an aperture without pulses
+    /// are NaN.
+    ///
+    /// # Arguments
+    /// * `aperture_index` - Optional index of a single aperture to summarize
+    /// * `min_dur_f`, `min_dur_s`, `max_dur_s`, `min_snr`, `min_intensity`,
+    ///   `min_binratio`, `max_binratio`, `start_m`, `end_m`, `mask_s`, `recalc_ipd` -
+    ///   Optional pulse filter settings. Pulses that fail the filter are left out of the
+    ///   pulse statistics, but not the record counts. `mask_s` is a vector of the start
+    ///   and end of a time window to leave out, in seconds
+    ///
+    /// # Returns
+    /// A Data Frame with one row per aperture, in index order, with the pulse count and
+    /// rate, duration and interpulse duration statistics, intensity and binratio
+    /// quantiles, median SNR, first and last pulse times, on-time fraction, and the number
+    /// of records of each type
+    ///
+    /// # Examples
+    /// ```R
+    /// reader <- PulseReader$new("pulses.bin")
+    /// summary <- reader$summarize()
+    /// active <- summary[summary$pulse_rate > 0.1, ]
+    /// long_pulses <- reader$summarize(min_dur_f = 3)
+    /// ```
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn summarize(
+        &self,
+        #[default = "NULL"] aperture_index: Nullable<usize>,
+        #[default = "NULL"] min_dur_f: Nullable<u32>,
+        #[default = "NULL"] min_dur_s: Nullable<f32>,
+        #[default = "NULL"] max_dur_s: Nullable<f32>,
+        #[default = "NULL"] min_snr: Nullable<f32>,
+        #[default = "NULL"] min_intensity: Nullable<f32>,
+        #[default = "NULL"] min_binratio: Nullable<f32>,
+        #[default = "NULL"] max_binratio: Nullable<f32>,
+        #[default = "NULL"] start_m: Nullable<f32>,
+        #[default = "NULL"] end_m: Nullable<f32>,
+        #[default = "NULL"] mask_s: Nullable<Vec<f32>>,
+        #[default = "FALSE"] recalc_ipd: bool,
+    ) -> Result<Dataframe<ApertureSummaryR>> {
+        let pulse_filter = pulse_filter(
+            min_dur_f,
+            min_dur_s,
+            max_dur_s,
+            min_snr,
+            min_intensity,
+            min_binratio,
+            max_binratio,
+            start_m,
+            end_m,
+            mask_s,
+            recalc_ipd,
+        )?;
+        let summaries = match Option::from(aperture_index) {
+            Some(ap) => self
+                .pulse_reader
+                .summarize_aperture(ap, pulse_filter.as_ref())
+                .map(|summary| vec![summary]),
+            None => self.pulse_reader.summarize_apertures(pulse_filter.as_ref()),
+        }
+        .map_err(to_r_error)?;
+        let r_summaries = summaries
+            .iter()
+            .map(ApertureSummaryR::from_summary)
+            .collect::<Vec<_>>();
+        Dataframe::try_from_values(r_summaries)
+    }
+
+    /// Choose a reproducible random sample of the apertures in the file
+    ///
+    /// Exactly one of `n` or `fraction` must be given. The same seed always gives the same
+    /// sample from the same file, in R, Python and Rust.
+    ///
+    /// # Arguments
+    /// * `n` - The number of apertures to sample, at least 1
+    /// * `fraction` - The fraction of the apertures to sample, between 0 and 1, which must
+    ///   round to at least one aperture
+    /// * `seed` - The seed of the random number generator, a whole number from 0 to 2^53
+    /// * `num_strata` - If given, the apertures are divided into this many groups at the
+    ///   quantiles of their number of records, and the sample is split as evenly as
+    ///   possible between the groups, so that the most active apertures are represented
+    ///
+    /// # Returns
+    /// A vector of the sampled aperture indices, in index order
+    ///
+    /// # Examples
+    /// ```R
+    /// reader <- PulseReader$new("pulses.bin")
+    /// apertures <- reader$sample_apertures(fraction = 0.01, seed = 42, num_strata = 10)
+    /// ```
+    pub(crate) fn sample_apertures(
+        &self,
+        #[default = "NULL"] n: Nullable<usize>,
+        #[default = "NULL"] fraction: Nullable<f64>,
+        #[default = "0"] seed: f64,
+        #[default = "NULL"] num_strata: Nullable<usize>,
+    ) -> Result<Vec<usize>> {
+        let sample = aperture_sample(n, fraction, seed, num_strata)?;
+        Ok(self
+            .pulse_reader
+            .sample_apertures(&sample)
+            .map_err(to_r_error)?)
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the apertures
+    ///
+    /// The apertures are chosen as by `sample_apertures`, and the sample is recorded in
+    /// the "apertureSample" field of the metadata of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The path to the new pulses.bin file
+    /// * `n` - The number of apertures to sample, at least 1
+    /// * `fraction` - The fraction of the apertures to sample, between 0 and 1, which must
+    ///   round to at least one aperture
+    /// * `seed` - The seed of the random number generator, a whole number from 0 to 2^53
+    /// * `num_strata` - If given, the number of groups to sample from by activity
+    /// * `overwrite` - Whether to replace the new file if it already exists
+    ///
+    /// # Returns
+    /// A vector of the sampled aperture indices, in index order
+    ///
+    /// # Examples
+    /// ```R
+    /// reader <- PulseReader$new("pulses.bin")
+    /// reader$write_sampled_apertures("sampled_pulses.bin", n = 1000, seed = 42)
+    /// ```
+    pub(crate) fn write_sampled_apertures(
+        &self,
+        file_name: &str,
+        #[default = "NULL"] n: Nullable<usize>,
+        #[default = "NULL"] fraction: Nullable<f64>,
+        #[default = "0"] seed: f64,
+        #[default = "NULL"] num_strata: Nullable<usize>,
+        #[default = "FALSE"] overwrite: bool,
+    ) -> Result<Vec<usize>> {
+        let sample = aperture_sample(n, fraction, seed, num_strata)?;
+        Ok(self
+            .pulse_reader
+            .write_sampled_apertures(&sample, file_name, OverwritePolicy::from_bool(overwrite))
+            .map_err(to_r_error)?)
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the pulses
+    ///
+    /// Every aperture is copied, keeping each of its pulses with probability `fraction`.
+    /// Non-pulse records are kept, and the remaining records keep their original start
+    /// frames. The same seed always keeps the same pulses, in R, Python and Rust, and the
+    /// sample is recorded in the "pulseSample" field of the metadata of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The path to the new pulses.bin file
+    /// * `fraction` - The probability of keeping each pulse, between 0 and 1
+    /// * `seed` - The seed of the random number generator, a whole number from 0 to 2^53
+    /// * `overwrite` - Whether to replace the new file if it already exists
+    ///
+    /// # Examples
+    /// ```R
+    /// reader <- PulseReader$new("pulses.bin")
+    /// reader$write_sampled_pulses("sampled_pulses.bin", fraction = 0.1, seed = 42)
+    /// ```
+    pub(crate) fn write_sampled_pulses(
+        &self,
+        file_name: &str,
+        fraction: f64,
+        #[default = "0"] seed: f64,
+        #[default = "FALSE"] overwrite: bool,
+    ) -> Result<()> {
+        let sample = PulseSample {
+            fraction,
+            seed: seed_from_r(seed)?,
+        };
+        self.pulse_reader
+            .write_sampled_pulses(&sample, file_name, OverwritePolicy::from_bool(overwrite))
+            .map_err(to_r_error)?;
+        Ok(())
+    }
 }
 
 extendr_module! {
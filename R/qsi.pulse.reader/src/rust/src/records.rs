@@ -1,5 +1,7 @@
 use extendr_api::prelude::*;
+use qsi_pulse_reader::pulse_reader::headers::ApertureHeader;
 use qsi_pulse_reader::pulse_reader::records::{FormattedRecord, NormalizedPulse};
+use qsi_pulse_reader::pulse_reader::summary::ApertureSummary;
 
 #[derive(IntoDataFrameRow)]
 pub(crate) struct FormattedRecordR {
@@ -78,3 +80,91 @@ impl NormalizedPulseR {
         }
     }
 }
+
+#[derive(IntoDataFrameRow)]
+pub(crate) struct ApertureHeaderR {
+    aperture_index: usize,
+    x: u32,
+    y: u32,
+    well_id: u32,
+    num_pulses: u32,
+    byte_loc: u64,
+}
+
+impl ApertureHeaderR {
+    pub(crate) fn from_header(aperture_index: usize, header: &ApertureHeader) -> Self {
+        ApertureHeaderR {
+            aperture_index,
+            x: header.x,
+            y: header.y,
+            well_id: header.well_id,
+            num_pulses: header.num_pulses,
+            byte_loc: header.byte_loc,
+        }
+    }
+}
+
+#[derive(IntoDataFrameRow)]
+pub(crate) struct ApertureSummaryR {
+    aperture_index: usize,
+    x: u32,
+    y: u32,
+    num_pulses: usize,
+    pulse_rate: f64,
+    mean_dur_s: f64,
+    median_dur_s: f64,
+    mean_ipd_s: f64,
+    median_ipd_s: f64,
+    intensity_q10: f64,
+    intensity_q50: f64,
+    intensity_q90: f64,
+    binratio_q10: f64,
+    binratio_q50: f64,
+    binratio_q90: f64,
+    median_snr: f64,
+    first_pulse_s: f64,
+    last_pulse_s: f64,
+    on_time_fraction: f64,
+    num_pulse_records: usize,
+    num_padding_records: usize,
+    num_long_pulse_update_records: usize,
+    num_long_pulse_dropped_records: usize,
+    num_step_up_records: usize,
+    num_step_down_records: usize,
+    num_background_records: usize,
+    num_unknown_records: usize,
+}
+
+impl ApertureSummaryR {
+    pub(crate) fn from_summary(summary: &ApertureSummary) -> Self {
+        ApertureSummaryR {
+            aperture_index: summary.aperture_index,
+            x: summary.x,
+            y: summary.y,
+            num_pulses: summary.num_pulses,
+            pulse_rate: summary.pulse_rate,
+            mean_dur_s: summary.mean_dur_s,
+            median_dur_s: summary.median_dur_s,
+            mean_ipd_s: summary.mean_ipd_s,
+            median_ipd_s: summary.median_ipd_s,
+            intensity_q10: summary.intensity_q10,
+            intensity_q50: summary.intensity_q50,
+            intensity_q90: summary.intensity_q90,
+            binratio_q10: summary.binratio_q10,
+            binratio_q50: summary.binratio_q50,
+            binratio_q90: summary.binratio_q90,
+            median_snr: summary.median_snr,
+            first_pulse_s: summary.first_pulse_s,
+            last_pulse_s: summary.last_pulse_s,
+            on_time_fraction: summary.on_time_fraction,
+            num_pulse_records: summary.num_pulse_records,
+            num_padding_records: summary.num_padding_records,
+            num_long_pulse_update_records: summary.num_long_pulse_update_records,
+            num_long_pulse_dropped_records: summary.num_long_pulse_dropped_records,
+            num_step_up_records: summary.num_step_up_records,
+            num_step_down_records: summary.num_step_down_records,
+            num_background_records: summary.num_background_records,
+            num_unknown_records: summary.num_unknown_records,
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use qsi_pulse_reader::pulse_reader::error::PulseError as RustPulseError;
+
+create_exception!(
+    qsi_pulse_reader,
+    PulseError,
+    PyRuntimeError,
+    "Base class for errors raised while reading or writing pulses.bin files"
+);
+create_exception!(
+    qsi_pulse_reader,
+    BadMagicError,
+    PulseError,
+    "The file is not a pulses.bin file"
+);
+create_exception!(
+    qsi_pulse_reader,
+    UnsupportedEncodingError,
+    PulseError,
+    "The file uses a record encoding that cannot be parsed"
+);
+create_exception!(
+    qsi_pulse_reader,
+    IndexMagicMismatchError,
+    PulseError,
+    "The aperture index of the file is corrupt"
+);
+create_exception!(
+    qsi_pulse_reader,
+    ApertureNotFoundError,
+    PulseError,
+    "The requested aperture index is not contained in the file"
+);
+create_exception!(
+    qsi_pulse_reader,
+    TruncatedFileError,
+    PulseError,
+    "The file ended unexpectedly"
+);
+create_exception!(
+    qsi_pulse_reader,
+    MetadataFieldError,
+    PulseError,
+    "A required metadata field is missing or invalid"
+);
+create_exception!(
+    qsi_pulse_reader,
+    InvalidMetadataError,
+    PulseError,
+    "The metadata of the file is not valid JSON"
+);
+create_exception!(
+    qsi_pulse_reader,
+    IncompatibleInputsError,
+    PulseError,
+    "The pulses.bin files passed to a merge cannot be merged"
+);
+create_exception!(
+    qsi_pulse_reader,
+    PulseIOError,
+    PulseError,
+    "An I/O error occurred while reading or writing a pulses.bin file"
+);
+
+/// Convert an error from the Rust library into the matching Python exception
+///
+/// Invalid arguments are raised as `ValueError`, and errors that do not originate from a
+/// `PulseError` are raised as `RuntimeError`.
+pub(crate) fn to_py_err(err: anyhow::Error) -> PyErr {
+    let msg = format!("{:#}", err);
+    match err.downcast_ref::<RustPulseError>() {
+        Some(RustPulseError::BadMagic { .. }) => BadMagicError::new_err(msg),
+        Some(RustPulseError::UnsupportedEncoding { .. }) => UnsupportedEncodingError::new_err(msg),
+        Some(RustPulseError::IndexMagicMismatch { .. }) => IndexMagicMismatchError::new_err(msg),
+        Some(RustPulseError::ApertureNotFound { .. }) => ApertureNotFoundError::new_err(msg),
+        Some(RustPulseError::Truncated { .. }) => TruncatedFileError::new_err(msg),
+        Some(RustPulseError::MetadataField { .. }) => MetadataFieldError::new_err(msg),
+        Some(RustPulseError::InvalidMetadata { .. }) => InvalidMetadataError::new_err(msg),
+        Some(RustPulseError::InvalidArgument { .. }) => PyValueError::new_err(msg),
+        Some(RustPulseError::IncompatibleInputs { .. }) => IncompatibleInputsError::new_err(msg),
+        Some(RustPulseError::Io(_)) => PulseIOError::new_err(msg),
+        None => PyRuntimeError::new_err(msg),
+    }
+}
+
+/// Register the exception classes with the Python module
+pub(crate) fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("PulseError", py.get_type::<PulseError>())?;
+    m.add("BadMagicError", py.get_type::<BadMagicError>())?;
+    m.add(
+        "UnsupportedEncodingError",
+        py.get_type::<UnsupportedEncodingError>(),
+    )?;
+    m.add(
+        "IndexMagicMismatchError",
+        py.get_type::<IndexMagicMismatchError>(),
+    )?;
+    m.add(
+        "ApertureNotFoundError",
+        py.get_type::<ApertureNotFoundError>(),
+    )?;
+    m.add("TruncatedFileError", py.get_type::<TruncatedFileError>())?;
+    m.add("MetadataFieldError", py.get_type::<MetadataFieldError>())?;
+    m.add(
+        "InvalidMetadataError",
+        py.get_type::<InvalidMetadataError>(),
+    )?;
+    m.add(
+        "IncompatibleInputsError",
+        py.get_type::<IncompatibleInputsError>(),
+    )?;
+    m.add("PulseIOError", py.get_type::<PulseIOError>())?;
+    Ok(())
+}
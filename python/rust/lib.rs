@@ -4,16 +4,25 @@
 //! - `PulseFile`, a pulses.bin reader
 //! - `PulseFilter`, a normalized pulse filter
 
+mod errors;
 pub mod pulse_filter;
 pub mod pulse_reader;
 mod records;
 use pulse_filter::PulseFilter;
-use pulse_reader::{PulseReader, merge_pulse_files};
+use pulse_reader::{
+    PulseReader, concat_pulse_files_in_time, merge_pulse_files, merge_shards, recover_file,
+    validate_file,
+};
 use pyo3::prelude::*;
 
 #[pymodule]
 fn qsi_pulse_reader(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    errors::register(m)?;
     m.add_function(wrap_pyfunction!(merge_pulse_files, m)?)?;
+    m.add_function(wrap_pyfunction!(concat_pulse_files_in_time, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_shards, m)?)?;
+    m.add_function(wrap_pyfunction!(recover_file, m)?)?;
+    m.add_function(wrap_pyfunction!(validate_file, m)?)?;
     m.add_class::<PulseReader>()?;
     m.add_class::<PulseFilter>()?;
     Ok(())
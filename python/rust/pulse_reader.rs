@@ -1,12 +1,27 @@
+use crate::errors::to_py_err;
 use crate::pulse_filter::PulseFilter;
 use crate::records::ToPyDict;
-use pyo3::exceptions::PyRuntimeError;
+use numpy::convert::IntoPyArray;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 use qsi_pulse_reader::pulse_filter::PulseFilter as RustPulseFilter;
 use qsi_pulse_reader::pulse_reader::PulseReader as RustPulseReader;
+use qsi_pulse_reader::pulse_reader::concat::concat_pulse_files_in_time as rust_concat_pulse_files_in_time;
+use qsi_pulse_reader::pulse_reader::crop::BoundaryPolicy;
 use qsi_pulse_reader::pulse_reader::headers::ApertureHeader;
-use qsi_pulse_reader::pulse_reader::merge_pulse_files as rust_merge_pulse_files;
+use qsi_pulse_reader::pulse_reader::merge::{
+    MergeLayout, merge_pulse_files_with_layout as rust_merge_pulse_files_with_layout,
+};
+use qsi_pulse_reader::pulse_reader::recover::recover_file as rust_recover_file;
+use qsi_pulse_reader::pulse_reader::sample::{
+    ApertureSample, PulseSample, SampleSize, SamplingStrategy,
+};
+use qsi_pulse_reader::pulse_reader::select::{ApertureSelection, ChipHalf};
+use qsi_pulse_reader::pulse_reader::shard::merge_shards as rust_merge_shards;
+use qsi_pulse_reader::pulse_reader::spatial::SpatialIndex;
+use qsi_pulse_reader::pulse_reader::validate::validate_file as rust_validate_file;
+use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
 
 /// Pulses.bin reader
 #[pyclass]
@@ -37,6 +52,24 @@ impl PulseReader {
         Ok(df.into())
     }
 
+    /// Calls `f` with the spatial index, building it first if needed
+    fn with_spatial_index<T, F>(&self, py: Python, f: F) -> PyResult<T>
+    where
+        T: Send,
+        F: FnOnce(&SpatialIndex) -> T + Send,
+    {
+        self.validate()?;
+        py.allow_threads(|| {
+            let spatial_index = self
+                .pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .spatial_index()
+                .map_err(to_py_err)?;
+            Ok(f(spatial_index))
+        })
+    }
+
     fn validate(&self) -> PyResult<()> {
         if self.pulse_reader.is_none() {
             return Err(PyRuntimeError::new_err(
@@ -47,6 +80,33 @@ impl PulseReader {
     }
 }
 
+/// Builds an aperture sample from the keyword arguments of the sampling methods
+fn aperture_sample(
+    n: Option<usize>,
+    fraction: Option<f64>,
+    seed: u64,
+    num_strata: Option<usize>,
+) -> PyResult<ApertureSample> {
+    let size = match (n, fraction) {
+        (Some(n), None) => SampleSize::Count(n),
+        (None, Some(fraction)) => SampleSize::Fraction(fraction),
+        _ => {
+            return Err(PyValueError::new_err(
+                "Exactly one of n or fraction must be given",
+            ));
+        }
+    };
+    let strategy = match num_strata {
+        Some(num_strata) => SamplingStrategy::Stratified { num_strata },
+        None => SamplingStrategy::Uniform,
+    };
+    Ok(ApertureSample {
+        size,
+        strategy,
+        seed,
+    })
+}
+
 #[pymethods]
 impl PulseReader {
     /// Open a pulses.bin file and return a PulseReader object
@@ -87,7 +147,7 @@ impl PulseReader {
         pulse_filter: Option<&PulseFilter>,
         pulse_filter_kwargs: Option<&Bound<'_, PyDict>>,
     ) -> PyResult<Self> {
-        let pulse_reader = RustPulseReader::open(file_name.to_string())?;
+        let pulse_reader = RustPulseReader::open(file_name).map_err(to_py_err)?;
         if pulse_filter.is_some() && pulse_filter_kwargs.is_some() {
             return Err(PyRuntimeError::new_err(
                 "Cannot provide both a PulseFilter object and keyword arguments for the filter!",
@@ -111,7 +171,7 @@ impl PulseReader {
                 .ok_or_else(|| PyRuntimeError::new_err("Invalid file name"))?,
         )?;
         common_attributes.set_item("frame_dur_s", 1.0 / pulse_reader.fps)?;
-        let duration = pulse_reader.metadata["duration"].as_f64().ok_or_else(|| {
+        let duration = pulse_reader.run_metadata.duration.ok_or_else(|| {
             PyRuntimeError::new_err("Missing or invalid 'duration' field in metadata")
         })? as f32;
         common_attributes.set_item("run_dur_f", (duration * pulse_reader.fps).ceil() as u64)?;
@@ -145,14 +205,14 @@ impl PulseReader {
     /// pulse_reader = PulseReader("path/to/pulses.bin")
     /// formatted_pulses = pulse_reader.get_records(0)
     /// ```
-    fn get_all_records(&mut self, py: Python, aperture_index: usize) -> PyResult<PyObject> {
+    fn get_all_records(&self, py: Python, aperture_index: usize) -> PyResult<PyObject> {
         self.validate()?;
         let (records, header) = py.allow_threads(|| {
             self.pulse_reader
-                .as_mut()
+                .as_ref()
                 .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
                 .get_all_records(aperture_index)
-                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get records: {}", e)))
+                .map_err(|e| to_py_err(e.context("Failed to get records")))
         })?;
         let pydict = records.to_pydict(py, None, None)?;
         let df = self.to_dataframe(py, &header, &pydict)?;
@@ -186,7 +246,7 @@ impl PulseReader {
         pulse_filter_kwargs=None,
     ))]
     fn get_pulses(
-        &mut self,
+        &self,
         py: Python,
         aperture_index: usize,
         include_aperture_index: bool,
@@ -212,10 +272,10 @@ impl PulseReader {
             .or(self.pulse_filter.as_ref().map(|pf| &pf.pulse_filter));
         let (pulses, header) = py.allow_threads(|| {
             self.pulse_reader
-                .as_mut()
+                .as_ref()
                 .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
                 .get_pulses(aperture_index, pulse_filter)
-                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get pulses: {}", e)))
+                .map_err(|e| to_py_err(e.context("Failed to get pulses")))
         })?;
         let ap = if include_aperture_index {
             Some(aperture_index)
@@ -226,7 +286,196 @@ impl PulseReader {
         let df = self.to_dataframe(py, &header, &pydict)?;
         // Pop the column named "index", then set it as the df index.
         let index_col = df.call_method1(py, "pop", ("index",))?;
-        Ok(df.call_method1(py, "set_index", (index_col,))?)
+        df.call_method1(py, "set_index", (index_col,))
+    }
+
+    /// Compute summary statistics of the pulses and records of each aperture
+    ///
+    /// The statistics of every aperture in the file are computed in a single pass over the
+    /// file, unless `aperture_index` is given. Pulses that fail the pulse filter are left
+    /// out of the pulse statistics, but not the record counts. Statistics of an aperture
+    /// without pulses are NaN.
+    ///
+    /// # Arguments
+    /// * `aperture_index` - Optional index of a single aperture to summarize
+    /// * `pulse_filter` - Optional PulseFilter object to filter the pulses
+    /// * `pulse_filter_kwargs` - Optional keyword arguments for the pulse filter
+    ///   (e.g., min_dur_f, min_dur_s, max_dur_s, min_snr, min_intensity, etc.)
+    ///   If neither is provided, the filter defined at initialization is used, if any.
+    ///
+    /// # Returns
+    /// A pandas DataFrame with one row per aperture, in index order, and the columns
+    /// "aperture_index", "x", "y", "num_pulses", "pulse_rate", "mean_dur_s", "median_dur_s",
+    /// "mean_ipd_s", "median_ipd_s", "intensity_q10", "intensity_q50", "intensity_q90",
+    /// "binratio_q10", "binratio_q50", "binratio_q90", "median_snr", "first_pulse_s",
+    /// "last_pulse_s", "on_time_fraction", and a "num_<record_type>_records" count for
+    /// every record type
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// summary = pulse_reader.summarize(pulse_filter_kwargs={"min_dur_f": 3})
+    /// active = summary[summary["pulse_rate"] > 0.1]
+    /// ```
+    #[pyo3(signature = (aperture_index=None, pulse_filter=None, pulse_filter_kwargs=None))]
+    fn summarize(
+        &self,
+        py: Python,
+        aperture_index: Option<usize>,
+        pulse_filter: Option<&PulseFilter>,
+        pulse_filter_kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<PyObject> {
+        self.validate()?;
+        if pulse_filter.is_some() && pulse_filter_kwargs.is_some() {
+            return Err(PyRuntimeError::new_err(
+                "Cannot provide both a PulseFilter object and keyword arguments for the filter!",
+            ));
+        }
+        let py_pulse_filter: Option<PulseFilter> = pulse_filter_kwargs
+            .map(|kwargs| PulseFilter::new(Some(kwargs)))
+            .transpose()?;
+        let pulse_filter: Option<&RustPulseFilter> = py_pulse_filter
+            .as_ref()
+            .or(pulse_filter)
+            .or(self.pulse_filter.as_ref())
+            .map(|pf| &pf.pulse_filter);
+        let summaries = py.allow_threads(|| {
+            let pulse_reader = self
+                .pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?;
+            match aperture_index {
+                Some(ap) => pulse_reader
+                    .summarize_aperture(ap, pulse_filter)
+                    .map(|summary| vec![summary]),
+                None => pulse_reader.summarize_apertures(pulse_filter),
+            }
+            .map_err(to_py_err)
+        })?;
+        let pydict = summaries.to_pydict(py, None, None)?;
+
+        let df = self.pandas.bind(py).call_method1("DataFrame", (pydict,))?;
+        let attrs = PyDict::new(py);
+        attrs.update(self.common_attributes.bind(py).as_mapping())?;
+        df.setattr("attrs", attrs)?;
+        Ok(df.into())
+    }
+
+    /// Get the header of every aperture in the file, without reading their records
+    ///
+    /// This is much faster than reading the records of every aperture, and is useful for
+    /// choosing which apertures to analyze.
+    ///
+    /// # Returns
+    /// A pandas DataFrame with one row per aperture, in index order, and the columns
+    /// "aperture_index", "x", "y", "well_id", "num_pulses" and "byte_loc"
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// headers = pulse_reader.aperture_headers()
+    /// busiest = headers.nlargest(100, "num_pulses")["aperture_index"].tolist()
+    /// ```
+    fn aperture_headers(&self, py: Python) -> PyResult<PyObject> {
+        self.validate()?;
+        let headers = py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .aperture_headers()
+                .map_err(to_py_err)
+        })?;
+        let pydict = PyDict::new(py);
+        let aperture_index: Vec<u64> = headers.aperture.iter().map(|&ap| ap as u64).collect();
+        pydict.set_item("aperture_index", aperture_index.into_pyarray(py))?;
+        pydict.set_item("x", headers.x.into_pyarray(py))?;
+        pydict.set_item("y", headers.y.into_pyarray(py))?;
+        pydict.set_item("well_id", headers.well_id.into_pyarray(py))?;
+        pydict.set_item("num_pulses", headers.num_pulses.into_pyarray(py))?;
+        pydict.set_item("byte_loc", headers.byte_loc.into_pyarray(py))?;
+
+        let df = self.pandas.bind(py).call_method1("DataFrame", (pydict,))?;
+        let attrs = PyDict::new(py);
+        attrs.update(self.common_attributes.bind(py).as_mapping())?;
+        df.setattr("attrs", attrs)?;
+        Ok(df.into())
+    }
+
+    /// Find the apertures in the file that are part of a region of interest
+    ///
+    /// Exactly one selection must be given. Positions are given as `x` (column) and `y`
+    /// (row), matching the `aperture_x` and `aperture_y` attributes of a record DataFrame.
+    ///
+    /// # Arguments
+    /// * `bounding_box` - A (min_x, min_y, max_x, max_y) rectangle, including its edges
+    /// * `polygon` - A list of (x, y) vertices. Apertures on the edge are included.
+    /// * `chip_half` - Either "left" or "right"
+    /// * `csv_file` - A CSV file with an `aperture` column, or `x` and `y` columns
+    ///
+    /// # Returns
+    /// The selected aperture indices, in index order. Apertures that are not in the file
+    /// are never selected.
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// apertures = pulse_reader.select_apertures(bounding_box=(0, 0, 511, 511))
+    /// pulse_reader.copy_apertures_to_new_file(apertures, "roi_pulses.bin")
+    /// ```
+    #[pyo3(signature = (bounding_box=None, polygon=None, chip_half=None, csv_file=None))]
+    fn select_apertures(
+        &self,
+        py: Python,
+        bounding_box: Option<(u32, u32, u32, u32)>,
+        polygon: Option<Vec<(f64, f64)>>,
+        chip_half: Option<&str>,
+        csv_file: Option<&str>,
+    ) -> PyResult<Vec<usize>> {
+        self.validate()?;
+        let mut selections = Vec::new();
+        if let Some((min_x, min_y, max_x, max_y)) = bounding_box {
+            selections.push(ApertureSelection::BoundingBox {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+            });
+        }
+        if let Some(polygon) = polygon {
+            selections.push(ApertureSelection::Polygon(polygon));
+        }
+        if let Some(chip_half) = chip_half {
+            let chip_half = match chip_half.to_lowercase().as_str() {
+                "left" => ChipHalf::Left,
+                "right" => ChipHalf::Right,
+                _ => {
+                    return Err(PyValueError::new_err(format!(
+                        "chip_half must be 'left' or 'right', not '{}'",
+                        chip_half
+                    )));
+                }
+            };
+            selections.push(ApertureSelection::ChipHalf(chip_half));
+        }
+        if let Some(csv_file) = csv_file {
+            selections.push(ApertureSelection::from_csv(csv_file).map_err(to_py_err)?);
+        }
+        let [selection] = selections.as_slice() else {
+            return Err(PyValueError::new_err(
+                "Exactly one of bounding_box, polygon, chip_half or csv_file must be given",
+            ));
+        };
+
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .select_apertures(selection)
+                .map_err(to_py_err)
+        })
     }
 
     /// Copy the specified apertures to a new file
@@ -235,9 +484,11 @@ impl PulseReader {
     /// * `apertures` - A list of aperture indices to copy
     /// * `file_name` - The name of the new file to create
     /// * `ignore_missing_apertures` - Whether to ignore missing apertures
+    /// * `overwrite` - Whether to replace `file_name` if it already exists
     ///
     /// # Returns
-    /// A new file containing the specified apertures.
+    /// A new file containing the specified apertures. Unless every aperture is copied, the
+    /// region of interest and rows in its metadata describe the subset.
     ///
     /// # Examples
     /// ```python
@@ -250,12 +501,13 @@ impl PulseReader {
     /// pulse_reader = PulseReader("path/to/pulses.bin")
     /// pulse_reader.copy_apertures_to_new_file([0, 1], "new_pulses.bin", ignore_missing_apertures=True)
     /// ```
-    #[pyo3(signature = (apertures, file_name, ignore_missing_apertures=false))]
+    #[pyo3(signature = (apertures, file_name, ignore_missing_apertures=false, overwrite=false))]
     fn copy_apertures_to_new_file(
-        &mut self,
+        &self,
         apertures: Vec<usize>,
         file_name: &str,
         ignore_missing_apertures: bool,
+        overwrite: bool,
     ) -> PyResult<()> {
         self.validate()?;
 
@@ -285,18 +537,430 @@ impl PulseReader {
                 j += 1;
             }
         }
-        if missing_apertures.len() > 0 && !ignore_missing_apertures {
+        if !missing_apertures.is_empty() && !ignore_missing_apertures {
             return Err(PyRuntimeError::new_err(format!(
                 "The following apertures were not found in the file: {:?}",
                 missing_apertures
             )));
         }
 
+        pulse_reader
+            .copy_apertures_to_new_file(
+                &found_apertures,
+                file_name,
+                OverwritePolicy::from_bool(overwrite),
+            )
+            .map_err(to_py_err)?;
+        Ok(())
+    }
+
+    /// Write a new file with only the pulses that pass a filter
+    ///
+    /// Pulse records that fail the filter are removed from every aperture, along with
+    /// non-pulse records unless `keep_non_pulse_records` is True. The remaining records keep
+    /// their original start frames, and the filter is recorded in the "pulseFilters" list in
+    /// the metadata of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The name of the new file to create
+    /// * `pulse_filter` - Optional PulseFilter object to filter the pulses
+    /// * `pulse_filter_kwargs` - Optional keyword arguments for the pulse filter
+    ///   (e.g., min_dur_f, min_dur_s, max_dur_s, min_snr, min_intensity, etc.)
+    ///   If neither is provided, the filter defined at initialization is used.
+    /// * `keep_non_pulse_records` - Whether to keep non-pulse records, such as background records
+    /// * `overwrite` - Whether to replace `file_name` if it already exists
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// pulse_reader.write_filtered("filtered_pulses.bin", pulse_filter_kwargs={"min_dur_f": 3})
+    /// ```
+    #[pyo3(signature = (
+        file_name,
+        pulse_filter=None,
+        pulse_filter_kwargs=None,
+        keep_non_pulse_records=false,
+        overwrite=false,
+    ))]
+    fn write_filtered(
+        &self,
+        py: Python,
+        file_name: &str,
+        pulse_filter: Option<&PulseFilter>,
+        pulse_filter_kwargs: Option<&Bound<'_, PyDict>>,
+        keep_non_pulse_records: bool,
+        overwrite: bool,
+    ) -> PyResult<()> {
+        self.validate()?;
+        if pulse_filter.is_some() && pulse_filter_kwargs.is_some() {
+            return Err(PyRuntimeError::new_err(
+                "Cannot provide both a PulseFilter object and keyword arguments for the filter!",
+            ));
+        }
+        let py_pulse_filter: Option<PulseFilter> = pulse_filter_kwargs
+            .map(|kwargs| PulseFilter::new(Some(kwargs)))
+            .transpose()?;
+        let pulse_filter: RustPulseFilter = py_pulse_filter
+            .as_ref()
+            .or(pulse_filter)
+            .or(self.pulse_filter.as_ref())
+            .map(|pf| pf.pulse_filter.clone())
+            .unwrap_or_default();
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .write_filtered(
+                    &pulse_filter,
+                    file_name,
+                    keep_non_pulse_records,
+                    OverwritePolicy::from_bool(overwrite),
+                )
+                .map_err(to_py_err)
+        })
+    }
+
+    /// Write a new file with only the records within a time window
+    ///
+    /// The start of the window becomes the start of the new file, unless pulses straddling
+    /// it are kept, and the duration in its metadata is set to the length of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The name of the new file to create
+    /// * `start_s` - The start of the window, in seconds from the start of the run
+    /// * `end_s` - The end of the window, in seconds from the start of the run
+    /// * `boundary` - What to do with pulses that straddle the start or end of the window:
+    ///   "drop" them, "clip" them to the window, or "keep" them whole. When pulses are kept,
+    ///   the new file starts at the start of the earliest pulse straddling the start.
+    /// * `overwrite` - Whether to replace `file_name` if it already exists
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// # Keep minutes 10 to 40 of the run
+    /// pulse_reader.write_cropped("cropped_pulses.bin", 600, 2400, boundary="clip")
+    /// ```
+    #[pyo3(signature = (file_name, start_s, end_s, boundary="drop", overwrite=false))]
+    fn write_cropped(
+        &self,
+        py: Python,
+        file_name: &str,
+        start_s: f64,
+        end_s: f64,
+        boundary: &str,
+        overwrite: bool,
+    ) -> PyResult<()> {
+        self.validate()?;
+        let boundary = match boundary.to_lowercase().as_str() {
+            "drop" => BoundaryPolicy::Drop,
+            "clip" => BoundaryPolicy::Clip,
+            "keep" => BoundaryPolicy::Keep,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "boundary must be 'drop', 'clip' or 'keep', not '{}'",
+                    boundary
+                )));
+            }
+        };
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .write_cropped(
+                    start_s,
+                    end_s,
+                    boundary,
+                    file_name,
+                    OverwritePolicy::from_bool(overwrite),
+                )
+                .map_err(to_py_err)
+        })
+    }
+
+    /// Find the aperture at a position on the chip
+    ///
+    /// The first spatial lookup reads the header of every aperture in the file, and later
+    /// lookups reuse it.
+    ///
+    /// # Arguments
+    /// * `x` - The column of the aperture
+    /// * `y` - The row of the aperture
+    ///
+    /// # Returns
+    /// The aperture index, or None if there is no aperture at that position in the file
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// ap = pulse_reader.get_aperture_by_xy(547, 408)
+    /// ```
+    fn get_aperture_by_xy(&self, py: Python, x: u32, y: u32) -> PyResult<Option<usize>> {
+        self.with_spatial_index(py, |spatial_index| spatial_index.get_by_xy(x, y))
+    }
+
+    /// Find the position of an aperture on the chip
+    ///
+    /// # Arguments
+    /// * `aperture_index` - The index of the aperture
+    ///
+    /// # Returns
+    /// The (x, y) position of the aperture, or None if it is not in the file
+    fn get_aperture_position(
+        &self,
+        py: Python,
+        aperture_index: usize,
+    ) -> PyResult<Option<(u32, u32)>> {
+        self.with_spatial_index(py, |spatial_index| spatial_index.position(aperture_index))
+    }
+
+    /// Find the apertures inside a rectangle, including its edges
+    ///
+    /// # Returns
+    /// The aperture indices, in row-major order
+    fn apertures_in_rect(
+        &self,
+        py: Python,
+        min_x: u32,
+        min_y: u32,
+        max_x: u32,
+        max_y: u32,
+    ) -> PyResult<Vec<usize>> {
+        self.with_spatial_index(py, |spatial_index| {
+            spatial_index.in_rect(min_x, min_y, max_x, max_y)
+        })
+    }
+
+    /// Find the apertures in the 8 positions surrounding a position on the chip
+    ///
+    /// # Returns
+    /// The aperture indices, in row-major order, not including the aperture at (x, y)
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// x, y = pulse_reader.get_aperture_position(836131)
+    /// neighbors = pulse_reader.neighboring_apertures(x, y)
+    /// ```
+    fn neighboring_apertures(&self, py: Python, x: u32, y: u32) -> PyResult<Vec<usize>> {
+        self.with_spatial_index(py, |spatial_index| spatial_index.neighbors(x, y))
+    }
+
+    /// Find the `k` apertures closest to a position on the chip
+    ///
+    /// # Returns
+    /// The aperture indices, nearest first, not including the aperture at (x, y).
+    /// Apertures at the same distance are ordered by row and then by column.
+    fn nearest_apertures(&self, py: Python, x: u32, y: u32, k: usize) -> PyResult<Vec<usize>> {
+        self.with_spatial_index(py, |spatial_index| spatial_index.nearest(x, y, k))
+    }
+
+    /// The well ID of the aperture at a position on the chip
+    ///
+    /// Well IDs number the apertures of the chip in row-major order, using the number of
+    /// columns in the metadata.
+    fn well_id(&self, x: u32, y: u32) -> PyResult<u32> {
+        self.validate()?;
         self.pulse_reader
-            .as_mut()
+            .as_ref()
             .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
-            .copy_apertures_to_new_file(&found_apertures, file_name)?;
-        Ok(())
+            .run_metadata
+            .well_id(x, y)
+            .map_err(to_py_err)
+    }
+
+    /// The (x, y) position on the chip of the aperture with a well ID
+    fn well_position(&self, well_id: u32) -> PyResult<(u32, u32)> {
+        self.validate()?;
+        self.pulse_reader
+            .as_ref()
+            .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+            .run_metadata
+            .well_position(well_id)
+            .map_err(to_py_err)
+    }
+
+    /// Choose a reproducible random sample of the apertures in the file
+    ///
+    /// Exactly one of `n` or `fraction` must be given. The same seed always gives the same
+    /// sample from the same file, in Python, R and Rust.
+    ///
+    /// # Arguments
+    /// * `n` - The number of apertures to sample, at least 1
+    /// * `fraction` - The fraction of the apertures to sample, between 0 and 1, which must
+    ///   round to at least one aperture
+    /// * `seed` - The seed of the random number generator, from 0 to 2^64 - 1. Only seeds
+    ///   up to 2^53 can also be given from R
+    /// * `num_strata` - If given, the apertures are divided into this many groups at the
+    ///   quantiles of their number of records, and the sample is split as evenly as
+    ///   possible between the groups, so that the most active apertures are represented
+    ///
+    /// # Returns
+    /// The sampled aperture indices, in index order
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// apertures = pulse_reader.sample_apertures(fraction=0.01, seed=42, num_strata=10)
+    /// ```
+    #[pyo3(signature = (n=None, fraction=None, seed=0, num_strata=None))]
+    fn sample_apertures(
+        &self,
+        py: Python,
+        n: Option<usize>,
+        fraction: Option<f64>,
+        seed: u64,
+        num_strata: Option<usize>,
+    ) -> PyResult<Vec<usize>> {
+        self.validate()?;
+        let sample = aperture_sample(n, fraction, seed, num_strata)?;
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .sample_apertures(&sample)
+                .map_err(to_py_err)
+        })
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the apertures
+    ///
+    /// The apertures are chosen as by `sample_apertures`, and the sample is recorded in
+    /// the "apertureSample" field of the metadata of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The path to the new pulses.bin file
+    /// * `n` - The number of apertures to sample, at least 1
+    /// * `fraction` - The fraction of the apertures to sample, between 0 and 1, which must
+    ///   round to at least one aperture
+    /// * `seed` - The seed of the random number generator, from 0 to 2^64 - 1. Only seeds
+    ///   up to 2^53 can also be given from R
+    /// * `num_strata` - If given, the number of groups to sample from by activity
+    /// * `overwrite` - Whether to replace the new file if it already exists
+    ///
+    /// # Returns
+    /// The sampled aperture indices, in index order
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// pulse_reader.write_sampled_apertures("sampled_pulses.bin", n=1000, seed=42)
+    /// ```
+    #[pyo3(signature = (file_name, n=None, fraction=None, seed=0, num_strata=None, overwrite=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn write_sampled_apertures(
+        &self,
+        py: Python,
+        file_name: &str,
+        n: Option<usize>,
+        fraction: Option<f64>,
+        seed: u64,
+        num_strata: Option<usize>,
+        overwrite: bool,
+    ) -> PyResult<Vec<usize>> {
+        self.validate()?;
+        let sample = aperture_sample(n, fraction, seed, num_strata)?;
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .write_sampled_apertures(&sample, file_name, OverwritePolicy::from_bool(overwrite))
+                .map_err(to_py_err)
+        })
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the pulses
+    ///
+    /// Every aperture is copied, keeping each of its pulses with probability `fraction`.
+    /// Non-pulse records are kept, and the remaining records keep their original start
+    /// frames. The same seed always keeps the same pulses, in Python, R and Rust, and the
+    /// sample is recorded in the "pulseSample" field of the metadata of the new file.
+    ///
+    /// # Arguments
+    /// * `file_name` - The path to the new pulses.bin file
+    /// * `fraction` - The probability of keeping each pulse, between 0 and 1
+    /// * `seed` - The seed of the random number generator, from 0 to 2^64 - 1. Only seeds
+    ///   up to 2^53 can also be given from R
+    /// * `overwrite` - Whether to replace the new file if it already exists
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// pulse_reader.write_sampled_pulses("sampled_pulses.bin", fraction=0.1, seed=42)
+    /// ```
+    #[pyo3(signature = (file_name, fraction, seed=0, overwrite=false))]
+    fn write_sampled_pulses(
+        &self,
+        py: Python,
+        file_name: &str,
+        fraction: f64,
+        seed: u64,
+        overwrite: bool,
+    ) -> PyResult<()> {
+        self.validate()?;
+        let sample = PulseSample { fraction, seed };
+        py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .write_sampled_pulses(&sample, file_name, OverwritePolicy::from_bool(overwrite))
+                .map_err(to_py_err)
+        })
+    }
+
+    /// Split the file into shards of roughly equal size, for processing in parallel
+    ///
+    /// The apertures are divided into contiguous groups in index order, balanced by their
+    /// size on disk, and each group is written to `output_dir` as a standalone pulses.bin
+    /// file, as by `copy_apertures_to_new_file`. A manifest describing the shards is
+    /// written alongside them, and can be passed to `merge_shards` to reassemble the
+    /// original file.
+    ///
+    /// # Arguments
+    /// * `num_shards` - The number of shards to create
+    /// * `output_dir` - The directory to write the shards and manifest to
+    /// * `overwrite` - Whether to replace existing shards and manifest
+    ///
+    /// # Returns
+    /// The manifest, as a dict. The shards are listed under "shards", each with its
+    /// "file_name", "first_aperture", "last_aperture", "num_apertures" and "num_bytes".
+    ///
+    /// # Examples
+    /// ```python
+    /// from qsi_pulse_reader import PulseReader, merge_shards
+    /// pulse_reader = PulseReader("path/to/pulses.bin")
+    /// manifest = pulse_reader.split_into_shards(8, "shards")
+    /// merge_shards("shards/pulses_shards.json", "pulses_reassembled.bin")
+    /// ```
+    #[pyo3(signature = (num_shards, output_dir, overwrite=false))]
+    fn split_into_shards(
+        &self,
+        py: Python,
+        num_shards: usize,
+        output_dir: &str,
+        overwrite: bool,
+    ) -> PyResult<PyObject> {
+        self.validate()?;
+        let manifest = py.allow_threads(|| {
+            self.pulse_reader
+                .as_ref()
+                .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+                .split_into_shards(
+                    num_shards,
+                    output_dir,
+                    OverwritePolicy::from_bool(overwrite),
+                )
+                .and_then(|manifest| manifest.to_json())
+                .map_err(to_py_err)
+        })?;
+        let json = PyModule::import(py, "json")?;
+        Ok(json.call_method1("loads", (manifest,))?.into())
     }
 
     /// Close the pulses.bin file
@@ -336,6 +1000,18 @@ impl PulseReader {
         Ok(self.metadata.clone_ref(py))
     }
 
+    /// The version of the pulses.bin format the file was written in
+    #[getter]
+    fn format_version(&self) -> PyResult<u32> {
+        self.validate()?;
+        Ok(self
+            .pulse_reader
+            .as_ref()
+            .ok_or_else(|| PyRuntimeError::new_err("PulseReader is not initialized"))?
+            .header
+            .version)
+    }
+
     #[getter]
     fn trimmed(&self) -> PyResult<bool> {
         self.validate()?;
@@ -347,12 +1023,175 @@ impl PulseReader {
     }
 }
 
+/// Merge multiple pulses.bin files into a single file
+///
+/// # Arguments
+/// * `file_names` - The paths to the pulses.bin files to merge
+/// * `new_file_name` - The path to the new pulses.bin file
+/// * `offsets` - An optional list of (row, col) offsets, one per file, at which each file
+///   is placed on the combined chip. By default, the files are stacked vertically.
+/// * `overwrite` - Whether to replace the new file if it already exists. The file is
+///   written to a temporary file first, so an existing file is only replaced once the
+///   new file is complete.
+///
+/// # Examples
+/// ```python
+/// from qsi_pulse_reader import merge_pulse_files
+/// # Place two 1024x2048 acquisitions side by side
+/// merge_pulse_files(["left.bin", "right.bin"], "merged.bin", offsets=[(0, 0), (0, 2048)])
+/// ```
+#[pyfunction]
+#[pyo3(signature = (file_names, new_file_name, offsets=None, overwrite=false))]
+pub fn merge_pulse_files(
+    file_names: Vec<String>,
+    new_file_name: &str,
+    offsets: Option<Vec<(u32, u32)>>,
+    overwrite: bool,
+) -> PyResult<()> {
+    let mut pulse_readers: Vec<RustPulseReader> = Vec::with_capacity(file_names.len());
+    for file_name in &file_names {
+        pulse_readers.push(RustPulseReader::open(file_name).map_err(to_py_err)?);
+    }
+    let layout = match offsets {
+        Some(offsets) => MergeLayout::Offsets(offsets),
+        None => MergeLayout::Vertical,
+    };
+    rust_merge_pulse_files_with_layout(
+        &pulse_readers,
+        new_file_name,
+        &layout,
+        OverwritePolicy::from_bool(overwrite),
+    )
+    .map_err(to_py_err)?;
+    Ok(())
+}
+
+/// Join sequential acquisitions of the same chip into a single pulses.bin file
+///
+/// Each file is treated as a later part of the same run, starting when the previous file
+/// ended according to its duration. The records of matching apertures are joined, so
+/// pulse start frames keep increasing across files, and the duration in the metadata of
+/// the new file is the total duration of all files.
+///
+/// # Arguments
+/// * `file_names` - The paths to the pulses.bin files to join, in acquisition order
+/// * `new_file_name` - The path to the new pulses.bin file
+/// * `overwrite` - Whether to replace the new file if it already exists. The file is
+///   written to a temporary file first, so an existing file is only replaced once the
+///   new file is complete.
+///
+/// # Examples
+/// ```python
+/// from qsi_pulse_reader import concat_pulse_files_in_time
+/// concat_pulse_files_in_time(["before.bin", "after.bin"], "joined.bin")
+/// ```
 #[pyfunction]
-pub fn merge_pulse_files(file_names: Vec<String>, new_file_name: &str) -> PyResult<()> {
+#[pyo3(signature = (file_names, new_file_name, overwrite=false))]
+pub fn concat_pulse_files_in_time(
+    file_names: Vec<String>,
+    new_file_name: &str,
+    overwrite: bool,
+) -> PyResult<()> {
     let mut pulse_readers: Vec<RustPulseReader> = Vec::with_capacity(file_names.len());
     for file_name in &file_names {
-        pulse_readers.push(RustPulseReader::open(file_name)?);
+        pulse_readers.push(RustPulseReader::open(file_name).map_err(to_py_err)?);
     }
-    rust_merge_pulse_files(&mut pulse_readers, new_file_name)?;
+    rust_concat_pulse_files_in_time(
+        &pulse_readers,
+        new_file_name,
+        OverwritePolicy::from_bool(overwrite),
+    )
+    .map_err(to_py_err)?;
     Ok(())
 }
+
+/// Reassemble the shards of a pulses.bin file into a single file
+///
+/// The new file contains the same apertures, records and metadata as the file that was
+/// split with `PulseReader.split_into_shards`.
+///
+/// # Arguments
+/// * `manifest_file` - The path to the manifest written alongside the shards
+/// * `new_file_name` - The path to the new pulses.bin file
+/// * `overwrite` - Whether to replace the new file if it already exists
+///
+/// # Examples
+/// ```python
+/// from qsi_pulse_reader import merge_shards
+/// merge_shards("shards/pulses_shards.json", "pulses_reassembled.bin")
+/// ```
+#[pyfunction]
+#[pyo3(signature = (manifest_file, new_file_name, overwrite=false))]
+pub fn merge_shards(
+    py: Python,
+    manifest_file: &str,
+    new_file_name: &str,
+    overwrite: bool,
+) -> PyResult<()> {
+    let overwrite = OverwritePolicy::from_bool(overwrite);
+    py.allow_threads(|| rust_merge_shards(manifest_file, new_file_name, overwrite))
+        .map_err(to_py_err)
+}
+
+/// Deeply validate the structure of a pulses.bin file
+///
+/// # Arguments
+/// * `file_name` - The path to the pulses.bin file
+///
+/// # Returns
+/// A dict describing every problem found in the file. The list of problems is stored
+/// under "issues", and each problem has a "kind", an "aperture" and "offset" (which may
+/// be None), and a human-readable "message". A file is valid if "issues" is empty.
+///
+/// # Examples
+/// ```python
+/// from qsi_pulse_reader import validate_file
+/// report = validate_file("path/to/pulses.bin")
+/// if report["issues"]:
+///     print(report["issues"][0]["message"])
+/// ```
+#[pyfunction]
+pub fn validate_file(py: Python, file_name: &str) -> PyResult<PyObject> {
+    let report = py
+        .allow_threads(|| rust_validate_file(file_name)?.to_json())
+        .map_err(to_py_err)?;
+    let json = PyModule::import(py, "json")?;
+    Ok(json.call_method1("loads", (report,))?.into())
+}
+
+/// Write a repaired copy of a pulses.bin file whose index is missing or corrupt
+///
+/// Walks the aperture headers from the start of the data section to reconstruct the
+/// index, dropping a truncated final aperture, then writes the recovered apertures to
+/// a new file with a correct header and index.
+///
+/// # Arguments
+/// * `file_name` - The path to the damaged pulses.bin file
+/// * `new_file_name` - The path of the repaired file to create
+/// * `overwrite` - Whether to replace `new_file_name` if it already exists
+///
+/// # Returns
+/// A dict describing the recovery, with the number of recovered apertures under
+/// "num_apertures" and the reason the scan stopped under "scan_end".
+///
+/// # Examples
+/// ```python
+/// from qsi_pulse_reader import recover_file
+/// report = recover_file("path/to/pulses.bin", "path/to/pulses_repaired.bin")
+/// print(report["num_apertures"])
+/// ```
+#[pyfunction]
+#[pyo3(signature = (file_name, new_file_name, overwrite=false))]
+pub fn recover_file(
+    py: Python,
+    file_name: &str,
+    new_file_name: &str,
+    overwrite: bool,
+) -> PyResult<PyObject> {
+    let overwrite = OverwritePolicy::from_bool(overwrite);
+    let report = py
+        .allow_threads(|| rust_recover_file(file_name, new_file_name, overwrite)?.to_json())
+        .map_err(to_py_err)?;
+    let json = PyModule::import(py, "json")?;
+    Ok(json.call_method1("loads", (report,))?.into())
+}
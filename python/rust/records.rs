@@ -4,6 +4,7 @@ use pyo3::prelude::*;
 use pyo3::types::PyDict;
 use qsi_pulse_reader::pulse_reader::headers::PulseRecordType;
 use qsi_pulse_reader::pulse_reader::records::{FormattedRecord, NormalizedPulse};
+use qsi_pulse_reader::pulse_reader::summary::ApertureSummary;
 
 // Helper utility for converting a vec of structs into a dict of vecs
 fn field_collect<P, T, F>(structs: &[P], f: F) -> Vec<T>
@@ -144,3 +145,117 @@ impl ToPyDict for Vec<FormattedRecord> {
         Ok(pydict.into())
     }
 }
+
+impl ToPyDict for Vec<ApertureSummary> {
+    fn to_pydict(
+        &self,
+        py: Python,
+        _aperture_index: Option<usize>,
+        _record_types: Option<&[PulseRecordType]>,
+    ) -> Result<Py<PyDict>> {
+        let pydict = PyDict::new(py);
+        pydict.set_item(
+            "aperture_index",
+            field_collect(self, |s| s.aperture_index as u64).into_pyarray(py),
+        )?;
+        pydict.set_item("x", field_collect(self, |s| s.x).into_pyarray(py))?;
+        pydict.set_item("y", field_collect(self, |s| s.y).into_pyarray(py))?;
+        pydict.set_item(
+            "num_pulses",
+            field_collect(self, |s| s.num_pulses as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "pulse_rate",
+            field_collect(self, |s| s.pulse_rate).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "mean_dur_s",
+            field_collect(self, |s| s.mean_dur_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "median_dur_s",
+            field_collect(self, |s| s.median_dur_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "mean_ipd_s",
+            field_collect(self, |s| s.mean_ipd_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "median_ipd_s",
+            field_collect(self, |s| s.median_ipd_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "intensity_q10",
+            field_collect(self, |s| s.intensity_q10).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "intensity_q50",
+            field_collect(self, |s| s.intensity_q50).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "intensity_q90",
+            field_collect(self, |s| s.intensity_q90).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "binratio_q10",
+            field_collect(self, |s| s.binratio_q10).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "binratio_q50",
+            field_collect(self, |s| s.binratio_q50).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "binratio_q90",
+            field_collect(self, |s| s.binratio_q90).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "median_snr",
+            field_collect(self, |s| s.median_snr).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "first_pulse_s",
+            field_collect(self, |s| s.first_pulse_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "last_pulse_s",
+            field_collect(self, |s| s.last_pulse_s).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "on_time_fraction",
+            field_collect(self, |s| s.on_time_fraction).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_pulse_records",
+            field_collect(self, |s| s.num_pulse_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_padding_records",
+            field_collect(self, |s| s.num_padding_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_long_pulse_update_records",
+            field_collect(self, |s| s.num_long_pulse_update_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_long_pulse_dropped_records",
+            field_collect(self, |s| s.num_long_pulse_dropped_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_step_up_records",
+            field_collect(self, |s| s.num_step_up_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_step_down_records",
+            field_collect(self, |s| s.num_step_down_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_background_records",
+            field_collect(self, |s| s.num_background_records as u64).into_pyarray(py),
+        )?;
+        pydict.set_item(
+            "num_unknown_records",
+            field_collect(self, |s| s.num_unknown_records as u64).into_pyarray(py),
+        )?;
+        Ok(pydict.into())
+    }
+}
@@ -1,3 +1,39 @@
-from qsi_pulse_reader.qsi_pulse_reader import PulseFilter, PulseReader, merge_pulse_files
+from qsi_pulse_reader.qsi_pulse_reader import (
+    ApertureNotFoundError,
+    BadMagicError,
+    IncompatibleInputsError,
+    IndexMagicMismatchError,
+    InvalidMetadataError,
+    MetadataFieldError,
+    PulseError,
+    PulseFilter,
+    PulseIOError,
+    PulseReader,
+    TruncatedFileError,
+    UnsupportedEncodingError,
+    concat_pulse_files_in_time,
+    merge_pulse_files,
+    merge_shards,
+    recover_file,
+    validate_file,
+)
 
-__all__ = ["PulseReader", "PulseFilter", "merge_pulse_files"]
+__all__ = [
+    "PulseReader",
+    "PulseFilter",
+    "merge_pulse_files",
+    "concat_pulse_files_in_time",
+    "merge_shards",
+    "recover_file",
+    "validate_file",
+    "PulseError",
+    "BadMagicError",
+    "UnsupportedEncodingError",
+    "IndexMagicMismatchError",
+    "ApertureNotFoundError",
+    "TruncatedFileError",
+    "MetadataFieldError",
+    "InvalidMetadataError",
+    "IncompatibleInputsError",
+    "PulseIOError",
+]
@@ -1,10 +1,26 @@
+from concurrent.futures import ThreadPoolExecutor
 from itertools import pairwise
 
 import numpy as np
 import pandas as pd
 import pytest
 
-from qsi_pulse_reader import PulseFilter, PulseReader, merge_pulse_files
+from qsi_pulse_reader import (
+    ApertureNotFoundError,
+    BadMagicError,
+    IncompatibleInputsError,
+    InvalidMetadataError,
+    PulseError,
+    PulseFilter,
+    PulseIOError,
+    PulseReader,
+    TruncatedFileError,
+    concat_pulse_files_in_time,
+    merge_pulse_files,
+    merge_shards,
+    recover_file,
+    validate_file,
+)
 
 
 def test_pulse_reader(pulse_reader):
@@ -33,6 +49,15 @@ def test_pulse_reader(pulse_reader):
         )
 
 
+def test_shared_pulse_reader(pulse_reader):
+    # A single reader can be shared between threads
+    expected = [pulse_reader.get_pulses(ap) for ap in pulse_reader.apertures]
+    with ThreadPoolExecutor(max_workers=4) as executor:
+        results = list(executor.map(pulse_reader.get_pulses, pulse_reader.apertures))
+    for expected_pulses, pulses in zip(expected, results):
+        pd.testing.assert_frame_equal(expected_pulses, pulses)
+
+
 @pytest.mark.parametrize(
     "pulse_filter_kwargs",
     [
@@ -108,6 +133,166 @@ def test_copy_apertures_to_new_file(pulse_reader, tmp_path):
         pd.testing.assert_frame_equal(original_pulses, new_pulses)
 
 
+def test_copy_apertures_overwrite(pulse_reader, tmp_path):
+    new_file = tmp_path / "new_pulses.bin"
+    new_file.write_bytes(b"existing")
+    apertures_to_copy = pulse_reader.apertures[:5]
+
+    with pytest.raises(PulseIOError):
+        pulse_reader.copy_apertures_to_new_file(apertures_to_copy, str(new_file))
+    assert new_file.read_bytes() == b"existing"
+
+    pulse_reader.copy_apertures_to_new_file(apertures_to_copy, str(new_file), overwrite=True)
+    assert PulseReader(str(new_file)).apertures == apertures_to_copy
+    assert [path.name for path in tmp_path.iterdir()] == ["new_pulses.bin"]
+
+
+def test_select_apertures(pulse_reader, tmp_path):
+    apertures = pulse_reader.select_apertures(bounding_box=(200, 200, 500, 800))
+    assert apertures == [565499, 1315058, 1585622]
+    assert pulse_reader.select_apertures(chip_half="left") == pulse_reader.apertures
+    assert pulse_reader.select_apertures(polygon=[(676, 128), (676, 0), (0, 0)]) == [262820]
+
+    csv_file = tmp_path / "roi.csv"
+    csv_file.write_text("x,y\n251,276\n")
+    assert pulse_reader.select_apertures(csv_file=str(csv_file)) == [565499]
+
+    with pytest.raises(ValueError):
+        pulse_reader.select_apertures()
+    with pytest.raises(ValueError):
+        pulse_reader.select_apertures(chip_half="top")
+
+    new_file = str(tmp_path / "roi_pulses.bin")
+    pulse_reader.copy_apertures_to_new_file(apertures, new_file)
+    metadata = PulseReader(new_file).metadata
+    assert metadata["rows"] == 775
+    assert metadata["roi_offset_row"] == 276
+    assert metadata["roi_offset_col"] == 242
+
+
+def test_aperture_headers(pulse_reader):
+    headers = pulse_reader.aperture_headers()
+    assert list(headers.columns) == [
+        "aperture_index", "x", "y", "well_id", "num_pulses", "byte_loc"
+    ]
+    assert headers["aperture_index"].tolist() == pulse_reader.apertures
+    for row in headers.itertuples():
+        records = pulse_reader.get_all_records(row.aperture_index)
+        assert row.x == records.attrs["aperture_x"]
+        assert row.y == records.attrs["aperture_y"]
+        assert row.byte_loc == records.attrs["aperture_byteloc"]
+        assert row.num_pulses == len(records)
+
+
+def test_summarize(pulse_reader):
+    summary = pulse_reader.summarize()
+    assert summary["aperture_index"].tolist() == pulse_reader.apertures
+    for row in summary.itertuples():
+        pulses = pulse_reader.get_pulses(row.aperture_index)
+        assert row.num_pulses == len(pulses)
+        if len(pulses) == 0:
+            assert np.isnan(row.median_dur_s)
+            continue
+        assert row.median_dur_s == pytest.approx(pulses["dur_s"].median())
+        assert row.median_ipd_s == pytest.approx(pulses["ipd_s"].iloc[1:].median())
+        assert row.intensity_q10 == pytest.approx(pulses["intensity"].quantile(0.1))
+        assert row.binratio_q90 == pytest.approx(pulses["binratio"].quantile(0.9))
+        assert row.median_snr == pytest.approx(pulses["snr"].median())
+
+        records = pulse_reader.get_all_records(row.aperture_index)
+        assert row.num_pulse_records == (records["record_type"] == "pulse").sum()
+
+    ap = pulse_reader.apertures[0]
+    single = pulse_reader.summarize(ap)
+    pd.testing.assert_frame_equal(single, summary.iloc[:1])
+
+    filtered = pulse_reader.summarize(pulse_filter_kwargs={"min_dur_f": 3})
+    assert (filtered["num_pulses"] <= summary["num_pulses"]).all()
+    assert (filtered["num_pulse_records"] == summary["num_pulse_records"]).all()
+
+
+def test_spatial_lookup(pulse_reader):
+    for ap in pulse_reader.apertures:
+        x, y = pulse_reader.get_aperture_position(ap)
+        assert pulse_reader.get_aperture_by_xy(x, y) == ap
+        assert pulse_reader.well_id(x, y) == ap
+        assert pulse_reader.well_position(ap) == (x, y)
+    assert pulse_reader.get_aperture_by_xy(0, 0) is None
+    assert pulse_reader.get_aperture_position(0) is None
+
+    assert pulse_reader.apertures_in_rect(0, 0, 511, 511) == pulse_reader.select_apertures(
+        bounding_box=(0, 0, 511, 511)
+    )
+    assert pulse_reader.neighboring_apertures(547, 408) == []
+    nearest = pulse_reader.nearest_apertures(547, 408, 3)
+    assert len(nearest) == 3
+    assert 836131 not in nearest
+    assert pulse_reader.nearest_apertures(547, 408, 100) == pulse_reader.nearest_apertures(
+        547, 408, len(pulse_reader.apertures) - 1
+    )
+
+
+def test_sample_apertures(pulse_reader, tmp_path):
+    # The same seed gives the same sample in every language
+    apertures = pulse_reader.sample_apertures(n=4, seed=42)
+    assert apertures == [565499, 836131, 1315058, 1808775]
+    assert len(pulse_reader.sample_apertures(fraction=0.5, seed=1, num_strata=2)) == 5
+    with pytest.raises(ValueError):
+        pulse_reader.sample_apertures(n=4, fraction=0.5)
+
+    new_file = str(tmp_path / "sampled_pulses.bin")
+    assert pulse_reader.write_sampled_apertures(new_file, n=4, seed=42) == apertures
+    sampled_reader = PulseReader(new_file)
+    assert sampled_reader.apertures == apertures
+    assert sampled_reader.metadata["apertureSample"]["seed"] == 42
+
+
+def test_write_sampled_pulses(pulse_reader, tmp_path):
+    # The same seed keeps the same pulses in every language
+    new_file = str(tmp_path / "sampled_pulses.bin")
+    pulse_reader.write_sampled_pulses(new_file, fraction=0.5, seed=42)
+    sampled_reader = PulseReader(new_file)
+    assert sampled_reader.apertures == pulse_reader.apertures
+    assert sum(len(sampled_reader.get_pulses(ap)) for ap in sampled_reader.apertures) == 2939
+    assert sampled_reader.metadata["pulseSample"] == {"fraction": 0.5, "seed": 42}
+    with pytest.raises(ValueError):
+        pulse_reader.write_sampled_pulses(str(tmp_path / "bad.bin"), fraction=1.5)
+
+
+def test_write_filtered(pulse_reader, tmp_path):
+    new_file = str(tmp_path / "filtered_pulses.bin")
+    pulse_filter_kwargs = {"min_dur_f": 3, "recalc_ipd": True}
+    pulse_reader.write_filtered(new_file, pulse_filter_kwargs=pulse_filter_kwargs)
+
+    filtered_reader = PulseReader(new_file)
+    assert filtered_reader.apertures == pulse_reader.apertures
+    assert filtered_reader.metadata["pulseFilters"][0]["min_dur_f"] == 3
+    for ap in pulse_reader.apertures:
+        expected = pulse_reader.get_pulses(ap, pulse_filter_kwargs=pulse_filter_kwargs)
+        pulses = filtered_reader.get_pulses(ap)
+        assert (pulses["start_f"].values == expected["start_f"].values).all()
+        assert (filtered_reader.get_all_records(ap)["record_type"] == "pulse").all()
+
+
+@pytest.mark.parametrize("boundary", ["drop", "clip", "keep"])
+def test_write_cropped(pulse_reader, tmp_path, boundary):
+    new_file = str(tmp_path / "cropped_pulses.bin")
+    pulse_reader.write_cropped(new_file, 600, 2400, boundary=boundary)
+
+    cropped_reader = PulseReader(new_file)
+    assert cropped_reader.metadata["duration"] >= 1800
+    fps = cropped_reader.metadata["fps"]
+    window_start, window_end = round(600 * fps), round(2400 * fps)
+    for ap in pulse_reader.apertures:
+        pulses = pulse_reader.get_pulses(ap)
+        inside = pulses[(pulses["start_f"] >= window_start) & (pulses["end_f"] <= window_end)]
+        cropped_pulses = cropped_reader.get_pulses(ap)
+        assert set(inside["start_f"] - window_start) <= set(cropped_pulses["start_f"])
+
+    with pytest.raises(ValueError):
+        pulse_reader.write_cropped(new_file, 600, 2400, boundary="extend", overwrite=True)
+
+
 @pytest.mark.parametrize("n_copies", [1, 2, 3])
 def test_merge_pulse_files(pulse_file, tmp_path, n_copies):
     new_file = str(tmp_path / "merged_pulses.bin")
@@ -143,3 +328,126 @@ def test_merge_pulse_files(pulse_file, tmp_path, n_copies):
 
     assert len(merged_pulse_reader.apertures) == tot_aps
     assert merged_pulse_reader.metadata["rows"] == tot_rows
+
+
+def test_merge_pulse_files_side_by_side(pulse_file, pulse_reader, tmp_path):
+    new_file = str(tmp_path / "merged_pulses.bin")
+    cols = pulse_reader.metadata["cols"]
+    merge_pulse_files([pulse_file, pulse_file], new_file, offsets=[(0, 0), (0, cols)])
+    merged_pulse_reader = PulseReader(new_file)
+    assert merged_pulse_reader.metadata["cols"] == 2 * cols
+    assert merged_pulse_reader.metadata["rows"] == pulse_reader.metadata["rows"]
+
+    for ap in pulse_reader.apertures:
+        records = pulse_reader.get_all_records(ap)
+        x = records.attrs["aperture_x"] + cols
+        y = records.attrs["aperture_y"]
+        new_records = merged_pulse_reader.get_all_records(y * 2 * cols + x)
+        assert new_records.attrs["aperture_x"] == x
+        assert records.equals(new_records)
+
+
+def test_concat_pulse_files_in_time(pulse_file, pulse_reader, tmp_path):
+    new_file = str(tmp_path / "concat_pulses.bin")
+    concat_pulse_files_in_time([pulse_file, pulse_file], new_file)
+    concat_pulse_reader = PulseReader(new_file)
+    assert concat_pulse_reader.metadata["duration"] == 2 * pulse_reader.metadata["duration"]
+
+    run_dur_f = pulse_reader.get_pulses(pulse_reader.apertures[0]).attrs["run_dur_f"]
+    for ap in pulse_reader.apertures:
+        pulses = pulse_reader.get_pulses(ap)
+        concat_pulses = concat_pulse_reader.get_pulses(ap)
+        assert len(concat_pulses) == 2 * len(pulses)
+        assert concat_pulses["start_f"].is_monotonic_increasing
+        np.testing.assert_array_equal(
+            concat_pulses["start_f"].to_numpy()[len(pulses) :],
+            pulses["start_f"].to_numpy() + run_dur_f,
+        )
+
+
+def test_split_into_shards(pulse_reader, tmp_path):
+    manifest = pulse_reader.split_into_shards(3, str(tmp_path))
+    assert len(manifest["shards"]) == 3
+    num_apertures = sum(shard["num_apertures"] for shard in manifest["shards"])
+    assert num_apertures == len(pulse_reader.apertures)
+
+    new_file = str(tmp_path / "reassembled_pulses.bin")
+    merge_shards(str(tmp_path / "pulses_shards.json"), new_file)
+    reassembled_reader = PulseReader(new_file)
+    assert reassembled_reader.apertures == pulse_reader.apertures
+    assert reassembled_reader.metadata == pulse_reader.metadata
+    for ap in pulse_reader.apertures:
+        records = pulse_reader.get_all_records(ap)
+        assert records.equals(reassembled_reader.get_all_records(ap))
+
+
+def test_merge_incompatible_files(pulse_file, tmp_path):
+    # Change the frame rate without changing the length of the metadata
+    data = open(pulse_file, "rb").read()
+    other_fps = tmp_path / "other_fps.bin"
+    other_fps.write_bytes(data.replace(b'"fps":16.675420168067227', b'"fps":20.000000000000000'))
+
+    new_file = tmp_path / "merged_pulses.bin"
+    with pytest.raises(IncompatibleInputsError, match="input 1 .* has fps 20"):
+        merge_pulse_files([pulse_file, str(other_fps)], str(new_file))
+    assert not new_file.exists()
+
+
+def test_pulse_errors(pulse_file, pulse_reader, tmp_path):
+    with pytest.raises(ApertureNotFoundError):
+        pulse_reader.get_pulses(max(pulse_reader.apertures) + 1)
+    # All pulse errors are also RuntimeErrors, for compatibility with older versions
+    with pytest.raises(RuntimeError):
+        pulse_reader.get_all_records(max(pulse_reader.apertures) + 1)
+
+    with pytest.raises(PulseIOError):
+        PulseReader(str(tmp_path / "does_not_exist.bin"))
+
+    data = bytearray(open(pulse_file, "rb").read())
+    bad_magic = tmp_path / "bad_magic.bin"
+    bad_magic.write_bytes(b"\x00" * 4 + data[4:])
+    with pytest.raises(BadMagicError):
+        PulseReader(str(bad_magic))
+
+    truncated = tmp_path / "truncated.bin"
+    truncated.write_bytes(data[:100])
+    with pytest.raises(TruncatedFileError) as exc_info:
+        PulseReader(str(truncated))
+    assert isinstance(exc_info.value, PulseError)
+
+    bad_metadata = tmp_path / "bad_metadata.bin"
+    metadata_start = data.index(b"{")
+    bad_metadata.write_bytes(data[:metadata_start] + b"[" + data[metadata_start + 1:])
+    with pytest.raises(InvalidMetadataError):
+        PulseReader(str(bad_metadata))
+
+    with pytest.raises(ValueError):
+        merge_pulse_files([], str(tmp_path / "merged.bin"))
+
+
+def test_validate_file(pulse_file, tmp_path):
+    report = validate_file(pulse_file)
+    assert report["issues"] == []
+
+    data = bytearray(open(pulse_file, "rb").read())
+    corrupted = tmp_path / "corrupted.bin"
+    corrupted.write_bytes(data[:-4])
+    report = validate_file(str(corrupted))
+    assert [issue["kind"] for issue in report["issues"]] == ["truncated"]
+
+
+def test_recover_file(pulse_file, pulse_reader, tmp_path):
+    # Drop the index from the end of the file
+    data = open(pulse_file, "rb").read()
+    index_offset = len(data) - 8 - 12 * len(pulse_reader.apertures)
+    damaged = tmp_path / "damaged.bin"
+    damaged.write_bytes(data[:index_offset])
+    with pytest.raises(PulseError):
+        PulseReader(str(damaged))
+
+    repaired = tmp_path / "repaired.bin"
+    report = recover_file(str(damaged), str(repaired))
+    assert report["num_apertures"] == len(pulse_reader.apertures)
+    assert report["scan_end"]["reason"] == "end_of_file"
+    assert validate_file(str(repaired))["issues"] == []
+    assert PulseReader(str(repaired)).apertures == pulse_reader.apertures
@@ -8,9 +8,29 @@ pub mod pulse_filter;
 pub mod pulse_reader;
 
 #[cfg(test)]
+// The filter tests set fields one at a time on a default filter
+#[allow(clippy::field_reassign_with_default)]
 mod tests {
     use crate::pulse_filter::PulseFilter;
-    use crate::pulse_reader::records::NormalizedPulse;
+    use crate::pulse_reader::concat::concat_pulse_files_in_time;
+    use crate::pulse_reader::crop::BoundaryPolicy;
+    use crate::pulse_reader::error::{MergeConflict, PulseError};
+    use crate::pulse_reader::headers::{
+        ApertureHeader, FormatVersion, PulseFileIndex, PulseRecordType,
+    };
+    use crate::pulse_reader::merge::{
+        MergeLayout, check_merge_compatibility, merge_pulse_files_with_layout,
+    };
+    use crate::pulse_reader::metadata::RunMetadata;
+    use crate::pulse_reader::records::{
+        FormattedRecord, FormattedRecordType, NormalizedPulse, PulseNormalizer, RawRecord,
+    };
+    use crate::pulse_reader::recover::{ScanEnd, recover_file};
+    use crate::pulse_reader::sample::{ApertureSample, PulseSample, SampleSize, SamplingStrategy};
+    use crate::pulse_reader::select::{ApertureSelection, ChipHalf};
+    use crate::pulse_reader::shard::{ShardManifest, merge_shards};
+    use crate::pulse_reader::validate::{IssueKind, validate_file};
+    use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
     use crate::pulse_reader::{PulseReader, merge_pulse_files};
     use anyhow::Result;
     use std::path::PathBuf;
@@ -28,7 +48,7 @@ mod tests {
 
     #[test]
     fn test_pulse_reader() -> Result<()> {
-        let mut pulse_reader = get_pulse_reader()?;
+        let pulse_reader = get_pulse_reader()?;
         let apertures = pulse_reader.index.apertures.clone();
         for ap in apertures {
             let (records, ap_header) = pulse_reader.get_all_records(ap)?;
@@ -128,7 +148,7 @@ mod tests {
 
     #[test]
     fn test_copy_apertures_to_new_file() -> Result<()> {
-        let mut pulse_reader = get_pulse_reader()?;
+        let pulse_reader = get_pulse_reader()?;
         let apertures = pulse_reader.index.apertures[0..5].to_vec();
         let temp_dir =
             tempdir().map_err(|e| anyhow::anyhow!("Failed to create temp directory: {}", e))?;
@@ -138,9 +158,13 @@ mod tests {
             .to_string_lossy()
             .to_string();
 
-        pulse_reader.copy_apertures_to_new_file(&apertures, &new_file_path)?;
+        pulse_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Fail,
+        )?;
 
-        let mut new_pulse_reader = PulseReader::open(new_file_path)?;
+        let new_pulse_reader = PulseReader::open(new_file_path)?;
         assert_eq!(new_pulse_reader.index.apertures.len(), apertures.len());
         for ap in apertures {
             let (orig_records, _ap_header) = pulse_reader.get_all_records(ap)?;
@@ -155,7 +179,7 @@ mod tests {
 
     #[test]
     fn test_merge_pulse_files() {
-        let mut pulse_readers = vec![get_pulse_reader().unwrap(), get_pulse_reader().unwrap()];
+        let pulse_readers = vec![get_pulse_reader().unwrap(), get_pulse_reader().unwrap()];
         let temp_dir = tempdir().unwrap();
         let new_file_path = temp_dir
             .path()
@@ -163,9 +187,9 @@ mod tests {
             .to_string_lossy()
             .to_string();
 
-        merge_pulse_files(&mut pulse_readers, &new_file_path).unwrap();
+        merge_pulse_files(&pulse_readers, &new_file_path, OverwritePolicy::Fail).unwrap();
 
-        let mut merged_reader = PulseReader::open(new_file_path).unwrap();
+        let merged_reader = PulseReader::open(new_file_path).unwrap();
         assert_eq!(
             merged_reader.index.apertures.len(),
             pulse_readers[0].index.apertures.len() * 2
@@ -180,4 +204,2104 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_pulse_file_writer() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let temp_dir = tempdir()?;
+        let new_file_path = temp_dir.path().join("written_pulses.bin");
+
+        let mut writer = PulseFileWriter::create(
+            &new_file_path,
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        let apertures = pulse_reader.index.apertures.clone();
+        // Write apertures in reverse order to check that the index is sorted on finish
+        for ap in apertures.iter().rev() {
+            let (records, ap_header) = pulse_reader.get_raw_records(*ap)?;
+            writer.write_aperture(&ap_header, &records)?;
+        }
+        writer.finish()?;
+
+        let new_pulse_reader = PulseReader::open(&new_file_path)?;
+        assert_eq!(new_pulse_reader.index.apertures, apertures);
+        assert_eq!(new_pulse_reader.metadata, pulse_reader.metadata);
+        assert_eq!(new_pulse_reader.header.data_offset % 16, 0);
+        for ap in apertures {
+            let (orig_records, orig_header) = pulse_reader.get_all_records(ap)?;
+            let (new_records, new_header) = new_pulse_reader.get_all_records(ap)?;
+            assert_eq!(orig_records, new_records);
+            assert_eq!(
+                (orig_header.x, orig_header.y, orig_header.num_pulses),
+                (new_header.x, new_header.y, new_header.num_pulses)
+            );
+        }
+
+        // Writing the same aperture twice is an error
+        let mut writer = PulseFileWriter::create(
+            temp_dir.path().join("duplicate_pulses.bin"),
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        let (records, ap_header) = pulse_reader.get_raw_records(pulse_reader.index.apertures[0])?;
+        writer.write_aperture(&ap_header, &records)?;
+        writer.write_aperture(&ap_header, &records)?;
+        assert!(writer.finish().is_err());
+
+        // An encoding table too long for the header is rejected
+        let record_types = vec![pulse_reader.record_types[0].clone(); u16::MAX as usize + 1];
+        let err = PulseFileWriter::create(
+            temp_dir.path().join("too_many_encodings.bin"),
+            &pulse_reader.metadata,
+            &record_types,
+            OverwritePolicy::Fail,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::InvalidArgument { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_encoding_round_trip() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let apertures = pulse_reader.index.apertures.clone();
+        for ap in apertures {
+            let (raw_records, _ap_header) = pulse_reader.get_raw_records(ap)?;
+            let (records, _ap_header) = pulse_reader.get_all_records(ap)?;
+            for (raw, record) in raw_records.iter().zip(records.iter()) {
+                assert_eq!(
+                    *raw,
+                    RawRecord::from_formatted_with_encoding(record, &pulse_reader.encoding)
+                );
+                #[allow(deprecated)]
+                {
+                    assert_eq!(
+                        *raw,
+                        RawRecord::from_formatted(record, &pulse_reader.record_types)
+                    );
+                    assert_eq!(
+                        *record,
+                        FormattedRecord::from_raw(raw, &pulse_reader.record_types, record.index)
+                    );
+                }
+            }
+
+            let (pulses, _ap_header) = pulse_reader.get_pulses(ap, None)?;
+            for pulse in pulses {
+                let raw = &raw_records[pulse.index];
+                let last_record_end = pulse.start_f - raw.frames_since_last as u32;
+                let record: FormattedRecord = pulse.to_formatted_record(last_record_end)?;
+                assert_eq!(record, records[pulse.index]);
+                assert_eq!(
+                    *raw,
+                    RawRecord::from_formatted_with_encoding(&record, &pulse_reader.encoding)
+                );
+            }
+        }
+
+        // Out of range values saturate, and non-finite values map to their sentinels
+        let record_type = &pulse_reader.encoding.m0;
+        assert_eq!(record_type.encode_value(1e9), 32766);
+        assert_eq!(record_type.encode_value(-1e9), -32766);
+        assert_eq!(record_type.encode_value(f32::NAN), -32768);
+        assert_eq!(record_type.encode_value(f32::INFINITY), 32767);
+        assert_eq!(record_type.encode_value(f32::NEG_INFINITY), -32767);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmap_pulse_reader() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let mmap_reader = PulseReader::open_mmap(&pulse_reader.file_name)?;
+        assert_eq!(mmap_reader.index.apertures, pulse_reader.index.apertures);
+
+        let pulse_filter = PulseFilter {
+            min_dur_f: Some(5),
+            ..Default::default()
+        };
+        let apertures = pulse_reader.index.apertures.clone();
+        for ap in apertures {
+            let (raw_records, _ap_header) = pulse_reader.get_raw_records(ap)?;
+            let aperture = mmap_reader.aperture(ap)?;
+            assert_eq!(aperture.len(), raw_records.len());
+            assert_eq!(mmap_reader.get_raw_records(ap)?.0, raw_records);
+            assert!(aperture.iter_raw().eq(raw_records.into_iter()));
+            assert_eq!(pulse_reader.aperture(ap)?.as_bytes(), aperture.as_bytes());
+
+            let (records, ap_header) = pulse_reader.get_all_records(ap)?;
+            let (mmap_records, mmap_ap_header) = mmap_reader.get_all_records(ap)?;
+            assert_eq!(records, mmap_records);
+            assert_eq!(ap_header.byte_loc, mmap_ap_header.byte_loc);
+
+            let (pulses, _ap_header) = pulse_reader.get_pulses(ap, Some(&pulse_filter))?;
+            let (mmap_pulses, _ap_header) = mmap_reader.get_pulses(ap, Some(&pulse_filter))?;
+            assert_eq!(
+                pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f))
+                    .collect::<Vec<_>>(),
+                mmap_pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f))
+                    .collect::<Vec<_>>()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_pulse_reader() -> Result<()> {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PulseReader>();
+
+        // Read every aperture from several threads sharing a single reader
+        let pulse_reader = get_pulse_reader()?;
+        let apertures = &pulse_reader.index.apertures;
+        let expected = apertures
+            .iter()
+            .map(|ap| Ok(pulse_reader.get_all_records(*ap)?.0))
+            .collect::<Result<Vec<_>>>()?;
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        apertures
+                            .iter()
+                            .map(|ap| Ok(pulse_reader.get_all_records(*ap)?.0))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                })
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap().unwrap(), expected);
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_map_apertures() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let pulse_filter = PulseFilter {
+            min_snr: Some(6.0),
+            ..Default::default()
+        };
+
+        let mut expected = Vec::new();
+        for ap in pulse_reader.index.apertures.iter() {
+            let (pulses, ap_header) = pulse_reader.get_pulses(*ap, Some(&pulse_filter))?;
+            expected.push((ap_header.well_id, pulses.len()));
+        }
+        let results = pulse_reader
+            .par_map_apertures(Some(&pulse_filter), |pulses, ap_header| {
+                (ap_header.well_id, pulses.len())
+            })?;
+        assert_eq!(results, expected);
+
+        let total = pulse_reader.par_reduce_apertures(
+            Some(&pulse_filter),
+            0,
+            |pulses, _ap_header| pulses.len(),
+            |a, b| a + b,
+        )?;
+        assert_eq!(total, expected.iter().map(|(_, n)| n).sum::<usize>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_apertures() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let pulse_filter = PulseFilter {
+            min_dur_f: Some(10),
+            ..Default::default()
+        };
+
+        let mut last_byte_loc = 0;
+        let mut num_apertures = 0;
+        let iter = pulse_reader
+            .iter_apertures()?
+            .zip(pulse_reader.iter_pulses(Some(&pulse_filter))?);
+        for (item, pulses_item) in iter {
+            let (records, ap_header) = item?;
+            let (pulses, _ap_header) = pulses_item?;
+            // Apertures are visited in on-disk order
+            assert!(ap_header.byte_loc > last_byte_loc);
+            last_byte_loc = ap_header.byte_loc;
+
+            let ap = ap_header.well_id as usize;
+            let (expected_records, _ap_header) = pulse_reader.get_all_records(ap)?;
+            assert_eq!(records, expected_records);
+            let (expected_pulses, _ap_header) = pulse_reader.get_pulses(ap, Some(&pulse_filter))?;
+            assert_eq!(
+                pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f))
+                    .collect::<Vec<_>>(),
+                expected_pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f))
+                    .collect::<Vec<_>>()
+            );
+            num_apertures += 1;
+        }
+        assert_eq!(num_apertures, pulse_reader.index.apertures.len());
+
+        // An aperture whose offset is too large to read from is an error
+        let mut pulse_reader = pulse_reader;
+        let ap = pulse_reader.index.apertures[0];
+        pulse_reader.index = PulseFileIndex::from_entries(&[(ap, u64::MAX - 4)])?;
+        let err = pulse_reader
+            .iter_raw_apertures()?
+            .next()
+            .unwrap()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::Truncated { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_aperture_records() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        for &ap in pulse_reader.index.apertures.iter() {
+            let (expected_records, expected_header) = pulse_reader.get_all_records(ap)?;
+            let (expected_pulses, _ap_header) = pulse_reader.get_pulses(ap, None)?;
+
+            // Use a small chunk size so that every aperture spans several chunks
+            let (records, ap_header) = pulse_reader.iter_aperture_records(ap)?;
+            let records = records.with_chunk_size(7);
+            assert_eq!(records.size_hint().0, expected_records.len());
+            let records = records.collect::<Result<Vec<_>>>()?;
+            assert_eq!(ap_header.byte_loc, expected_header.byte_loc);
+            assert_eq!(records, expected_records);
+
+            let (pulses, _ap_header) = pulse_reader.iter_aperture_pulses(ap)?;
+            let pulses = pulses.collect::<Result<Vec<_>>>()?;
+            assert_eq!(
+                pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f, p.end_f, p.ipd_f))
+                    .collect::<Vec<_>>(),
+                expected_pulses
+                    .iter()
+                    .map(|p| (p.index, p.start_f, p.end_f, p.ipd_f))
+                    .collect::<Vec<_>>()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pulse_errors() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let err = pulse_reader.get_pulses(usize::MAX, None).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::ApertureNotFound { index: usize::MAX })
+        ));
+
+        let err = PulseReader::open("does_not_exist.bin").err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::Io(_))
+        ));
+
+        // Corrupt copies of the example file in different ways
+        let temp_dir = tempdir()?;
+        let bytes = std::fs::read(&pulse_reader.file_name)?;
+        let index_offset = pulse_reader.header.index_offset as usize;
+        let open_corrupted = |name: &str, bytes: &[u8]| -> Result<PulseError> {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, bytes)?;
+            let err = PulseReader::open(&path).err().unwrap();
+            err.downcast::<PulseError>()
+        };
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] ^= 0xff;
+        let err = open_corrupted("bad_magic.bin", &bad_magic)?;
+        assert!(matches!(err, PulseError::BadMagic { .. }));
+
+        let mut bad_encoding = bytes.clone();
+        bad_encoding[20] = 2; // encoding_record_type
+        let err = open_corrupted("bad_encoding.bin", &bad_encoding)?;
+        assert!(matches!(
+            err,
+            PulseError::UnsupportedEncoding { value: 2, .. }
+        ));
+
+        let mut bad_index = bytes.clone();
+        bad_index[index_offset] ^= 0xff;
+        let err = open_corrupted("bad_index.bin", &bad_index)?;
+        assert!(matches!(err, PulseError::IndexMagicMismatch { .. }));
+
+        let err = open_corrupted("truncated.bin", &bytes[..index_offset + 4])?;
+        assert!(matches!(
+            err,
+            PulseError::Truncated { offset } if offset == index_offset as u64
+        ));
+
+        let mut bad_metadata = bytes.clone();
+        let metadata_start = bytes.iter().position(|&b| b == b'{').unwrap();
+        bad_metadata[metadata_start] = b'[';
+        let err = open_corrupted("bad_metadata.bin", &bad_metadata)?;
+        assert!(matches!(err, PulseError::InvalidMetadata { .. }));
+
+        let err = merge_pulse_files(
+            &[],
+            temp_dir.path().join("merged.bin"),
+            OverwritePolicy::Fail,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::InvalidArgument { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_file() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let report = validate_file(&pulse_reader.file_name)?;
+        assert!(report.is_valid(), "{}", report.to_json()?);
+        assert_eq!(report.num_apertures, pulse_reader.header.num_reads);
+
+        // Corrupt several parts of a copy of the example file, all of which should be reported
+        let mut bytes = std::fs::read(&pulse_reader.file_name)?;
+        let index_start = pulse_reader.header.index_offset as usize + 8;
+        // Swap the first two index entries
+        let (first, second) = bytes[index_start..index_start + 24].split_at_mut(12);
+        first.swap_with_slice(second);
+        // Change the well_id of the third aperture's header
+        let ap = pulse_reader.index.apertures[2];
+        let byte_loc = pulse_reader.index.get(ap)? as usize;
+        bytes[byte_loc + 8..byte_loc + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+        // Make the last aperture claim more records than fit in the data section
+        let ap = *pulse_reader.index.apertures.last().unwrap();
+        let byte_loc = pulse_reader.index.get(ap)? as usize;
+        bytes[byte_loc + 12..byte_loc + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("corrupt_pulses.bin");
+        std::fs::write(&path, &bytes)?;
+        let report = validate_file(&path)?;
+        let kinds: Vec<IssueKind> = report.issues.iter().map(|issue| issue.kind).collect();
+        assert!(kinds.contains(&IssueKind::IndexUnsorted));
+        assert!(kinds.contains(&IssueKind::WellIdMismatch));
+        assert!(kinds.contains(&IssueKind::RecordsOutOfRange));
+        // The last aperture is excluded from the data section, leaving a gap at the end
+        assert!(kinds.contains(&IssueKind::DataGap));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()?)?;
+        assert_eq!(json["issues"][0]["kind"], "index_unsorted");
+
+        // Duplicates are found even when they aren't next to each other in the index
+        let mut bytes = std::fs::read(&pulse_reader.file_name)?;
+        let first_ap = pulse_reader.index.apertures[0];
+        bytes[index_start + 24..index_start + 28].copy_from_slice(&(first_ap as u32).to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+        let report = validate_file(&path)?;
+        let duplicates: Vec<_> = report
+            .issues
+            .iter()
+            .filter(|issue| issue.kind == IssueKind::IndexDuplicate)
+            .collect();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].aperture, Some(first_ap));
+        assert_eq!(duplicates[0].offset, Some(index_start as u64 + 24));
+
+        // An index offset that can't be added to without overflowing
+        let mut bytes = std::fs::read(&pulse_reader.file_name)?;
+        bytes[40..48].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes)?;
+        let report = validate_file(&path)?;
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!(report.issues[0].kind, IssueKind::InvalidOffsets);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_file() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let (recovered_reader, report) = PulseReader::open_recovered(&pulse_reader.file_name)?;
+        assert_eq!(report.scan_end, ScanEnd::IndexFound);
+        assert_eq!(
+            recovered_reader.index.apertures,
+            pulse_reader.index.apertures
+        );
+        assert_eq!(report.data_end, pulse_reader.header.index_offset);
+
+        let temp_dir = tempdir()?;
+        let bytes = std::fs::read(&pulse_reader.file_name)?;
+
+        // Overwrite the index with garbage
+        let mut bad_index = bytes.clone();
+        let index_offset = pulse_reader.header.index_offset as usize;
+        bad_index[index_offset..].fill(0xff);
+        let path = temp_dir.path().join("bad_index.bin");
+        std::fs::write(&path, &bad_index)?;
+        assert!(PulseReader::open(&path).is_err());
+        let (recovered_reader, report) = PulseReader::open_recovered(&path)?;
+        assert_eq!(
+            recovered_reader.index.apertures,
+            pulse_reader.index.apertures
+        );
+        assert!(matches!(report.scan_end, ScanEnd::InvalidHeader { .. }));
+
+        // A zero-filled tail ends the data, rather than being read as an empty aperture 0
+        let mut zero_filled = bytes[..index_offset].to_vec();
+        zero_filled.resize(index_offset + 1024, 0);
+        let path = temp_dir.path().join("zero_filled.bin");
+        std::fs::write(&path, &zero_filled)?;
+        let (recovered_reader, report) = PulseReader::open_recovered(&path)?;
+        assert_eq!(
+            recovered_reader.index.apertures,
+            pulse_reader.index.apertures
+        );
+        assert_eq!(
+            report.scan_end,
+            ScanEnd::ZeroFilled {
+                byte_loc: index_offset as u64
+            }
+        );
+        assert_eq!(report.discarded_bytes, 1024);
+
+        // Cut the file off in the middle of the last aperture
+        let last_ap = *pulse_reader.index.apertures.last().unwrap();
+        let last_byte_loc = pulse_reader.index.get(last_ap)?;
+        let path = temp_dir.path().join("truncated.bin");
+        std::fs::write(&path, &bytes[..last_byte_loc as usize + 40])?;
+        assert!(PulseReader::open(&path).is_err());
+
+        let repaired_path = temp_dir.path().join("repaired.bin");
+        let report = recover_file(&path, &repaired_path, OverwritePolicy::Fail)?;
+        assert_eq!(
+            report.scan_end,
+            ScanEnd::TruncatedAperture {
+                aperture: last_ap,
+                byte_loc: last_byte_loc
+            }
+        );
+        assert_eq!(report.num_apertures, pulse_reader.index.apertures.len() - 1);
+        assert_eq!(report.discarded_bytes, 40);
+        assert!(validate_file(&repaired_path)?.is_valid());
+
+        let repaired_reader = PulseReader::open(&repaired_path)?;
+        assert_eq!(
+            repaired_reader.index.apertures,
+            pulse_reader.index.apertures[..report.num_apertures]
+        );
+        for &ap in repaired_reader.index.apertures.iter() {
+            assert_eq!(
+                repaired_reader.get_all_records(ap)?.0,
+                pulse_reader.get_all_records(ap)?.0
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_version() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        assert_eq!(pulse_reader.header.format_version()?, FormatVersion::V4);
+        let bytes = std::fs::read(&pulse_reader.file_name)?;
+        let temp_dir = tempdir()?;
+
+        // The example file converted to the layout with only a partial header
+        let v3_path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../example_files/pulses_v3.bin");
+        let v3_reader = PulseReader::open(&v3_path)?;
+        assert_eq!(v3_reader.header.format_version()?, FormatVersion::V3);
+        assert!(!v3_reader.header.format_version()?.has_encoding_table());
+        assert_eq!(v3_reader.header.metadata_offset(), 20);
+        assert_eq!(v3_reader.header.data_offset, 240);
+        assert_eq!(v3_reader.record_types, pulse_reader.record_types);
+        assert_eq!(v3_reader.raw_metadata, pulse_reader.raw_metadata);
+        assert!(validate_file(&v3_path)?.is_valid());
+        assert_eq!(v3_reader.index.apertures, pulse_reader.index.apertures);
+        for &ap in pulse_reader.index.apertures.iter() {
+            assert_eq!(
+                v3_reader.get_all_records(ap)?.0,
+                pulse_reader.get_all_records(ap)?.0
+            );
+        }
+        let mmap_reader = PulseReader::open_mmap(&v3_path)?;
+        assert_eq!(mmap_reader.index.apertures, pulse_reader.index.apertures);
+
+        // Copies are always written in the latest version
+        let v4_path = temp_dir.path().join("pulses_v4.bin");
+        v3_reader.copy_apertures_to_new_file(
+            &v3_reader.index.apertures,
+            &v4_path,
+            OverwritePolicy::Fail,
+        )?;
+        let v4_reader = PulseReader::open(&v4_path)?;
+        assert_eq!(v4_reader.header.format_version()?, FormatVersion::V4);
+        assert_eq!(v4_reader.raw_metadata, pulse_reader.raw_metadata);
+        for &ap in pulse_reader.index.apertures.iter() {
+            assert_eq!(
+                v4_reader.get_all_records(ap)?.0,
+                pulse_reader.get_all_records(ap)?.0
+            );
+        }
+
+        // Unknown versions are rejected
+        for version in [2u32, 5] {
+            let mut bad_version = bytes.clone();
+            bad_version[4..8].copy_from_slice(&version.to_le_bytes());
+            let path = temp_dir.path().join(format!("pulses_v{}.bin", version));
+            std::fs::write(&path, &bad_version)?;
+            let err = PulseReader::open(&path).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<PulseError>(),
+                Some(PulseError::UnsupportedEncoding { value, .. }) if *value == version as u64
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reordered_encoding_table() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+
+        // Reverse the encoding table and append an encoding for an unknown field
+        let mut record_types = pulse_reader.record_types.clone();
+        record_types.reverse();
+        record_types.push(PulseRecordType::new(&[9, 3, 0, 0])?);
+        // A scale that can't be represented is rejected rather than overflowing
+        assert!(matches!(
+            PulseRecordType::new(&[2, 16, 0, 0]),
+            Err(PulseError::UnsupportedEncoding { value: 16, .. })
+        ));
+        let new_pulse_file_path = dir.path().join("reordered.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &new_pulse_file_path,
+            &pulse_reader.metadata,
+            &record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for ap in pulse_reader.index.apertures.iter() {
+            let (records, aperture_header) = pulse_reader.get_raw_records(*ap)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()?;
+
+        let new_pulse_reader = PulseReader::open(&new_pulse_file_path)?;
+        assert_eq!(new_pulse_reader.header.num_encoding_records, 9);
+        assert_eq!(new_pulse_reader.record_types, record_types);
+        assert!(validate_file(&new_pulse_file_path)?.is_valid());
+        for ap in pulse_reader.index.apertures.iter() {
+            let (records, _) = pulse_reader.get_all_records(*ap)?;
+            let (new_records, _) = new_pulse_reader.get_all_records(*ap)?;
+            assert_eq!(records, new_records);
+
+            // The table-based conversions look up the fields by id, not position
+            let (raw_records, _) = new_pulse_reader.get_raw_records(*ap)?;
+            for (raw, record) in raw_records.iter().zip(records.iter()) {
+                #[allow(deprecated)]
+                {
+                    assert_eq!(
+                        FormattedRecord::from_raw(raw, &record_types, record.index),
+                        *record
+                    );
+                    assert_eq!(RawRecord::from_formatted(record, &record_types), *raw);
+                }
+            }
+        }
+
+        // Missing and duplicated encodings for formatted fields are rejected
+        let ap = pulse_reader.index.apertures[0];
+        let (records, aperture_header) = pulse_reader.get_raw_records(ap)?;
+        // The table-based conversions leave fields without an encoding unscaled
+        let (formatted_records, _) = pulse_reader.get_all_records(ap)?;
+        for (raw, record) in records.iter().zip(formatted_records.iter()) {
+            #[allow(deprecated)]
+            let unscaled = FormattedRecord::from_raw(raw, &[], record.index);
+            assert_eq!(unscaled.record_type, record.record_type);
+            if unscaled.record_type == FormattedRecordType::Pulse {
+                assert_eq!(unscaled.intensity1, raw.m1 as f32);
+            }
+            #[allow(deprecated)]
+            let reencoded = RawRecord::from_formatted(&unscaled, &[]);
+            assert_eq!(reencoded, *raw);
+        }
+        for record_types in [
+            record_types[1..].to_vec(),
+            [record_types.clone(), vec![record_types[0].clone()]].concat(),
+        ] {
+            let bad_pulse_file_path = dir.path().join("bad.pulses.bin");
+            let mut writer = PulseFileWriter::create(
+                &bad_pulse_file_path,
+                &pulse_reader.metadata,
+                &record_types,
+                OverwritePolicy::Overwrite,
+            )?;
+            writer.write_aperture(&aperture_header, &records)?;
+            writer.finish()?;
+            let err = PulseReader::open(&bad_pulse_file_path).err().unwrap();
+            assert!(matches!(
+                err.downcast_ref::<PulseError>(),
+                Some(PulseError::UnsupportedEncoding { .. })
+            ));
+            let report = validate_file(&bad_pulse_file_path)?;
+            assert!(
+                report
+                    .issues
+                    .iter()
+                    .any(|issue| issue.kind == IssueKind::InvalidHeader)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_metadata() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let run_metadata = &pulse_reader.run_metadata;
+        assert_eq!(
+            run_metadata.fps,
+            Some(pulse_reader.metadata["fps"].as_f64().unwrap())
+        );
+        assert_eq!(run_metadata.duration, Some(36000.0));
+        assert_eq!(run_metadata.roi_offset_col, Some(0));
+        assert_eq!(run_metadata.valid_wells, Some(866105));
+        assert!(run_metadata.pulse_caller.is_none());
+        assert!(run_metadata.extra.is_empty());
+
+        // Unknown keys are kept, and missing keys are None
+        let metadata = serde_json::json!({
+            "fps": 10.0,
+            "chip": "A1",
+            "pulseCaller": {
+                "version": "2.0",
+                "options": ["trim_boundary_frames"],
+            },
+        });
+        let run_metadata = RunMetadata::from_value(&metadata)?;
+        assert!(run_metadata.trimmed());
+        assert_eq!(run_metadata.rows, None);
+        assert_eq!(run_metadata.extra["chip"], "A1");
+        let pulse_caller = run_metadata.pulse_caller.as_ref().unwrap();
+        assert_eq!(pulse_caller.extra["version"], "2.0");
+        assert_eq!(serde_json::to_value(&run_metadata)?, metadata);
+
+        // Fields with the wrong type are read as missing, and only fail when they are needed
+        let metadata = serde_json::json!({
+            "fps": 10.0,
+            "rows": 1e3,
+            "roi_offset_col": -1,
+            "pulseCaller": {"options": [1, "trim_boundary_frames", null]},
+        });
+        let run_metadata = RunMetadata::from_value(&metadata)?;
+        assert_eq!(run_metadata.fps, Some(10.0));
+        assert_eq!(run_metadata.rows, None);
+        assert_eq!(run_metadata.roi_offset_col, None);
+        // Options that aren't strings are skipped one at a time
+        assert!(run_metadata.trimmed());
+        let pulse_caller = run_metadata.pulse_caller.as_ref().unwrap();
+        assert_eq!(pulse_caller.options, vec!["trim_boundary_frames"]);
+        let metadata = serde_json::json!({"pulseCaller": {"options": "trim_boundary_frames"}});
+        assert!(!RunMetadata::from_value(&metadata)?.trimmed());
+        let err = run_metadata.roi_position(0, 0).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::MetadataField { name }) if name == "roi_offset_col"
+        ));
+        assert!(RunMetadata::from_value(&serde_json::json!([1, 2])).is_err());
+
+        // Files with mistyped optional fields can still be opened
+        let mut metadata = pulse_reader.metadata.clone();
+        metadata["rows"] = serde_json::json!(1e3);
+        let temp_dir = tempdir()?;
+        let path = temp_dir.path().join("mistyped.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &path,
+            &metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for ap in pulse_reader.index.apertures.iter() {
+            let (records, aperture_header) = pulse_reader.get_raw_records(*ap)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()?;
+        let mistyped_reader = PulseReader::open(&path)?;
+        assert_eq!(mistyped_reader.run_metadata.rows, None);
+        assert_eq!(mistyped_reader.metadata["rows"], 1e3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_incompatible_files() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+
+        // Write a copy with a different frame rate and encoding, and missing a field
+        let mut metadata = pulse_reader.metadata.clone();
+        metadata["fps"] = serde_json::json!(20.0);
+        metadata.as_object_mut().unwrap().remove("validWells");
+        let mut record_types = pulse_reader.record_types.clone();
+        record_types[2] = PulseRecordType::new(&[2, 6, 245, 0])?;
+        let other_file_path = dir.path().join("other.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &other_file_path,
+            &metadata,
+            &record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for ap in pulse_reader.index.apertures.iter() {
+            let (records, aperture_header) = pulse_reader.get_raw_records(*ap)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()?;
+
+        let pulse_readers = vec![pulse_reader, PulseReader::open(&other_file_path)?];
+        let new_file_path = dir.path().join("merged.pulses.bin");
+        let err = merge_pulse_files(&pulse_readers, &new_file_path, OverwritePolicy::Fail)
+            .err()
+            .unwrap();
+        assert!(!new_file_path.exists());
+        let Some(PulseError::IncompatibleInputs { conflicts }) = err.downcast_ref::<PulseError>()
+        else {
+            panic!("Unexpected error: {}", err);
+        };
+        let conflict = |property, expected: Option<&str>, found: Option<&str>| MergeConflict {
+            input: 1,
+            file_name: other_file_path.clone(),
+            property,
+            expected: expected.map(str::to_string),
+            found: found.map(str::to_string),
+        };
+        assert_eq!(conflicts.len(), 3);
+        assert_eq!(conflicts[0].property, "encoding records");
+        assert_eq!(
+            conflicts[1],
+            conflict("fps", Some("16.675420168067227"), Some("20"))
+        );
+        assert_eq!(conflicts[2], conflict("validWells", None, None));
+        assert!(err.to_string().contains("is missing validWells"));
+
+        // Conflicts name the input that is missing the property
+        let file_name = other_file_path.display();
+        assert_eq!(
+            conflict("fps", None, Some("20")).to_string(),
+            format!(
+                "input 1 ({}) has fps 20, but input 0 is missing it",
+                file_name
+            )
+        );
+        assert_eq!(
+            conflict("fps", Some("20"), None).to_string(),
+            format!("input 1 ({}) is missing fps, but input 0 has 20", file_name)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_layout() -> Result<()> {
+        let pulse_readers: Vec<PulseReader> =
+            (0..4).map(|_| get_pulse_reader()).collect::<Result<_>>()?;
+        let rows = pulse_readers[0].run_metadata.rows.unwrap();
+        let cols = pulse_readers[0].run_metadata.cols.unwrap();
+        let dir = tempdir()?;
+
+        // Tile the inputs in a 2x2 grid
+        let offsets = vec![(0, 0), (0, cols), (rows, 0), (rows, cols)];
+        let new_file_path = dir.path().join("grid.pulses.bin");
+        let layout = MergeLayout::Offsets(offsets.clone());
+        merge_pulse_files_with_layout(
+            &pulse_readers,
+            &new_file_path,
+            &layout,
+            OverwritePolicy::Fail,
+        )?;
+        assert!(validate_file(&new_file_path)?.is_valid());
+
+        let merged_reader = PulseReader::open(&new_file_path)?;
+        let run_metadata = &merged_reader.run_metadata;
+        assert_eq!(run_metadata.rows, Some(2 * rows));
+        assert_eq!(run_metadata.cols, Some(2 * cols));
+        assert_eq!(run_metadata.roi_offset_col, Some(0));
+        assert_eq!(run_metadata.roi_cols, Some(cols + 1024));
+        assert_eq!(
+            merged_reader.index.apertures.len(),
+            4 * pulse_readers[0].index.apertures.len()
+        );
+        for (row, col) in offsets {
+            for ap in pulse_readers[0].index.apertures.iter() {
+                let (records, aperture_header) = pulse_readers[0].get_all_records(*ap)?;
+                let x = aperture_header.x + col;
+                let y = aperture_header.y + row;
+                let new_ap = (y * 2 * cols + x) as usize;
+                let (new_records, new_aperture_header) = merged_reader.get_all_records(new_ap)?;
+                assert_eq!((new_aperture_header.x, new_aperture_header.y), (x, y));
+                assert_eq!(records, new_records);
+            }
+        }
+
+        // Overlapping inputs, a mismatched number of offsets and offsets that overflow
+        // the position or well_id of an aperture are rejected
+        let new_file_path = dir.path().join("bad.pulses.bin");
+        for offsets in [
+            vec![(0, 0), (rows - 1, cols - 1)],
+            vec![(0, 0)],
+            vec![(0, 0), (u32::MAX - 1, 0)],
+            vec![(0, 0), (0, u32::MAX - 1)],
+            vec![(0, 0), (u32::MAX / cols, 0)],
+        ] {
+            let layout = MergeLayout::Offsets(offsets);
+            let err = merge_pulse_files_with_layout(
+                &pulse_readers[..2],
+                &new_file_path,
+                &layout,
+                OverwritePolicy::Fail,
+            )
+            .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<PulseError>(),
+                Some(PulseError::InvalidArgument { .. })
+            ));
+            assert!(!new_file_path.exists());
+        }
+
+        // Stacked inputs keep their well_ids, offset by the apertures of the inputs above
+        let new_file_path = dir.path().join("vertical.pulses.bin");
+        merge_pulse_files_with_layout(
+            &pulse_readers[..2],
+            &new_file_path,
+            &MergeLayout::Vertical,
+            OverwritePolicy::Fail,
+        )?;
+        let merged_reader = PulseReader::open(&new_file_path)?;
+        for ap in pulse_readers[0].index.apertures.iter() {
+            let (records, aperture_header) = pulse_readers[0].get_all_records(*ap)?;
+            let new_ap = *ap + (rows * cols) as usize;
+            let (new_records, new_aperture_header) = merged_reader.get_all_records(new_ap)?;
+            assert_eq!(new_aperture_header.well_id as usize, new_ap);
+            assert_eq!(
+                (new_aperture_header.x, new_aperture_header.y),
+                (aperture_header.x, aperture_header.y + rows)
+            );
+            assert_eq!(records, new_records);
+        }
+
+        // Stacking doesn't need the rows of the region of interest, which then spans the
+        // combined chip, but placing inputs at offsets does
+        let mut metadata = pulse_readers[0].metadata.clone();
+        let fields = metadata.as_object_mut().unwrap();
+        fields.remove("roi_offset_row");
+        fields.remove("roi_rows");
+        let no_roi_rows_path = dir.path().join("no_roi_rows.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &no_roi_rows_path,
+            &metadata,
+            &pulse_readers[0].record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for ap in pulse_readers[0].index.apertures.iter() {
+            let (records, aperture_header) = pulse_readers[0].get_raw_records(*ap)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()?;
+        let no_roi_rows = vec![
+            PulseReader::open(&no_roi_rows_path)?,
+            PulseReader::open(&no_roi_rows_path)?,
+        ];
+        let new_file_path = dir.path().join("vertical_no_roi_rows.pulses.bin");
+        merge_pulse_files(&no_roi_rows, &new_file_path, OverwritePolicy::Fail)?;
+        let run_metadata = PulseReader::open(&new_file_path)?.run_metadata;
+        assert_eq!(run_metadata.roi_offset_row, Some(0));
+        assert_eq!(run_metadata.roi_rows, Some(2 * rows));
+        assert_eq!(
+            run_metadata.roi_offset_col,
+            pulse_readers[0].run_metadata.roi_offset_col
+        );
+        assert_eq!(
+            run_metadata.roi_cols,
+            pulse_readers[0].run_metadata.roi_cols
+        );
+
+        let layout = MergeLayout::Offsets(vec![(0, 0), (rows, 0)]);
+        let err = check_merge_compatibility(&no_roi_rows, &layout).unwrap_err();
+        let PulseError::IncompatibleInputs { conflicts } = err else {
+            panic!("Unexpected error: {}", err);
+        };
+        let properties: Vec<&str> = conflicts.iter().map(|c| c.property).collect();
+        assert_eq!(
+            properties,
+            ["roi_offset_row", "roi_rows", "roi_offset_row", "roi_rows"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_concat_pulse_files_in_time() -> Result<()> {
+        let pulse_readers = vec![get_pulse_reader()?, get_pulse_reader()?];
+        let dir = tempdir()?;
+        let new_file_path = dir.path().join("concat.pulses.bin");
+        concat_pulse_files_in_time(&pulse_readers, &new_file_path, OverwritePolicy::Fail)?;
+        assert!(validate_file(&new_file_path)?.is_valid());
+
+        let concat_reader = PulseReader::open(&new_file_path)?;
+        let run_metadata = &pulse_readers[0].run_metadata;
+        let duration = run_metadata.duration.unwrap();
+        assert_eq!(concat_reader.run_metadata.duration, Some(2.0 * duration));
+        assert_eq!(
+            concat_reader.index.apertures,
+            pulse_readers[0].index.apertures
+        );
+
+        // The pulses of the second input follow those of the first, offset by its duration
+        let num_frames = (duration * run_metadata.fps.unwrap()).ceil() as u32;
+        for ap in pulse_readers[0].index.apertures.iter() {
+            let (pulses, _) = pulse_readers[0].get_pulses(*ap, None)?;
+            let (concat_pulses, _) = concat_reader.get_pulses(*ap, None)?;
+            assert_eq!(concat_pulses.len(), 2 * pulses.len());
+            assert!(concat_pulses.windows(2).all(|w| w[0].end_f <= w[1].start_f));
+            for (i, pulse) in pulses.iter().chain(pulses.iter()).enumerate() {
+                let offset = if i < pulses.len() { 0 } else { num_frames };
+                assert_eq!(concat_pulses[i].start_f, pulse.start_f + offset);
+                assert_eq!(concat_pulses[i].dur_f, pulse.dur_f);
+                assert_eq!(concat_pulses[i].intensity, pulse.intensity);
+            }
+        }
+
+        // Inputs with a different number of rows are rejected
+        let mut metadata = pulse_readers[0].metadata.clone();
+        metadata["rows"] = serde_json::json!(512);
+        let other_file_path = dir.path().join("other.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &other_file_path,
+            &metadata,
+            &pulse_readers[0].record_types,
+            OverwritePolicy::Fail,
+        )?;
+        let (records, aperture_header) =
+            pulse_readers[0].get_raw_records(pulse_readers[0].index.apertures[0])?;
+        writer.write_aperture(&aperture_header, &records)?;
+        writer.finish()?;
+        let pulse_readers = vec![get_pulse_reader()?, PulseReader::open(&other_file_path)?];
+        let new_file_path = dir.path().join("bad.pulses.bin");
+        let err = concat_pulse_files_in_time(&pulse_readers, &new_file_path, OverwritePolicy::Fail)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::IncompatibleInputs { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwrite_policy() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let temp_dir = tempdir()?;
+        let new_file_path = temp_dir.path().join("pulses.bin");
+        std::fs::write(&new_file_path, b"existing")?;
+
+        // An existing file is left untouched unless overwriting is allowed
+        let apertures = pulse_reader.index.apertures[0..5].to_vec();
+        let err = pulse_reader
+            .copy_apertures_to_new_file(&apertures, &new_file_path, OverwritePolicy::Fail)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(&new_file_path)?, b"existing");
+        pulse_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Overwrite,
+        )?;
+        assert_eq!(
+            PulseReader::open(&new_file_path)?.index.apertures,
+            apertures
+        );
+
+        // A writer that is dropped or fails before it finishes leaves nothing behind
+        let unfinished_path = temp_dir.path().join("unfinished.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &unfinished_path,
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        let (records, ap_header) = pulse_reader.get_raw_records(apertures[0])?;
+        writer.write_aperture(&ap_header, &records)?;
+        drop(writer);
+        let mut writer = PulseFileWriter::create(
+            &new_file_path,
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Overwrite,
+        )?;
+        writer.write_aperture(&ap_header, &records)?;
+        writer.write_aperture(&ap_header, &records)?;
+        assert!(writer.finish().is_err());
+        assert_eq!(
+            PulseReader::open(&new_file_path)?.index.apertures,
+            apertures
+        );
+        let file_names: Vec<_> = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(file_names, vec!["pulses.bin"]);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_written_file_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let pulse_reader = get_pulse_reader()?;
+        let temp_dir = tempdir()?;
+        let mode = |path: &std::path::Path| -> Result<u32> {
+            Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+
+        // New files get the same permissions as any other file created under the umask
+        let created_path = temp_dir.path().join("created");
+        std::fs::File::create(&created_path)?;
+        let new_file_path = temp_dir.path().join("pulses.bin");
+        let apertures = pulse_reader.index.apertures[0..5].to_vec();
+        pulse_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Fail,
+        )?;
+        assert_eq!(mode(&new_file_path)?, mode(&created_path)?);
+
+        // Replaced files keep their permissions
+        std::fs::set_permissions(&new_file_path, std::fs::Permissions::from_mode(0o640))?;
+        pulse_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Overwrite,
+        )?;
+        assert_eq!(mode(&new_file_path)?, 0o640);
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_apertures() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+
+        let bounding_box = ApertureSelection::BoundingBox {
+            min_x: 200,
+            min_y: 200,
+            max_x: 500,
+            max_y: 800,
+        };
+        let apertures = pulse_reader.select_apertures(&bounding_box)?;
+        assert_eq!(apertures, vec![565499, 1315058, 1585622]);
+
+        // Points on the edge of the polygon are selected
+        let triangle = ApertureSelection::Polygon(vec![(0.0, 0.0), (1000.0, 0.0), (0.0, 1000.0)]);
+        assert_eq!(
+            pulse_reader.select_apertures(&triangle)?,
+            vec![221939, 262820, 565499, 836131, 1315058]
+        );
+        let edge = ApertureSelection::Polygon(vec![(676.0, 128.0), (676.0, 0.0), (0.0, 0.0)]);
+        assert_eq!(pulse_reader.select_apertures(&edge)?, vec![262820]);
+
+        let left = pulse_reader.select_apertures(&ApertureSelection::ChipHalf(ChipHalf::Left))?;
+        assert_eq!(left, pulse_reader.index.apertures);
+        let right = pulse_reader.select_apertures(&ApertureSelection::ChipHalf(ChipHalf::Right))?;
+        assert!(right.is_empty());
+
+        // Apertures that are not in the file are ignored
+        let csv_path = dir.path().join("apertures.csv");
+        std::fs::write(&csv_path, "aperture,label\n565499,a\n999,b\n262820,c\n")?;
+        let selection = ApertureSelection::from_csv(&csv_path)?;
+        assert_eq!(
+            selection,
+            ApertureSelection::Indices(vec![565499, 999, 262820])
+        );
+        assert_eq!(
+            pulse_reader.select_apertures(&selection)?,
+            vec![262820, 565499]
+        );
+        std::fs::write(&csv_path, "y,x\n276,251\n")?;
+        let selection = ApertureSelection::from_csv(&csv_path)?;
+        assert_eq!(pulse_reader.select_apertures(&selection)?, vec![565499]);
+        std::fs::write(&csv_path, "well\n565499\n")?;
+        assert!(ApertureSelection::from_csv(&csv_path).is_err());
+
+        // The metadata of a subset describes the subset
+        let new_file_path = dir.path().join("subset.pulses.bin");
+        pulse_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Fail,
+        )?;
+        assert!(validate_file(&new_file_path)?.is_valid());
+        let subset_reader = PulseReader::open(&new_file_path)?;
+        let run_metadata = &subset_reader.run_metadata;
+        assert_eq!(run_metadata.rows, Some(775));
+        assert_eq!(run_metadata.cols, pulse_reader.run_metadata.cols);
+        // The valid wells of each half are scaled by the share of its apertures kept, or of
+        // all apertures for the right half, which has none in the file, and validWells stays
+        // the mean of the two halves
+        assert_eq!(run_metadata.valid_wells, Some(259832));
+        assert_eq!(run_metadata.valid_wells_left, Some(259588));
+        assert_eq!(run_metadata.valid_wells_right, Some(260075));
+        assert_eq!(run_metadata.roi_rows, Some(499));
+        assert_eq!(run_metadata.roi_cols, Some(229));
+        assert_eq!(run_metadata.roi_offset_row, Some(276));
+        assert_eq!(run_metadata.roi_offset_col, Some(242));
+        assert_eq!(run_metadata.fps, pulse_reader.run_metadata.fps);
+
+        // The counts change continuously from a copy of every aperture, which keeps them
+        let counts = |reader: &PulseReader| {
+            let run_metadata = &reader.run_metadata;
+            (
+                run_metadata.valid_wells,
+                run_metadata.valid_wells_left,
+                run_metadata.valid_wells_right,
+            )
+        };
+        for (num_apertures, expected) in [
+            (10, (866105, 865292, 866918)),
+            (9, (779495, 778763, 780226)),
+        ] {
+            let new_file_path = dir
+                .path()
+                .join(format!("subset_{num_apertures}.pulses.bin"));
+            pulse_reader.copy_apertures_to_new_file(
+                &pulse_reader.index.apertures[..num_apertures],
+                &new_file_path,
+                OverwritePolicy::Fail,
+            )?;
+            let (all, left, right) = expected;
+            assert_eq!(
+                counts(&PulseReader::open(&new_file_path)?),
+                (Some(all), Some(left), Some(right))
+            );
+        }
+
+        // Without the chip layout, the metadata is copied unchanged
+        let mut metadata = pulse_reader.metadata.clone();
+        metadata.as_object_mut().unwrap().remove("cols");
+        let no_cols_path = dir.path().join("no_cols.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &no_cols_path,
+            &metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for ap in pulse_reader.index.apertures.iter() {
+            let (records, aperture_header) = pulse_reader.get_raw_records(*ap)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()?;
+        let no_cols_reader = PulseReader::open(&no_cols_path)?;
+        let new_file_path = dir.path().join("no_cols_subset.pulses.bin");
+        no_cols_reader.copy_apertures_to_new_file(
+            &apertures,
+            &new_file_path,
+            OverwritePolicy::Fail,
+        )?;
+        let subset_reader = PulseReader::open(&new_file_path)?;
+        assert_eq!(subset_reader.raw_metadata, no_cols_reader.raw_metadata);
+        assert_eq!(
+            subset_reader.index.apertures,
+            vec![565499, 1315058, 1585622]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_filtered() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+        let pulse_filter = PulseFilter {
+            min_dur_f: Some(3),
+            min_snr: Some(5.0),
+            recalc_ipd: true,
+            ..Default::default()
+        };
+
+        // Only passing pulses are kept, and they keep their original timing
+        let new_file_path = dir.path().join("filtered.pulses.bin");
+        pulse_reader.write_filtered(&pulse_filter, &new_file_path, false, OverwritePolicy::Fail)?;
+        assert!(validate_file(&new_file_path)?.is_valid());
+        let filtered_reader = PulseReader::open(&new_file_path)?;
+        assert_eq!(
+            filtered_reader.index.apertures,
+            pulse_reader.index.apertures
+        );
+        for &ap in pulse_reader.index.apertures.iter() {
+            let (expected, _) = pulse_reader.get_pulses(ap, Some(&pulse_filter))?;
+            let (pulses, _) = filtered_reader.get_pulses(ap, None)?;
+            let (records, _) = filtered_reader.get_all_records(ap)?;
+            assert!(
+                records
+                    .iter()
+                    .all(|record| record.record_type == FormattedRecordType::Pulse)
+            );
+            assert_eq!(pulses.len(), expected.len());
+            for (pulse, expected) in pulses.iter().zip(expected.iter()) {
+                let pulse = NormalizedPulse {
+                    index: expected.index,
+                    ..pulse.clone()
+                };
+                assert_eq!(format!("{:?}", pulse), format!("{:?}", expected));
+            }
+        }
+        let pulse_filters = &filtered_reader.metadata["pulseFilters"];
+        assert_eq!(
+            pulse_filters.as_array().map(|filters| filters.len()),
+            Some(1)
+        );
+        assert_eq!(pulse_filters[0]["min_dur_f"], 3);
+        assert_eq!(pulse_filters[0]["keep_non_pulse_records"], false);
+
+        // Non-pulse records can be kept, and filters applied to a filtered file are appended
+        let kept_file_path = dir.path().join("kept.pulses.bin");
+        pulse_reader.write_filtered(&pulse_filter, &kept_file_path, true, OverwritePolicy::Fail)?;
+        let kept_reader = PulseReader::open(&kept_file_path)?;
+        let non_pulse_records = |reader: &PulseReader, ap| -> Result<Vec<(u32, String)>> {
+            let (records, _) = reader.get_all_records(ap)?;
+            let mut normalizer = PulseNormalizer::new(reader.fps);
+            let mut non_pulse_records = Vec::new();
+            for record in records {
+                // Compare the frame and contents of each record, which are preserved
+                if normalizer.push(&record).is_none()
+                    && record.record_type != FormattedRecordType::Padding
+                {
+                    non_pulse_records.push((
+                        normalizer.last_record_end(),
+                        format!(
+                            "{:?}",
+                            FormattedRecord {
+                                index: 0,
+                                frames_since_last: 0,
+                                ..record
+                            }
+                        ),
+                    ));
+                }
+            }
+            Ok(non_pulse_records)
+        };
+        for &ap in pulse_reader.index.apertures.iter() {
+            assert_eq!(
+                non_pulse_records(&kept_reader, ap)?,
+                non_pulse_records(&pulse_reader, ap)?
+            );
+        }
+        let refiltered_path = dir.path().join("refiltered.pulses.bin");
+        kept_reader.write_filtered(
+            &PulseFilter::default(),
+            &refiltered_path,
+            true,
+            OverwritePolicy::Fail,
+        )?;
+        let refiltered_reader = PulseReader::open(&refiltered_path)?;
+        assert_eq!(
+            refiltered_reader.metadata["pulseFilters"]
+                .as_array()
+                .map(|filters| filters.len()),
+            Some(2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_cropped() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+        let fps = pulse_reader.fps as f64;
+
+        // Place the window boundaries inside pulses of the first aperture
+        let (pulses, _) = pulse_reader.get_pulses(pulse_reader.index.apertures[0], None)?;
+        let mut long_pulses = pulses.iter().filter(|pulse| pulse.dur_f > 1);
+        let first = long_pulses.next().unwrap();
+        let last = long_pulses.next_back().unwrap();
+        let start_s = (first.start_f + 1) as f64 / fps;
+        let end_s = (last.start_f + 1) as f64 / fps;
+        let window_start = (start_s * fps).round() as u32;
+        let window_end = (end_s * fps).round() as u32;
+
+        // With BoundaryPolicy::Keep, the cropped file starts with the earliest pulse that
+        // straddles the start of the window
+        let mut keep_origin = window_start;
+        for &ap in pulse_reader.index.apertures.iter() {
+            let (pulses, _) = pulse_reader.get_pulses(ap, None)?;
+            for pulse in pulses.iter() {
+                if pulse.start_f < window_start && pulse.end_f > window_start {
+                    keep_origin = keep_origin.min(pulse.start_f);
+                }
+            }
+        }
+        assert!(keep_origin <= first.start_f);
+
+        let mut num_straddling = 0;
+        for boundary in [
+            BoundaryPolicy::Drop,
+            BoundaryPolicy::Clip,
+            BoundaryPolicy::Keep,
+        ] {
+            let new_file_path = dir.path().join(format!("{:?}.pulses.bin", boundary));
+            pulse_reader.write_cropped(
+                start_s,
+                end_s,
+                boundary,
+                &new_file_path,
+                OverwritePolicy::Fail,
+            )?;
+            let report = validate_file(&new_file_path)?;
+            assert!(report.is_valid(), "{:?}", report.issues);
+            let cropped_reader = PulseReader::open(&new_file_path)?;
+            let duration = cropped_reader.run_metadata.duration.unwrap();
+            let origin = if boundary == BoundaryPolicy::Keep {
+                keep_origin
+            } else {
+                window_start
+            };
+            if boundary == BoundaryPolicy::Keep {
+                assert!(duration > (window_end - keep_origin) as f64 / fps);
+            } else {
+                assert!((duration - (end_s - start_s)).abs() < 1e-9);
+            }
+
+            for &ap in pulse_reader.index.apertures.iter() {
+                let (pulses, _) = pulse_reader.get_pulses(ap, None)?;
+                let mut expected = Vec::new();
+                for pulse in pulses.iter() {
+                    if pulse.end_f <= window_start || pulse.start_f >= window_end {
+                        continue;
+                    }
+                    let straddles = pulse.start_f < window_start || pulse.end_f > window_end;
+                    num_straddling += straddles as usize;
+                    let (start, end) = match boundary {
+                        BoundaryPolicy::Drop if straddles => continue,
+                        BoundaryPolicy::Clip => {
+                            (pulse.start_f.max(window_start), pulse.end_f.min(window_end))
+                        }
+                        _ => (pulse.start_f, pulse.end_f),
+                    };
+                    let (start, end) = (start - origin, end - origin);
+                    expected.push((start, end, pulse.intensity));
+                }
+                let (cropped_pulses, _) = cropped_reader.get_pulses(ap, None)?;
+                let cropped_pulses: Vec<_> = cropped_pulses
+                    .iter()
+                    .map(|pulse| (pulse.start_f, pulse.end_f, pulse.intensity))
+                    .collect();
+                assert_eq!(cropped_pulses, expected);
+            }
+        }
+        assert!(num_straddling > 0);
+
+        let new_file_path = dir.path().join("invalid.pulses.bin");
+        for (start_s, end_s) in [(-1.0, 10.0), (10.0, 10.0), (40000.0, 50000.0)] {
+            assert!(
+                pulse_reader
+                    .write_cropped(
+                        start_s,
+                        end_s,
+                        BoundaryPolicy::Drop,
+                        &new_file_path,
+                        OverwritePolicy::Fail,
+                    )
+                    .is_err()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_into_shards() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+
+        let manifest = pulse_reader.split_into_shards(3, dir.path(), OverwritePolicy::Fail)?;
+        let manifest_path = dir.path().join("pulses_shards.json");
+        assert_eq!(ShardManifest::load(&manifest_path)?, manifest);
+        assert_eq!(manifest.shards.len(), 3);
+        assert_eq!(manifest.num_apertures, pulse_reader.index.apertures.len());
+        let shards = manifest
+            .shards
+            .iter()
+            .map(|shard| PulseReader::open(dir.path().join(&shard.file_name)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // The shards hold contiguous runs of apertures in index order, at their original
+        // positions and with their original well IDs
+        let mut shard_apertures = Vec::new();
+        for (shard, shard_reader) in manifest.shards.iter().zip(shards.iter()) {
+            assert!(validate_file(&shard_reader.file_name)?.is_valid());
+            let apertures = &shard_reader.index.apertures;
+            assert_eq!(apertures.len(), shard.num_apertures);
+            assert_eq!(apertures.first(), Some(&shard.first_aperture));
+            assert_eq!(apertures.last(), Some(&shard.last_aperture));
+            for &ap in apertures.iter() {
+                let header = pulse_reader.get_aperture_header(ap)?;
+                let shard_header = shard_reader.get_aperture_header(ap)?;
+                assert_eq!(
+                    (shard_header.x, shard_header.y, shard_header.num_pulses),
+                    (header.x, header.y, header.num_pulses)
+                );
+            }
+            shard_apertures.extend_from_slice(apertures);
+        }
+        assert_eq!(shard_apertures, pulse_reader.index.apertures);
+
+        // The largest aperture is much larger than the rest, so it gets a shard of its own
+        let largest = manifest.shards.iter().map(|shard| shard.num_bytes).max();
+        assert_eq!(largest, Some(manifest.shards[1].num_bytes));
+        assert_eq!(manifest.shards[1].num_apertures, 1);
+
+        // The valid well counts of each shard are its share of those of the file, and add
+        // up to them, up to rounding
+        let mut valid_wells = [0; 3];
+        for shard_reader in shards.iter() {
+            let run_metadata = &shard_reader.run_metadata;
+            let num_apertures = shard_reader.index.apertures.len() as f64;
+            let counts = [
+                run_metadata.valid_wells.unwrap(),
+                run_metadata.valid_wells_left.unwrap(),
+                run_metadata.valid_wells_right.unwrap(),
+            ];
+            assert_eq!(counts[1], (865292.0 * num_apertures / 10.0).round() as u64);
+            assert_eq!(counts[2], (866918.0 * num_apertures / 10.0).round() as u64);
+            for (total, count) in valid_wells.iter_mut().zip(counts) {
+                *total += count;
+            }
+        }
+        for (total, expected) in valid_wells.iter().zip([866105, 865292, 866918]) {
+            assert!(total.abs_diff(expected) <= 1);
+        }
+
+        // Merging the shards restores the original file exactly
+        let reassembled_path = dir.path().join("reassembled.pulses.bin");
+        merge_shards(&manifest_path, &reassembled_path, OverwritePolicy::Fail)?;
+        let reassembled_reader = PulseReader::open(&reassembled_path)?;
+        assert_eq!(reassembled_reader.raw_metadata, pulse_reader.raw_metadata);
+        assert_eq!(
+            reassembled_reader.index.apertures,
+            pulse_reader.index.apertures
+        );
+        for &ap in pulse_reader.index.apertures.iter() {
+            let (records, header) = pulse_reader.get_raw_records(ap)?;
+            let (merged_records, merged_header) = reassembled_reader.get_raw_records(ap)?;
+            assert_eq!(merged_records, records);
+            assert_eq!(
+                (merged_header.x, merged_header.y, merged_header.well_id),
+                (header.x, header.y, header.well_id)
+            );
+        }
+
+        // Every aperture can have a shard of its own
+        let single_dir = tempdir()?;
+        let num_apertures = pulse_reader.index.apertures.len();
+        let manifest = pulse_reader.split_into_shards(
+            num_apertures,
+            single_dir.path(),
+            OverwritePolicy::Fail,
+        )?;
+        assert!(manifest.shards.iter().all(|shard| shard.num_apertures == 1));
+
+        // Shards that don't match the manifest are rejected
+        let mut manifest = ShardManifest::load(&manifest_path)?;
+        manifest.shards.swap(0, 2);
+        std::fs::write(&manifest_path, manifest.to_json()?)?;
+        let swapped_path = dir.path().join("swapped.pulses.bin");
+        assert!(merge_shards(&manifest_path, &swapped_path, OverwritePolicy::Fail).is_err());
+        assert!(!swapped_path.exists());
+
+        // Nothing is written if any output exists
+        let other_dir = tempdir()?;
+        std::fs::write(other_dir.path().join("pulses_shards.json"), "{}")?;
+        assert!(
+            pulse_reader
+                .split_into_shards(3, other_dir.path(), OverwritePolicy::Fail)
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(other_dir.path())?.count(), 1);
+        for num_shards in [0, pulse_reader.index.apertures.len() + 1] {
+            assert!(
+                pulse_reader
+                    .split_into_shards(num_shards, other_dir.path(), OverwritePolicy::Overwrite)
+                    .is_err()
+            );
+        }
+
+        // The shards that were written are removed if a later output can't be written,
+        // except for those that replaced an existing file
+        let blocked_dir = tempdir()?;
+        std::fs::create_dir(blocked_dir.path().join("pulses_shards.json"))?;
+        let existing_path = blocked_dir.path().join("pulses_shard_001.bin");
+        std::fs::write(&existing_path, "not a shard")?;
+        assert!(
+            pulse_reader
+                .split_into_shards(3, blocked_dir.path(), OverwritePolicy::Overwrite)
+                .is_err()
+        );
+        assert_eq!(std::fs::read_dir(blocked_dir.path())?.count(), 2);
+        assert!(existing_path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_apertures() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let num_apertures = pulse_reader.index.apertures.len();
+        let uniform = |size, seed| ApertureSample {
+            size,
+            strategy: SamplingStrategy::Uniform,
+            seed,
+        };
+
+        // The same seed always gives the same sample, which is pinned so that it can
+        // never change between releases
+        let pinned_sample = vec![565499, 836131, 1315058, 1808775];
+        let sample = uniform(SampleSize::Count(4), 42);
+        let apertures = pulse_reader.sample_apertures(&sample)?;
+        assert_eq!(apertures, pulse_reader.sample_apertures(&sample)?);
+        assert_eq!(apertures, pinned_sample);
+        let samples: std::collections::HashSet<Vec<usize>> = (0..10)
+            .map(|seed| pulse_reader.sample_apertures(&uniform(SampleSize::Count(4), seed)))
+            .collect::<Result<_>>()?;
+        assert!(samples.len() > 1);
+        for apertures in samples {
+            assert!(apertures.is_sorted());
+            assert!(
+                apertures
+                    .iter()
+                    .all(|ap| pulse_reader.index.apertures.contains(ap))
+            );
+        }
+
+        assert_eq!(
+            pulse_reader
+                .sample_apertures(&uniform(SampleSize::Fraction(0.25), 0))?
+                .len(),
+            3
+        );
+        assert_eq!(
+            pulse_reader.sample_apertures(&uniform(SampleSize::Fraction(1.0), 0))?,
+            pulse_reader.index.apertures
+        );
+        for size in [
+            SampleSize::Count(num_apertures + 1),
+            SampleSize::Fraction(-0.1),
+            SampleSize::Fraction(1.5),
+        ] {
+            assert!(pulse_reader.sample_apertures(&uniform(size, 0)).is_err());
+        }
+
+        // Empty samples are rejected, and nothing is written
+        let dir = tempdir()?;
+        let new_file_path = dir.path().join("empty.pulses.bin");
+        for size in [SampleSize::Count(0), SampleSize::Fraction(0.04)] {
+            let err = pulse_reader
+                .write_sampled_apertures(&uniform(size, 0), &new_file_path, OverwritePolicy::Fail)
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<PulseError>(),
+                Some(PulseError::InvalidArgument { .. })
+            ));
+            assert!(err.to_string().contains("would be empty"));
+        }
+        assert!(!new_file_path.exists());
+
+        // Stratified samples take the same number of apertures from each half of the
+        // apertures sorted by activity
+        let mut activity = pulse_reader
+            .index
+            .apertures
+            .iter()
+            .map(|&ap| Ok((pulse_reader.get_aperture_header(ap)?.num_pulses, ap)))
+            .collect::<Result<Vec<_>>>()?;
+        activity.sort();
+        let most_active: Vec<usize> = activity[num_apertures / 2..]
+            .iter()
+            .map(|(_, ap)| *ap)
+            .collect();
+        for seed in 0..10 {
+            let sample = ApertureSample {
+                size: SampleSize::Count(4),
+                strategy: SamplingStrategy::Stratified { num_strata: 2 },
+                seed,
+            };
+            let apertures = pulse_reader.sample_apertures(&sample)?;
+            assert_eq!(apertures.len(), 4);
+            assert_eq!(
+                apertures
+                    .iter()
+                    .filter(|ap| most_active.contains(ap))
+                    .count(),
+                2
+            );
+        }
+        let sample = ApertureSample {
+            size: SampleSize::Count(num_apertures),
+            strategy: SamplingStrategy::Stratified { num_strata: 3 },
+            seed: 0,
+        };
+        assert_eq!(
+            pulse_reader.sample_apertures(&sample)?,
+            pulse_reader.index.apertures
+        );
+        let sample = ApertureSample {
+            strategy: SamplingStrategy::Stratified { num_strata: 0 },
+            ..sample
+        };
+        assert!(pulse_reader.sample_apertures(&sample).is_err());
+
+        // The sampled file has the sampled apertures, and records the sample
+        let dir = tempdir()?;
+        let new_path = dir.path().join("sampled.pulses.bin");
+        let sample = uniform(SampleSize::Count(4), 42);
+        let apertures =
+            pulse_reader.write_sampled_apertures(&sample, &new_path, OverwritePolicy::Fail)?;
+        assert_eq!(apertures, pinned_sample);
+        let sampled_reader = PulseReader::open(&new_path)?;
+        assert!(validate_file(&new_path)?.is_valid());
+        assert_eq!(sampled_reader.index.apertures, apertures);
+        let max_y = apertures
+            .iter()
+            .map(|&ap| Ok(pulse_reader.get_aperture_header(ap)?.y))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .max();
+        assert_eq!(sampled_reader.run_metadata.rows, max_y.map(|y| y + 1));
+        assert_eq!(
+            sampled_reader.metadata["apertureSample"],
+            serde_json::json!({"size": {"count": 4}, "strategy": "uniform", "seed": 42})
+        );
+        for &ap in apertures.iter() {
+            assert_eq!(
+                sampled_reader.get_raw_records(ap)?.0,
+                pulse_reader.get_raw_records(ap)?.0
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_sampled_pulses() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let dir = tempdir()?;
+        let pulse_frames = |reader: &PulseReader| -> Result<Vec<Vec<(u32, u32)>>> {
+            reader
+                .index
+                .apertures
+                .iter()
+                .map(|&ap| {
+                    let (pulses, _) = reader.get_pulses(ap, None)?;
+                    Ok(pulses.iter().map(|p| (p.start_f, p.end_f)).collect())
+                })
+                .collect()
+        };
+        let original = pulse_frames(&pulse_reader)?;
+
+        // Every pulse is kept with a fraction of 1, and none with a fraction of 0
+        for (fraction, expected) in [(1.0, original.clone()), (0.0, vec![vec![]; 10])] {
+            let new_path = dir.path().join(format!("sampled_{fraction}.pulses.bin"));
+            let sample = PulseSample { fraction, seed: 0 };
+            pulse_reader.write_sampled_pulses(&sample, &new_path, OverwritePolicy::Fail)?;
+            assert!(validate_file(&new_path)?.is_valid());
+            assert_eq!(pulse_frames(&PulseReader::open(&new_path)?)?, expected);
+        }
+
+        // The same seed always keeps the same pulses, which keep their original timing.
+        // The number kept is pinned so that it can never change between releases
+        let sample = PulseSample {
+            fraction: 0.5,
+            seed: 42,
+        };
+        let new_path = dir.path().join("sampled.pulses.bin");
+        let again_path = dir.path().join("sampled_again.pulses.bin");
+        pulse_reader.write_sampled_pulses(&sample, &new_path, OverwritePolicy::Fail)?;
+        pulse_reader.write_sampled_pulses(&sample, &again_path, OverwritePolicy::Fail)?;
+        assert_eq!(std::fs::read(&new_path)?, std::fs::read(&again_path)?);
+        let sampled_reader = PulseReader::open(&new_path)?;
+        let sampled = pulse_frames(&sampled_reader)?;
+        for (pulses, original_pulses) in sampled.iter().zip(original.iter()) {
+            assert!(pulses.iter().all(|pulse| original_pulses.contains(pulse)));
+        }
+        let num_sampled: usize = sampled.iter().map(|pulses| pulses.len()).sum();
+        let num_original: usize = original.iter().map(|pulses| pulses.len()).sum();
+        assert!(num_sampled.abs_diff(num_original / 2) < num_original / 20);
+        assert_eq!(num_sampled, 2939);
+        assert_eq!(
+            sampled_reader.metadata["pulseSample"],
+            serde_json::json!({"fraction": 0.5, "seed": 42})
+        );
+
+        let sample = PulseSample {
+            fraction: 1.5,
+            seed: 0,
+        };
+        let err = pulse_reader
+            .write_sampled_pulses(
+                &sample,
+                dir.path().join("bad.pulses.bin"),
+                OverwritePolicy::Fail,
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::InvalidArgument { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spatial_index() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let spatial_index = pulse_reader.spatial_index()?;
+        assert!(std::ptr::eq(spatial_index, pulse_reader.spatial_index()?));
+        assert_eq!(spatial_index.len(), pulse_reader.index.apertures.len());
+
+        let run_metadata = &pulse_reader.run_metadata;
+        for &ap in pulse_reader.index.apertures.iter() {
+            let header = pulse_reader.get_aperture_header(ap)?;
+            assert_eq!(spatial_index.get_by_xy(header.x, header.y), Some(ap));
+            assert_eq!(spatial_index.position(ap), Some((header.x, header.y)));
+            assert_eq!(run_metadata.well_id(header.x, header.y)?, header.well_id);
+            assert_eq!(
+                run_metadata.well_position(header.well_id)?,
+                (header.x, header.y)
+            );
+        }
+        assert_eq!(spatial_index.position(0), None);
+        assert_eq!(
+            spatial_index.in_rect(0, 0, 511, 511),
+            pulse_reader.select_apertures(&ApertureSelection::BoundingBox {
+                min_x: 0,
+                min_y: 0,
+                max_x: 511,
+                max_y: 511
+            })?
+        );
+        assert!(spatial_index.in_rect(600, 0, 500, 1023).is_empty());
+
+        // Nearest apertures match a brute force search
+        let (x, y) = (547, 408);
+        let mut by_distance = pulse_reader
+            .index
+            .apertures
+            .iter()
+            .filter(|&&ap| ap != 836131)
+            .map(|&ap| {
+                let (ap_x, ap_y) = spatial_index.position(ap).unwrap();
+                let (dx, dy) = (ap_x.abs_diff(x) as u64, ap_y.abs_diff(y) as u64);
+                (dx * dx + dy * dy, ap)
+            })
+            .collect::<Vec<_>>();
+        by_distance.sort();
+        let by_distance: Vec<usize> = by_distance.into_iter().map(|(_, ap)| ap).collect();
+        assert_eq!(spatial_index.nearest(x, y, 3), by_distance[..3]);
+        assert_eq!(spatial_index.nearest(x, y, 100), by_distance);
+        assert!(spatial_index.neighbors(x, y).is_empty());
+
+        // Neighbors in a block of apertures around (100, 100)
+        let dir = tempdir()?;
+        let block_path = dir.path().join("block.pulses.bin");
+        let cols = run_metadata.cols.unwrap();
+        let (records, _) = pulse_reader.get_raw_records(pulse_reader.index.apertures[0])?;
+        let mut writer = PulseFileWriter::create(
+            &block_path,
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        let positions = [
+            (99, 99),
+            (100, 99),
+            (101, 99),
+            (99, 100),
+            (101, 100),
+            (103, 100),
+            (99, 101),
+            (100, 101),
+            (101, 101),
+        ];
+        for (x, y) in positions {
+            let header = ApertureHeader {
+                x,
+                y,
+                well_id: y * cols + x,
+                num_pulses: records.len() as u32,
+                byte_loc: 0,
+            };
+            writer.write_aperture(&header, &records)?;
+        }
+        writer.finish()?;
+        let block_reader = PulseReader::open(&block_path)?;
+        let spatial_index = block_reader.spatial_index()?;
+        let well_ids = |positions: &[(u32, u32)]| -> Vec<usize> {
+            positions
+                .iter()
+                .map(|(x, y)| (y * cols + x) as usize)
+                .collect()
+        };
+        let neighbors = [0, 1, 2, 3, 4, 6, 7, 8].map(|idx| positions[idx]);
+        assert_eq!(spatial_index.neighbors(100, 100), well_ids(&neighbors));
+        assert_eq!(
+            spatial_index.neighbors(102, 100),
+            well_ids(&[(101, 99), (101, 100), (103, 100), (101, 101)])
+        );
+        assert_eq!(
+            spatial_index.nearest(100, 100, 5),
+            well_ids(&[(100, 99), (99, 100), (101, 100), (100, 101), (99, 99)])
+        );
+        assert_eq!(
+            spatial_index.nearest(100, 100, 9).last(),
+            Some(&well_ids(&[(103, 100)])[0])
+        );
+        assert_eq!(spatial_index.get_by_xy(100, 100), None);
+
+        // Converting positions to and from the region of interest
+        let mut run_metadata = run_metadata.clone();
+        run_metadata.roi_offset_col = Some(100);
+        run_metadata.roi_cols = Some(200);
+        assert_eq!(run_metadata.roi_position(547, 408)?, None);
+        assert_eq!(run_metadata.roi_position(150, 408)?, Some((50, 408)));
+        assert_eq!(run_metadata.chip_position(50, 408)?, (150, 408));
+        assert!(run_metadata.chip_position(200, 408).is_err());
+        let mut overflowing = run_metadata.clone();
+        overflowing.roi_offset_col = Some(u32::MAX - 100);
+        let err = overflowing.chip_position(150, 408).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::MetadataField { name }) if name == "roi_offset_col"
+        ));
+        assert!(run_metadata.well_id(cols, 0).is_err());
+        run_metadata.cols = None;
+        let err = run_metadata.well_position(0).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PulseError>(),
+            Some(PulseError::MetadataField { name }) if name == "cols"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_aperture_headers() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let headers = pulse_reader.aperture_headers()?;
+        assert_eq!(headers.len(), pulse_reader.index.apertures.len());
+        assert_eq!(headers.aperture, pulse_reader.index.apertures);
+        for (row, (ap, header)) in headers.iter().enumerate() {
+            let expected = pulse_reader.get_aperture_header(ap)?;
+            assert_eq!(
+                (
+                    header.x,
+                    header.y,
+                    header.well_id,
+                    header.num_pulses,
+                    header.byte_loc
+                ),
+                (
+                    expected.x,
+                    expected.y,
+                    expected.well_id,
+                    expected.num_pulses,
+                    expected.byte_loc
+                )
+            );
+            assert_eq!(headers.byte_loc[row], expected.byte_loc);
+            let (records, _) = pulse_reader.get_all_records(ap)?;
+            assert_eq!(records.len(), header.num_pulses as usize);
+        }
+        assert!(headers.get(headers.len()).is_none());
+        assert_eq!(headers.num_pulses.iter().max(), Some(&3378));
+
+        // Rows stay in index order when the apertures are stored in a different order
+        let dir = tempdir()?;
+        let new_file_path = dir.path().join("reversed.pulses.bin");
+        let mut writer = PulseFileWriter::create(
+            &new_file_path,
+            &pulse_reader.metadata,
+            &pulse_reader.record_types,
+            OverwritePolicy::Fail,
+        )?;
+        for &ap in pulse_reader.index.apertures.iter().rev() {
+            let (records, header) = pulse_reader.get_raw_records(ap)?;
+            writer.write_aperture(&header, &records)?;
+        }
+        writer.finish()?;
+        let reversed_headers = PulseReader::open(&new_file_path)?.aperture_headers()?;
+        assert_eq!(reversed_headers.aperture, headers.aperture);
+        assert_eq!(reversed_headers.well_id, headers.well_id);
+        assert_eq!(reversed_headers.num_pulses, headers.num_pulses);
+        assert!(reversed_headers.byte_loc.is_sorted_by(|a, b| a > b));
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarize_apertures() -> Result<()> {
+        let pulse_reader = get_pulse_reader()?;
+        let run_dur_s = pulse_reader.run_metadata.duration.unwrap();
+        let summaries = pulse_reader.summarize_apertures(None)?;
+        assert_eq!(summaries.len(), pulse_reader.index.apertures.len());
+
+        let median = |mut values: Vec<f64>| {
+            values.sort_by(f64::total_cmp);
+            let mid = values.len() / 2;
+            if values.len().is_multiple_of(2) {
+                (values[mid - 1] + values[mid]) / 2.0
+            } else {
+                values[mid]
+            }
+        };
+        for (&ap, summary) in pulse_reader.index.apertures.iter().zip(summaries.iter()) {
+            // Summaries with NaN fields are never equal, so compare their representations
+            assert_eq!(
+                format!("{:?}", pulse_reader.summarize_aperture(ap, None)?),
+                format!("{:?}", summary)
+            );
+
+            let (records, header) = pulse_reader.get_all_records(ap)?;
+            let (pulses, _) = pulse_reader.get_pulses(ap, None)?;
+            assert_eq!(summary.aperture_index, ap);
+            assert_eq!((summary.x, summary.y), (header.x, header.y));
+            let count = |record_type| {
+                records
+                    .iter()
+                    .filter(|record| record.record_type == record_type)
+                    .count()
+            };
+            assert_eq!(summary.num_pulse_records, count(FormattedRecordType::Pulse));
+            assert_eq!(
+                summary.num_padding_records,
+                count(FormattedRecordType::Padding)
+            );
+            assert_eq!(
+                summary.num_background_records,
+                count(FormattedRecordType::Background)
+            );
+            assert_eq!(
+                summary.num_step_up_records,
+                count(FormattedRecordType::StepUp)
+            );
+            assert_eq!(
+                summary.num_pulse_records
+                    + summary.num_padding_records
+                    + summary.num_long_pulse_update_records
+                    + summary.num_long_pulse_dropped_records
+                    + summary.num_step_up_records
+                    + summary.num_step_down_records
+                    + summary.num_background_records
+                    + summary.num_unknown_records,
+                records.len()
+            );
+
+            assert_eq!(summary.num_pulses, pulses.len());
+            assert!((summary.pulse_rate - pulses.len() as f64 / run_dur_s).abs() < 1e-9);
+            if pulses.is_empty() {
+                assert!(summary.mean_dur_s.is_nan());
+                assert!(summary.first_pulse_s.is_nan());
+                continue;
+            }
+            let dur_s: Vec<f64> = pulses.iter().map(|p| p.dur_s as f64).collect();
+            let mean_dur_s = dur_s.iter().sum::<f64>() / dur_s.len() as f64;
+            assert!((summary.mean_dur_s - mean_dur_s).abs() < 1e-9);
+            assert_eq!(summary.median_dur_s, median(dur_s));
+            let ipd_s = pulses[1..].iter().map(|p| p.ipd_s as f64).collect();
+            assert_eq!(summary.median_ipd_s, median(ipd_s));
+            let snr = pulses.iter().map(|p| p.snr as f64).collect();
+            assert_eq!(summary.median_snr, median(snr));
+            let intensity = pulses.iter().map(|p| p.intensity as f64).collect();
+            assert_eq!(summary.intensity_q50, median(intensity));
+            assert!(summary.intensity_q10 <= summary.intensity_q50);
+            assert!(summary.intensity_q50 <= summary.intensity_q90);
+            assert!(summary.binratio_q10 <= summary.binratio_q90);
+            let fps = pulse_reader.fps as f64;
+            assert_eq!(summary.first_pulse_s, pulses[0].start_f as f64 / fps);
+            assert_eq!(
+                summary.last_pulse_s,
+                pulses.last().unwrap().end_f as f64 / fps
+            );
+            assert!((0.0..=1.0).contains(&summary.on_time_fraction));
+        }
+
+        // Filtered pulses are left out of the pulse statistics, but not the record counts
+        let pulse_filter = PulseFilter {
+            min_dur_f: Some(3),
+            ..Default::default()
+        };
+        let filtered = pulse_reader.summarize_apertures(Some(&pulse_filter))?;
+        let mut num_first_ipd_defined = 0;
+        for (&ap, (summary, filtered)) in pulse_reader
+            .index
+            .apertures
+            .iter()
+            .zip(summaries.iter().zip(filtered.iter()))
+        {
+            let (pulses, _) = pulse_reader.get_pulses(ap, Some(&pulse_filter))?;
+            assert_eq!(filtered.num_pulses, pulses.len());
+            assert_eq!(filtered.num_pulse_records, summary.num_pulse_records);
+            assert!(filtered.on_time_fraction <= summary.on_time_fraction);
+
+            // The interpulse duration of the first pulse kept is only left out if it is
+            // the first pulse of the aperture
+            let (all_pulses, _) = pulse_reader.get_pulses(ap, None)?;
+            let first_ipd_defined =
+                pulses.first().map(|p| p.index) != all_pulses.first().map(|p| p.index);
+            num_first_ipd_defined += first_ipd_defined as usize;
+            let skip = if first_ipd_defined { 0 } else { 1 };
+            if pulses.len() > skip {
+                let ipd_s: Vec<f64> = pulses[skip..].iter().map(|p| p.ipd_s as f64).collect();
+                let mean_ipd_s = ipd_s.iter().sum::<f64>() / ipd_s.len() as f64;
+                assert!((filtered.mean_ipd_s - mean_ipd_s).abs() < 1e-9);
+                assert_eq!(filtered.median_ipd_s, median(ipd_s));
+            }
+        }
+        assert!(num_first_ipd_defined > 0);
+
+        // Statistics of an aperture without pulses are NaN
+        let pulse_filter = PulseFilter {
+            min_dur_f: Some(u32::MAX),
+            ..Default::default()
+        };
+        let ap = pulse_reader.index.apertures[0];
+        let summary = pulse_reader.summarize_aperture(ap, Some(&pulse_filter))?;
+        assert_eq!(summary.num_pulses, 0);
+        assert_eq!(summary.pulse_rate, 0.0);
+        assert_eq!(summary.on_time_fraction, 0.0);
+        assert!(summary.median_dur_s.is_nan());
+        assert!(summary.intensity_q90.is_nan());
+        assert!(summary.first_pulse_s.is_nan());
+        assert!(summary.num_pulse_records > 0);
+
+        // Rates can't be computed for a run without a positive duration
+        let mut pulse_reader = pulse_reader;
+        for duration in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            pulse_reader.run_metadata.duration = Some(duration);
+            for err in [
+                pulse_reader.summarize_apertures(None).unwrap_err(),
+                pulse_reader.summarize_aperture(ap, None).unwrap_err(),
+            ] {
+                assert!(matches!(
+                    err.downcast_ref::<PulseError>(),
+                    Some(PulseError::MetadataField { name }) if name == "duration"
+                ));
+            }
+        }
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 use crate::pulse_reader::records::NormalizedPulse;
 use anyhow::{Result, anyhow};
+use serde::Serialize;
 
 /// A pulse filter for normalized pulse records
 ///
@@ -7,7 +8,7 @@ use anyhow::{Result, anyhow};
 /// a method for classifying whether a NormalizedPulse passes the filter.
 /// A value of `None` for one of the fields indicates that stage of the filter
 /// is disabled.
-#[derive(Clone, Debug)]
+#[derive(Serialize, Clone, Debug, Default)]
 pub struct PulseFilter {
     pub min_dur_f: Option<u32>,
     pub min_dur_s: Option<f32>,
@@ -22,25 +23,8 @@ pub struct PulseFilter {
     pub recalc_ipd: bool,
 }
 
-impl Default for PulseFilter {
-    fn default() -> Self {
-        PulseFilter {
-            min_dur_f: None,
-            min_dur_s: None,
-            max_dur_s: None,
-            min_snr: None,
-            min_intensity: None,
-            min_binratio: None,
-            max_binratio: None,
-            start_m: None,
-            end_m: None,
-            mask_s: None,
-            recalc_ipd: false,
-        }
-    }
-}
-
 impl PulseFilter {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         min_dur_f: Option<u32>,
         min_dur_s: Option<f32>,
@@ -70,22 +54,20 @@ impl PulseFilter {
     }
 
     fn evaluate_filter(&self, pulse: &NormalizedPulse, fps: f32) -> bool {
-        self.min_dur_f.map_or(true, |min| pulse.dur_f >= min)
-            && self.min_dur_s.map_or(true, |min| pulse.dur_s >= min)
-            && self.max_dur_s.map_or(true, |max| pulse.dur_s <= max)
-            && self.min_snr.map_or(true, |min| pulse.snr >= min)
+        self.min_dur_f.is_none_or(|min| pulse.dur_f >= min)
+            && self.min_dur_s.is_none_or(|min| pulse.dur_s >= min)
+            && self.max_dur_s.is_none_or(|max| pulse.dur_s <= max)
+            && self.min_snr.is_none_or(|min| pulse.snr >= min)
+            && self.min_intensity.is_none_or(|min| pulse.intensity >= min)
+            && self.min_binratio.is_none_or(|min| pulse.binratio >= min)
+            && self.max_binratio.is_none_or(|max| pulse.binratio <= max)
             && self
-                .min_intensity
-                .map_or(true, |min| pulse.intensity >= min)
-            && self.min_binratio.map_or(true, |min| pulse.binratio >= min)
-            && self.max_binratio.map_or(true, |max| pulse.binratio <= max)
-            && self.start_m.map_or(true, |start_m| {
-                (pulse.start_f as f32) / (60f32 * fps) >= start_m
-            })
+                .start_m
+                .is_none_or(|start_m| (pulse.start_f as f32) / (60f32 * fps) >= start_m)
             && self
                 .end_m
-                .map_or(true, |end_m| (pulse.end_f as f32) / (60f32 * fps) <= end_m)
-            && self.mask_s.map_or(true, |(start_s, end_s)| {
+                .is_none_or(|end_m| (pulse.end_f as f32) / (60f32 * fps) <= end_m)
+            && self.mask_s.is_none_or(|(start_s, end_s)| {
                 ((pulse.start_f as f32) / fps > end_s) || ((pulse.end_f as f32) / fps < start_s)
             })
     }
@@ -106,7 +88,7 @@ impl PulseFilter {
     ///
     /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     /// # let pulse_file_path = path.join("../example_files/pulses.bin").to_string_lossy().to_string();
-    /// # let mut pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
     /// # let fps = pulse_reader.fps;
     /// # let (mut pulses, ap_header) = pulse_reader.get_pulses(pulse_reader.index.apertures[0], None).unwrap();
     ///
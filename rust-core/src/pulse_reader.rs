@@ -1,38 +1,281 @@
+pub mod concat;
 mod constants;
+pub mod crop;
+pub mod error;
+pub mod filtered;
 pub mod headers;
+pub mod iter;
+pub mod merge;
+pub mod metadata;
+pub mod mmap;
+#[cfg(feature = "rayon")]
+pub mod parallel;
 pub mod records;
+pub mod recover;
+pub mod sample;
+pub mod select;
+pub mod shard;
+pub mod spatial;
+pub mod summary;
+pub mod validate;
+pub mod writer;
 
 use crate::pulse_filter::PulseFilter;
 
 use constants::*;
+use error::PulseError;
 use headers::*;
+use metadata::RunMetadata;
 use records::*;
+use spatial::SpatialIndex;
+use writer::{OverwritePolicy, PulseFileWriter};
 
+pub use merge::merge_pulse_files;
+
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::prelude::*;
-use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
+use memmap2::Mmap;
 use serde_json::Value;
 
-const BUFFER_SIZE: usize = 1024 * 1024; // 1MB buffer size for reading and writing
+/// Reads exactly `buffer.len()` bytes from `file`, starting at `offset`
+///
+/// Unlike `Read::read_exact`, this does not depend on the position of the file cursor,
+/// which allows a single file handle to be read from multiple threads through a shared
+/// reference. On targets without positional reads, such as WebAssembly, it falls back to
+/// a seek and a read under a lock. Reads past the end of the file are reported as
+/// `PulseError::Truncated`.
+fn read_exact_at(file: &File, buffer: &mut [u8], offset: u64) -> Result<(), PulseError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        FileExt::read_exact_at(file, buffer, offset).map_err(|e| PulseError::from_read(e, offset))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match FileExt::seek_read(file, &mut buffer[filled..], offset + filled as u64) {
+                Ok(0) => return Err(PulseError::Truncated { offset }),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(PulseError::Io(e)),
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        use std::sync::Mutex;
+
+        // Without positional reads the shared cursor has to be moved, so hold a lock
+        // between the seek and the read to keep other threads from moving it in between
+        static CURSOR_LOCK: Mutex<()> = Mutex::new(());
+        let _guard = CURSOR_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset)).map_err(PulseError::Io)?;
+        file.read_exact(buffer)
+            .map_err(|e| PulseError::from_read(e, offset))
+    }
+}
+
+/// A source of bytes that can be read at any offset through a shared reference
+pub(crate) trait ReadAt {
+    /// Reads exactly `buffer.len()` bytes starting at `offset`
+    ///
+    /// Reads past the end of the source are reported as `PulseError::Truncated`.
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), PulseError>;
+}
+
+impl ReadAt for File {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), PulseError> {
+        read_exact_at(self, buffer, offset)
+    }
+}
+
+/// The source that a `PulseReader` reads the records of its apertures from
+pub(crate) enum ReadBackend {
+    /// Positional reads from an open file, see `read_exact_at`
+    File(File),
+    /// A read-only memory map of the whole file, see `PulseReader::open_mmap`
+    Mmap(Mmap),
+}
+
+impl ReadAt for ReadBackend {
+    fn read_exact_at(&self, buffer: &mut [u8], offset: u64) -> Result<(), PulseError> {
+        match self {
+            ReadBackend::File(file) => read_exact_at(file, buffer, offset),
+            ReadBackend::Mmap(mmap) => {
+                buffer.copy_from_slice(mapped_bytes(mmap, offset, buffer.len())?);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ReadBackend {
+    /// Returns `len` bytes starting at `offset`
+    ///
+    /// The bytes are borrowed from the map if the file is memory-mapped, and read into a
+    /// new buffer otherwise.
+    pub(crate) fn bytes_at(&self, offset: u64, len: usize) -> Result<Cow<'_, [u8]>, PulseError> {
+        match self {
+            ReadBackend::File(file) => {
+                let mut buffer = vec![0; len];
+                read_exact_at(file, &mut buffer, offset)?;
+                Ok(Cow::Owned(buffer))
+            }
+            ReadBackend::Mmap(mmap) => mapped_bytes(mmap, offset, len).map(Cow::Borrowed),
+        }
+    }
+}
+
+/// The `len` bytes of a memory-mapped file starting at `offset`
+///
+/// Ranges that extend past the end of the file, including those that would overflow,
+/// are reported as `PulseError::Truncated`.
+fn mapped_bytes(mmap: &Mmap, offset: u64, len: usize) -> Result<&[u8], PulseError> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| mmap.get(start..start.checked_add(len)?))
+        .ok_or(PulseError::Truncated { offset })
+}
+
+/// Reads exactly `buffer.len()` bytes from the current position of `file`
+///
+/// `offset` is the current position, and is only used to report truncated files.
+fn read_exact_from<R: Read>(
+    file: &mut R,
+    buffer: &mut [u8],
+    offset: u64,
+) -> Result<(), PulseError> {
+    file.read_exact(buffer)
+        .map_err(|e| PulseError::from_read(e, offset))
+}
+
+/// The parsed sections of a pulses.bin file that describe its contents
+///
+/// This contains everything in a pulses.bin file except for the aperture records
+/// themselves, and is shared by the different reader backends.
+struct FileSections {
+    header: PulseFileHeader,
+    record_types: Vec<PulseRecordType>,
+    encoding: RecordEncoding,
+    raw_metadata: String,
+    metadata: Value,
+    run_metadata: RunMetadata,
+    fps: f32,
+    trimmed: bool,
+    index: PulseFileIndex,
+}
+
+impl FileSections {
+    /// Reads the file header, record types, metadata and aperture index
+    fn read<R: Read + Seek>(file: &mut R) -> Result<Self> {
+        Self::read_with(file, |file, header, _metadata| {
+            Self::read_index(file, header)
+        })
+    }
+
+    /// Reads the file header, record types and metadata, using `read_index` to build the index
+    ///
+    /// `read_index` is passed the parsed run metadata, and may update the number of reads and the
+    /// index offset in the header, which is used to recover files with a missing or corrupt index.
+    fn read_with<R, F>(file: &mut R, read_index: F) -> Result<Self>
+    where
+        R: Read + Seek,
+        F: FnOnce(&mut R, &mut PulseFileHeader, &RunMetadata) -> Result<PulseFileIndex>,
+    {
+        // Parse and validate pulse file header and record types
+        let (mut header, record_types) = PulseFileHeader::read(file)?;
+        let encoding = RecordEncoding::new(&record_types)?;
+
+        // Parse metadata
+        let mut metadata_buffer = vec![0; header.metadata_length as usize];
+        read_exact_from(file, &mut metadata_buffer, header.metadata_offset())?;
+        let raw_metadata =
+            String::from_utf8(metadata_buffer).map_err(|e| PulseError::InvalidMetadata {
+                reason: format!("not valid UTF-8: {}", e),
+            })?;
+        let metadata: Value =
+            serde_json::from_str(&raw_metadata).map_err(|e| PulseError::InvalidMetadata {
+                reason: format!("not valid JSON: {}", e),
+            })?;
+        let run_metadata = RunMetadata::from_value(&metadata)?;
+        let fps = run_metadata
+            .fps
+            .ok_or_else(|| PulseError::metadata_field("fps"))? as f32;
+        let trimmed = run_metadata.trimmed();
+
+        // Parse aperture index
+        let index = read_index(file, &mut header, &run_metadata)?;
+
+        Ok(FileSections {
+            header,
+            record_types,
+            encoding,
+            raw_metadata,
+            metadata,
+            run_metadata,
+            fps,
+            trimmed,
+            index,
+        })
+    }
+
+    /// Reads the aperture index at the offset given in the header
+    fn read_index<R: Read + Seek>(
+        file: &mut R,
+        header: &PulseFileHeader,
+    ) -> Result<PulseFileIndex> {
+        // Parse aperture index
+        let _ = file
+            .seek(SeekFrom::Start(header.index_offset))
+            .map_err(PulseError::Io)?;
+        let mut index_magic_buffer = [0; 8];
+        read_exact_from(file, &mut index_magic_buffer, header.index_offset)?;
+        let index_magic = u64::from_le_bytes(index_magic_buffer);
+        if index_magic != INDEX_SECTION_MAGIC {
+            return Err(PulseError::IndexMagicMismatch { magic: index_magic }.into());
+        };
+
+        // Populate aperture index map
+        let mut index_buffer = vec![0; INDEX_RECORD_SIZE * header.num_reads as usize];
+        read_exact_from(
+            file,
+            &mut index_buffer,
+            header.index_offset + index_magic_buffer.len() as u64,
+        )?;
+        PulseFileIndex::new(&index_buffer, header.num_reads as usize)
+    }
+}
 
 /// A pulses.bin reader
 ///
 /// This struct is used to parse pulses.bin, extract metadata, and read and
-/// format records from apertures.
+/// format records from apertures. All reads use positional I/O rather than
+/// seeking a shared cursor, so a single reader can be shared between threads.
+/// Records are read from the file with a system call per read, or from a memory
+/// map of the file if it was opened with `open_mmap`.
 ///
 pub struct PulseReader {
     pub file_name: PathBuf,
-    file: File,
+    backend: ReadBackend,
     pub header: PulseFileHeader,
     pub record_types: Vec<PulseRecordType>,
+    pub encoding: RecordEncoding,
     pub raw_metadata: String,
     pub fps: f32,
     pub trimmed: bool,
     pub metadata: Value,
+    pub run_metadata: RunMetadata,
     pub index: PulseFileIndex,
+    spatial_index: OnceLock<SpatialIndex>,
 }
 
 impl PulseReader {
@@ -48,104 +291,53 @@ impl PulseReader {
     ///
     /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     /// # let pulse_file_path = path.join("../example_files/pulses.bin");
-    /// let mut pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
     /// ```
     pub fn open<P: AsRef<Path>>(file_name: P) -> Result<Self> {
-        let mut file = File::open(file_name.as_ref())?;
-
-        // Parse and validate pulse file header
-        let mut header_buffer = [0; FILE_HEADER_SIZE_FULL];
-        file.read_exact(&mut header_buffer)?;
-        let header = PulseFileHeader::new(&header_buffer)?;
-        header.validate()?;
-
-        // Parse record types
-        let mut record_types: Vec<PulseRecordType> = Vec::new();
-        let mut record_buffer = vec![0; 4 * header.num_encoding_records as usize];
-        file.read_exact(&mut record_buffer)?;
-        for idx in 0..header.num_encoding_records as usize {
-            let rec_type = u8::from_le_bytes(
-                record_buffer[(4 * idx)..(4 * idx + 1)]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse record type byte at index {}", idx))?,
-            );
-            let rec_bits = u8::from_le_bytes(
-                record_buffer[(4 * idx + 1)..(4 * idx + 2)]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse record bits byte at index {}", idx))?,
-            );
-            let rec_off = u16::from_le_bytes(
-                record_buffer[(4 * idx + 2)..(4 * idx + 4)]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse record offset bytes at index {}", idx))?,
-            );
-            let rec_scale: u16 = 1u16 << rec_bits;
-            record_types.push(PulseRecordType {
-                record_type: rec_type,
-                bits: rec_bits,
-                scale: rec_scale as f32,
-                offset: rec_off as f32,
-            });
-        }
-
-        // Parse metadata
-        let mut metadata_buffer = vec![0; header.metadata_length as usize];
-        file.read_exact(&mut metadata_buffer)?;
-        let raw_metadata = String::from_utf8(metadata_buffer)
-            .map_err(|e| anyhow!("Failed to parse metadata as UTF-8: {}", e))?;
-        let metadata: Value = serde_json::from_str(&raw_metadata)
-            .map_err(|e| anyhow!("Failed to parse metadata JSON: {}", e))?;
-        let fps = metadata["fps"]
-            .as_f64()
-            .ok_or_else(|| anyhow!("Missing or invalid 'fps' field in metadata"))?
-            as f32;
-
-        // Check if the trimmed pulse caller was used
-        let trimmed = metadata
-            .get("pulseCaller")
-            .and_then(|pulse_caller| pulse_caller.get("options").and_then(Value::as_array))
-            .map_or(false, |options| {
-                options
-                    .iter()
-                    .any(|value| value.as_str().unwrap_or("") == "trim_boundary_frames")
-            });
-
-        // Parse aperture index
-        let _ = file.seek(SeekFrom::Start(header.index_offset))?;
-        let mut index_magic_buffer = [0; 8];
-        file.read_exact(&mut index_magic_buffer)?;
-        let index_magic = u64::from_le_bytes(index_magic_buffer);
-        if index_magic != INDEX_SECTION_MAGIC {
-            return Err(anyhow!("Index magic number mismatch"));
-        };
-
-        // Populate aperture index map
-        let mut index_buffer = vec![0; INDEX_RECORD_SIZE * header.num_reads as usize];
-        file.read_exact(&mut index_buffer)?;
-        let index = PulseFileIndex::new(&index_buffer, header.num_reads as usize)?;
+        let mut file = File::open(file_name.as_ref()).map_err(PulseError::Io)?;
+        let sections = FileSections::read(&mut file)?;
+        Ok(Self::from_sections(
+            file_name.as_ref(),
+            ReadBackend::File(file),
+            sections,
+        ))
+    }
 
-        Ok(PulseReader {
-            file_name: file_name.as_ref().to_path_buf(),
-            file,
-            header,
-            record_types,
-            raw_metadata,
-            metadata,
-            fps,
-            trimmed,
-            index,
-        })
+    /// Builds a reader from the parsed sections of a file and the backend to read it with
+    fn from_sections(file_name: &Path, backend: ReadBackend, sections: FileSections) -> Self {
+        PulseReader {
+            file_name: file_name.to_path_buf(),
+            backend,
+            header: sections.header,
+            record_types: sections.record_types,
+            encoding: sections.encoding,
+            raw_metadata: sections.raw_metadata,
+            metadata: sections.metadata,
+            run_metadata: sections.run_metadata,
+            fps: sections.fps,
+            trimmed: sections.trimmed,
+            index: sections.index,
+            spatial_index: OnceLock::new(),
+        }
     }
 
     /// Create a new pulses.bin file with a subset of the apertures in this one
     ///
     /// This function creates a new pulses.bin file with only records from the specified list of
     /// apertures. All other apertures will be omitted from the created pulses.bin file. This is
-    /// useful for creating smaller pulses.bin files for testing purposes.
+    /// useful for creating smaller pulses.bin files for testing purposes, or for sharing a
+    /// region of interest chosen with `select_apertures`. An existing file is handled
+    /// according to [`OverwritePolicy`]. Records are copied without being decoded.
+    ///
+    /// The region of interest, the number of rows and the valid well counts in the metadata
+    /// of the new file are updated to describe the subset. If every aperture is copied, or
+    /// the metadata does not describe the layout of the chip, the metadata is copied
+    /// unchanged.
     ///
     /// # Examples
     /// ```
     /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
     /// # use std::path::PathBuf;
     /// # use tempfile::tempdir;
     ///
@@ -153,117 +345,135 @@ impl PulseReader {
     /// # let pulse_file_path = path.join("../example_files/pulses.bin");
     /// # let temp_dir = tempdir().unwrap();
     /// # let new_pulse_file_path = temp_dir.path().join("pulses_copy.bin");
-    /// # let mut pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
     ///
     /// // Copy the first 5 apertures to a new file
     /// let apertures_to_copy = pulse_reader.index.apertures[0..5].to_vec();
-    /// pulse_reader.copy_apertures_to_new_file(&apertures_to_copy, &new_pulse_file_path).unwrap();
+    /// pulse_reader
+    ///     .copy_apertures_to_new_file(&apertures_to_copy, &new_pulse_file_path, OverwritePolicy::Fail)
+    ///     .unwrap();
     /// ```
     pub fn copy_apertures_to_new_file<P: AsRef<Path>>(
-        &mut self,
+        &self,
+        apertures: &[usize],
+        file_name: P,
+        overwrite: OverwritePolicy,
+    ) -> Result<()> {
+        self.copy_subset(apertures, None, file_name, overwrite)
+    }
+
+    /// Copies the given apertures to a new file, as `copy_apertures_to_new_file` does
+    ///
+    /// `edit_metadata` is applied to the metadata of the new file after it has been updated
+    /// to describe the subset. The metadata is only re-serialized if it was changed, so
+    /// that a copy of every aperture without an edit keeps the metadata byte for byte.
+    pub(crate) fn copy_subset<P: AsRef<Path>>(
+        &self,
         apertures: &[usize],
+        edit_metadata: Option<&dyn Fn(&mut Value)>,
         file_name: P,
+        overwrite: OverwritePolicy,
     ) -> Result<()> {
-        // The index logic requires apertures to be sorted
+        // Copy apertures in order, so that they are laid out in the same order as the index
         let mut apertures = apertures.to_vec();
         apertures.sort();
-
-        // Initialize the offset to the beginning of the pulse record data
-        let mut offset = self.header.data_offset as usize;
-
-        // To determine the size of each aperture on disk, we will first need to parse the header
-        // for that aperture. Allocate memory for an aperture header.
-        let mut ap_header_buffer = [0u8; READ_HEADER_SIZE];
-
-        // The index tells us where each aperture's records begin on disk. Allocate memory to store
-        // the new byte location for each aperture.
-        let mut new_ap_byte_loc: Vec<usize> = vec![0; apertures.len()];
-
-        // The total size in bytes of every aperture's header + records
-        let mut ap_byte_len: Vec<usize> = vec![0; apertures.len()];
-
-        for (idx, ap) in apertures.iter().enumerate() {
-            // The byte loc of the aperture in the new file
-            new_ap_byte_loc[idx] = offset;
-
-            // Read and parse the aperture header
-            let byte_loc = self.index.get(*ap)?;
-            self.file.seek(SeekFrom::Start(byte_loc))?;
-            self.file.read_exact(&mut ap_header_buffer)?;
-            let aperture_header = ApertureHeader::new(&ap_header_buffer, byte_loc)?;
-
-            // Store the size of the aperture, then update the offset
-            ap_byte_len[idx] = READ_HEADER_SIZE + aperture_header.num_pulses as usize * PULSE_SIZE;
-            offset += ap_byte_len[idx];
+        apertures.dedup();
+
+        let headers = apertures
+            .iter()
+            .map(|ap| self.get_aperture_header(*ap))
+            .collect::<Result<Vec<_>>>()?;
+        let subset_metadata = if apertures == self.index.apertures {
+            None
+        } else {
+            select::subset_metadata(self, &headers)
+        };
+        let raw_metadata = match (subset_metadata, edit_metadata) {
+            (None, None) => self.raw_metadata.clone(),
+            (metadata, edit_metadata) => {
+                let mut metadata = metadata.unwrap_or_else(|| self.metadata.clone());
+                if let Some(edit_metadata) = edit_metadata {
+                    edit_metadata(&mut metadata);
+                }
+                metadata.to_string()
+            }
+        };
+        let mut writer = PulseFileWriter::create_with_raw_metadata(
+            file_name,
+            &raw_metadata,
+            &self.record_types,
+            overwrite,
+        )?;
+        for aperture_header in headers.iter() {
+            writer.copy_aperture(self, aperture_header)?;
         }
+        writer.finish()
+    }
 
-        // Open the new file with a buffered writer
-        let mut new_file = BufWriter::with_capacity(BUFFER_SIZE, File::create(file_name)?);
+    /// Extract the header of the given aperture index, without reading its records
+    pub fn get_aperture_header(&self, aperture: usize) -> Result<ApertureHeader> {
+        let byte_loc = self.index.get(aperture)?;
+        let mut buffer = [0; READ_HEADER_SIZE];
+        self.backend.read_exact_at(&mut buffer, byte_loc)?;
+        ApertureHeader::new(&buffer, byte_loc)
+    }
 
-        // Create a new header with updated num_reads and index_offset, then write to new file
-        let new_file_header = PulseFileHeader {
-            num_reads: apertures.len() as u64,
-            index_offset: offset as u64,
-            ..self.header
-        };
-        new_file_header.write_all(&mut new_file)?;
-
-        // Seek to the position immediately after the initial header, which contains the metadata
-        // and pulse record info, and copy that data to the new file
-        self.file
-            .seek(SeekFrom::Start(FILE_HEADER_SIZE_FULL as u64))?;
-        let mut remaining_header_buffer: Vec<u8> =
-            vec![0; self.header.data_offset as usize - FILE_HEADER_SIZE_FULL];
-        self.file.read_exact(&mut remaining_header_buffer)?;
-        new_file.write_all(&remaining_header_buffer)?;
-
-        // Allocate enough memory for the largest aperture, then loop over apertures and copy their
-        // header and records one-by-one to the new file
-        let max_byte_len = *ap_byte_len.iter().max().unwrap_or(&0);
-        let mut ap_buffer: Vec<u8> = vec![0; max_byte_len];
-        for (idx, ap) in apertures.iter().enumerate() {
-            let ap_buffer_slice = &mut ap_buffer[0..ap_byte_len[idx]];
-            let byte_loc = self.index.get(*ap)?;
-            self.file.seek(SeekFrom::Start(byte_loc))?;
-            self.file.read_exact(ap_buffer_slice)?;
-            new_file.write_all(ap_buffer_slice)?;
-        }
+    /// Read the headers of every aperture in the file, without reading their records
+    ///
+    /// The headers are read in the order they are stored in the file, which is much
+    /// faster than reading the apertures one by one on a large file. The rows of the
+    /// table are in index order, matching `index.apertures`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// let headers = pulse_reader.aperture_headers().unwrap();
+    /// assert_eq!(headers.aperture, pulse_reader.index.apertures);
+    /// let most_records = headers.num_pulses.iter().max();
+    /// ```
+    pub fn aperture_headers(&self) -> Result<ApertureHeaderTable> {
+        let mut locations = self
+            .index
+            .apertures
+            .iter()
+            .enumerate()
+            .map(|(row, &ap)| Ok((self.index.get(ap)?, row)))
+            .collect::<Result<Vec<_>, PulseError>>()?;
+        locations.sort_unstable();
 
-        // Write the index magic integer
-        new_file.write_all(&INDEX_SECTION_MAGIC.to_le_bytes())?;
+        let mut headers: Vec<Option<ApertureHeader>> = (0..locations.len()).map(|_| None).collect();
+        let mut buffer = [0; READ_HEADER_SIZE];
+        for (byte_loc, row) in locations {
+            self.backend.read_exact_at(&mut buffer, byte_loc)?;
+            headers[row] = Some(ApertureHeader::new(&buffer, byte_loc)?);
+        }
 
-        // Write the index
-        for (ap, new_byte_loc) in apertures.iter().zip(new_ap_byte_loc) {
-            // This may look inefficient, but since we're using a buffered writer, it's actually
-            // more performant than collecting the whole index in memory and writing all at once.
-            new_file.write_all(&(*ap as u32).to_le_bytes())?;
-            new_file.write_all(&(new_byte_loc as u64).to_le_bytes())?;
+        let mut table = ApertureHeaderTable::with_capacity(headers.len());
+        for (&ap, header) in self.index.apertures.iter().zip(headers.iter().flatten()) {
+            table.push(ap, header);
         }
-        new_file.flush()?;
-        Ok(())
+        Ok(table)
     }
 
     /// Extract header and raw (unformatted) records for the given aperture index
-    fn get_raw_records(&mut self, aperture: usize) -> Result<(Vec<RawRecord>, ApertureHeader)> {
-        // Seek to beginning of records for given aperture
-        let byte_loc = self.index.get(aperture)?;
-        let _ = self.file.seek(SeekFrom::Start(byte_loc))?;
-
-        // Parse aperture header
-        let mut buffer = [0; READ_HEADER_SIZE];
-        self.file.read_exact(&mut buffer)?;
-        let aperture_header = ApertureHeader::new(&buffer, byte_loc)?;
-
-        // Parse raw records
-        let mut raw_pulse_records: Vec<RawRecord> = Vec::new();
-        let mut pulse_buffer = vec![0; PULSE_SIZE * aperture_header.num_pulses as usize];
-        self.file.read_exact(&mut pulse_buffer)?;
-        for idx in 0..aperture_header.num_pulses as usize {
-            raw_pulse_records.push(RawRecord::new(
-                &pulse_buffer[(idx * PULSE_SIZE)..((idx + 1) * PULSE_SIZE)],
-            )?);
-        }
-        Ok((raw_pulse_records, aperture_header))
+    ///
+    /// Returns the integer-encoded records exactly as they are stored on disk. This is
+    /// primarily useful for writing records to a new file with a `PulseFileWriter`.
+    ///
+    /// The records are decoded from the same view as `aperture`, so a memory-mapped file
+    /// is not copied into an intermediate buffer, but the decoded records are always
+    /// collected into a new `Vec`. Use `aperture` to work with the records without
+    /// allocating.
+    pub fn get_raw_records(&self, aperture: usize) -> Result<(Vec<RawRecord>, ApertureHeader)> {
+        let records = self.aperture(aperture)?;
+        let raw_records = records.iter_raw().collect();
+        Ok((raw_records, records.header))
     }
 
     /// Extract header and all formatted records for the given aperture index
@@ -278,7 +488,7 @@ impl PulseReader {
     ///
     /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     /// # let pulse_file_path = path.join("../example_files/pulses.bin");
-    /// # let mut pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
     /// let ap = pulse_reader.index.apertures[0];
     ///
     /// let (records, aperture_header) = pulse_reader.get_all_records(ap).unwrap();
@@ -287,18 +497,12 @@ impl PulseReader {
     /// assert!(aperture_header.num_pulses as usize == records.len());
     /// ```
     pub fn get_all_records(
-        &mut self,
+        &self,
         aperture: usize,
     ) -> Result<(Vec<FormattedRecord>, ApertureHeader)> {
-        let (raw_records, aperture_header) = self.get_raw_records(aperture)?;
-        let records: Vec<FormattedRecord> = raw_records
-            .into_iter()
-            .enumerate()
-            .map(|(idx, raw_record)| {
-                FormattedRecord::from_raw(&raw_record, &self.record_types, idx)
-            })
-            .collect();
-        Ok((records, aperture_header))
+        let records = self.aperture(aperture)?;
+        let formatted_records = records.iter().collect();
+        Ok((formatted_records, records.header))
     }
 
     /// Extract header and normalized pulses for the given aperture index
@@ -315,7 +519,7 @@ impl PulseReader {
     ///
     /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     /// # let pulse_file_path = path.join("../example_files/pulses.bin");
-    /// # let mut pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
     /// let ap = pulse_reader.index.apertures[0];
     ///
     /// let (pulses, aperture_header) = pulse_reader.get_pulses(ap, None).unwrap();
@@ -326,7 +530,7 @@ impl PulseReader {
     /// assert!(aperture_header.num_pulses as usize >= pulses.len());
     /// ```
     pub fn get_pulses(
-        &mut self,
+        &self,
         aperture: usize,
         pulse_filter: Option<&PulseFilter>,
     ) -> Result<(Vec<NormalizedPulse>, ApertureHeader)> {
@@ -342,227 +546,3 @@ impl PulseReader {
         }
     }
 }
-
-/// Combine two pulses.bin files into a single file with all pulses from both files
-///
-/// This function merges multiple PulseReader instances into a single pulses.bin file.
-/// It combines the metadata, updates the data offset, and writes all records from each
-/// PulseReader to the new file. The resulting file will contain all apertures from the
-/// provided PulseReader instances, with updated metadata reflecting the total number of
-/// reads and apertures.
-/// # Examples
-/// ```
-/// # use qsi_pulse_reader::pulse_reader::{PulseReader, merge_pulse_files};
-/// # use std::path::PathBuf;
-/// # use tempfile::tempdir;
-/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-/// # let pulse_file_path1 = path.join("../example_files/pulses.bin");
-/// # let pulse_file_path2 = path.join("../example_files/pulses.bin");
-/// # let temp_dir = tempdir().unwrap();
-/// # let new_pulse_file_path = temp_dir.path().join("merged_pulses.bin");
-/// let mut pulse_readers = vec![
-///     PulseReader::open(pulse_file_path1).unwrap(),
-///     PulseReader::open(pulse_file_path2).unwrap(),
-/// ];
-/// merge_pulse_files(&mut pulse_readers, &new_pulse_file_path).unwrap();
-/// ```
-pub fn merge_pulse_files<P: AsRef<Path>>(
-    pulse_files: &mut [PulseReader],
-    new_file_name: P,
-) -> Result<()> {
-    if pulse_files.is_empty() {
-        return Err(anyhow!("No pulse files provided"));
-    }
-
-    // Check if the file already exists
-    if new_file_name.as_ref().exists() {
-        return Err(anyhow!(
-            "File {} already exists",
-            new_file_name.as_ref().display()
-        ));
-    }
-
-    // First loop to collect data needed for new metadata/header
-    let mut valid_wells: u64 = 0;
-    let mut valid_wells_left: u64 = 0;
-    let mut valid_wells_right: u64 = 0;
-    let mut tot_reads: u64 = 0;
-    let mut tot_rows: usize = 0;
-    let mut roi_offset_col: Option<u64> = None;
-    let mut last_col: Option<u64> = None;
-    let mut total_aperture_data_size: u64 = 0;
-
-    for pulse_file in pulse_files.iter_mut() {
-        tot_reads += pulse_file.header.num_reads;
-        valid_wells += pulse_file.metadata["validWells"]
-            .as_u64()
-            .expect("validWells missing or invalid");
-        valid_wells_left += pulse_file.metadata["validWellsLeft"]
-            .as_u64()
-            .expect("validWellsLeft missing or invalid");
-        valid_wells_right += pulse_file.metadata["validWellsRight"]
-            .as_u64()
-            .expect("validWellsRight missing or invalid");
-        tot_rows += pulse_file.metadata["rows"]
-            .as_u64()
-            .expect("rows missing or invalid") as usize;
-
-        let run_roi_offset_col = pulse_file.metadata["roi_offset_col"]
-            .as_u64()
-            .expect("roi_offset_col missing or invalid");
-        let run_last_col = run_roi_offset_col
-            + pulse_file.metadata["roi_cols"]
-                .as_u64()
-                .expect("roi_cols missing or invalid");
-        if roi_offset_col.is_none() || run_roi_offset_col < roi_offset_col.unwrap() {
-            roi_offset_col = Some(run_roi_offset_col);
-        }
-        if last_col.is_none() || run_last_col > last_col.unwrap() {
-            last_col = Some(run_last_col);
-        }
-        total_aperture_data_size += pulse_file.header.index_offset - pulse_file.header.data_offset;
-    }
-    if last_col.is_none() || roi_offset_col.is_none() {
-        return Err(anyhow!("roi_offset_col or last_col is missing"));
-    }
-    let roi_cols = last_col.unwrap() - roi_offset_col.unwrap();
-
-    // Update metadata
-    let mut new_metadata: Value = pulse_files[0].metadata.clone();
-    new_metadata["rows"] = Value::from(tot_rows);
-    new_metadata["validWells"] = Value::from(valid_wells);
-    new_metadata["validWellsLeft"] = Value::from(valid_wells_left);
-    new_metadata["validWellsRight"] = Value::from(valid_wells_right);
-    new_metadata["roi_cols"] = Value::from(roi_cols);
-    new_metadata["roi_rows"] = Value::from(tot_rows);
-    new_metadata["roi_offset_col"] = Value::from(roi_offset_col.unwrap());
-
-    let new_raw_metadata = new_metadata.to_string();
-    let new_metadata_len = new_raw_metadata.len() as u32;
-
-    // Find new data offset, rounded up to the next multiple of 16
-    // We do this so that each aperture header and pulse record will be aligned to an integer multiple of its length
-    let new_data_offset = {
-        let offset = (pulse_files[0].header.data_offset as i64
-            + (new_metadata_len as i64 - pulse_files[0].header.metadata_length as i64))
-            as u64;
-        (offset + 15) & !15 // Align to 16-byte boundary
-    };
-
-    let new_index_offset = new_data_offset + total_aperture_data_size;
-
-    // Create new header with calculated index offset
-    let new_file_header = PulseFileHeader {
-        num_reads: tot_reads,
-        metadata_length: new_metadata_len,
-        data_offset: new_data_offset,
-        index_offset: new_index_offset,
-        ..pulse_files[0].header
-    };
-
-    // Open the new file with a buffered writer and write the header
-    let mut new_file: BufWriter<File> =
-        BufWriter::with_capacity(BUFFER_SIZE, File::create(new_file_name)?);
-    new_file_header.write_all(&mut new_file)?;
-
-    // Copy record info from first pulse file
-    let mut record_buffer = vec![0; 4 * new_file_header.num_encoding_records as usize];
-    pulse_files[0]
-        .file
-        .seek(SeekFrom::Start(FILE_HEADER_SIZE_FULL as u64))?; // Skip the header
-    pulse_files[0].file.read_exact(&mut record_buffer)?;
-    new_file.write_all(&record_buffer)?;
-
-    // Write the new raw metadata
-    new_file.write_all(new_raw_metadata.as_bytes())?;
-
-    // Write zeros until we hit position new_data_offset
-    let stream_position = new_file.stream_position()?;
-    if stream_position > new_data_offset as u64 {
-        return Err(anyhow!("Stream position exceeds new data offset"));
-    }
-    let zero_buffer: Vec<u8> = vec![0; new_data_offset as usize - stream_position as usize];
-    new_file.write_all(&zero_buffer)?;
-
-    // Verify that our position matches new_data_offset
-    if new_file.stream_position()? != new_data_offset {
-        return Err(anyhow!("Stream position mismatch"));
-    }
-
-    // Copy aperture records to new file, updating aperture indices and y positions as we go
-    let mut row_offset: u32 = 0;
-    let mut ap_index_offset: u32 = 0;
-    let mut ap_header_buffer = [0u8; READ_HEADER_SIZE];
-    let mut ap_buffer = vec![0; BUFFER_SIZE]; // 1MB buffer for pulse records
-    for pulse_file in pulse_files.iter_mut() {
-        for ap in pulse_file.index.apertures.iter() {
-            // Read aperture header
-            let byte_loc = pulse_file
-                .index
-                .get(*ap)
-                .expect("Invalid aperture index encountered");
-            pulse_file.file.seek(SeekFrom::Start(byte_loc))?;
-            pulse_file.file.read_exact(&mut ap_header_buffer)?;
-
-            // Update aperture header's position and index, then write to new file
-            let mut aperture_header = ApertureHeader::new(&ap_header_buffer, byte_loc)?;
-            aperture_header.y += row_offset;
-            aperture_header.well_id += ap_index_offset;
-            aperture_header.write_all(&mut new_file)?;
-
-            // Copy pulse records to new file
-            let mut remaining_bytes = aperture_header.num_pulses as usize * PULSE_SIZE;
-            while remaining_bytes > 0 {
-                let slice_len = remaining_bytes.min(ap_buffer.len());
-                let ap_buffer_slice = &mut ap_buffer[0..slice_len];
-                pulse_file.file.read_exact(ap_buffer_slice)?;
-                new_file.write_all(ap_buffer_slice)?;
-                remaining_bytes -= slice_len;
-            }
-        }
-        let rows = pulse_file.metadata["rows"]
-            .as_u64()
-            .expect("rows missing or invalid");
-        let cols = pulse_file.metadata["cols"]
-            .as_u64()
-            .expect("cols missing or invalid");
-        row_offset += rows as u32;
-        ap_index_offset += (rows * cols) as u32;
-    }
-
-    // Verify that we are at the expected location of the index
-    if new_file.stream_position()? != new_file_header.index_offset {
-        return Err(anyhow!("Stream position mismatch"));
-    }
-
-    // Write the index magic
-    new_file.write_all(&INDEX_SECTION_MAGIC.to_le_bytes())?;
-
-    // Finally, write the aperture index
-    let mut cumulative_offset = new_data_offset as i64;
-    ap_index_offset = 0;
-    for pulse_file in pulse_files.iter_mut() {
-        let byte_offset = cumulative_offset - pulse_file.header.data_offset as i64;
-        cumulative_offset +=
-            pulse_file.header.index_offset as i64 - pulse_file.header.data_offset as i64;
-
-        for ap in pulse_file.index.apertures.iter() {
-            let new_ap = *ap as u32 + ap_index_offset;
-            let original_byte_loc =
-                pulse_file.index.get(*ap).expect("Invalid aperture index") as i64;
-            let new_byte_loc = (original_byte_loc + byte_offset) as u64;
-            new_file.write_all(&new_ap.to_le_bytes())?;
-            new_file.write_all(&new_byte_loc.to_le_bytes())?;
-        }
-        let rows = pulse_file.metadata["rows"]
-            .as_u64()
-            .expect("rows missing or invalid");
-        let cols = pulse_file.metadata["cols"]
-            .as_u64()
-            .expect("cols missing or invalid");
-        ap_index_offset += (rows * cols) as u32;
-    }
-    new_file.flush()?;
-
-    Ok(())
-}
@@ -0,0 +1,187 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::ApertureHeader;
+use crate::pulse_reader::merge::{MergeLayout, check_inputs, matched_properties, required};
+use crate::pulse_reader::records::RawRecord;
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+/// Join sequential acquisitions of the same chip into a single pulses.bin file
+///
+/// Each input is treated as a later part of the same run, starting at the frame at which
+/// the previous input ended according to its `duration`. The records of apertures with the
+/// same well_id are joined, with padding records inserted where necessary so that the
+/// pulses of every input keep their original timing relative to the start of that input.
+/// As a result, `NormalizedPulse.start_f` continues to increase across inputs. The
+/// `event_frame` of step records is shifted by the start of their input in the same way.
+///
+/// The metadata of the first input is used for the new file, with `duration` set to the
+/// total duration of all inputs. Every input must have the same format version, encoding
+/// records, frame rate, number of rows and number of columns, and an aperture present in
+/// more than one input must have the same position in each. Apertures present in only
+/// some of the inputs are kept. An existing file is handled according to
+/// [`OverwritePolicy`].
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::concat::concat_pulse_files_in_time;
+/// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+/// # use std::path::PathBuf;
+/// # use tempfile::tempdir;
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// # let temp_dir = tempdir().unwrap();
+/// # let new_pulse_file_path = temp_dir.path().join("concat_pulses.bin");
+/// let pulse_readers = vec![
+///     PulseReader::open(&pulse_file_path).unwrap(),
+///     PulseReader::open(&pulse_file_path).unwrap(),
+/// ];
+/// concat_pulse_files_in_time(&pulse_readers, &new_pulse_file_path, OverwritePolicy::Fail).unwrap();
+///
+/// let concat_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+/// assert_eq!(concat_reader.run_metadata.duration, Some(72000.0));
+/// ```
+pub fn concat_pulse_files_in_time<P: AsRef<Path>>(
+    pulse_files: &[PulseReader],
+    new_file_name: P,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    if pulse_files.is_empty() {
+        return Err(PulseError::invalid_argument("No pulse files provided").into());
+    }
+    overwrite.check(&new_file_name)?;
+    check_inputs(
+        pulse_files,
+        |pulse_file| {
+            let mut properties = matched_properties(pulse_file, &MergeLayout::Vertical);
+            let rows = pulse_file.run_metadata.rows.map(|rows| rows.to_string());
+            properties.push(("rows", rows));
+            properties
+        },
+        |pulse_file| vec![("duration", pulse_file.run_metadata.duration.is_some())],
+    )?;
+
+    // Find the frame at which each input starts
+    let fps = required(pulse_files[0].run_metadata.fps, "fps")?;
+    let mut starts: Vec<u64> = Vec::with_capacity(pulse_files.len());
+    let mut num_frames: u64 = 0;
+    let mut duration: f64 = 0.0;
+    for pulse_file in pulse_files.iter() {
+        let run_duration = required(pulse_file.run_metadata.duration, "duration")?;
+        starts.push(num_frames);
+        num_frames += (run_duration * fps).ceil() as u64;
+        duration += run_duration;
+    }
+    if num_frames > u32::MAX as u64 {
+        return Err(anyhow!(
+            "Combined run of {} frames is too long to be represented",
+            num_frames
+        ));
+    }
+
+    let mut new_metadata: Value = pulse_files[0].metadata.clone();
+    new_metadata["duration"] = Value::from(duration);
+
+    // Join the records of each aperture across inputs, in index order
+    let apertures: BTreeSet<usize> = pulse_files
+        .iter()
+        .flat_map(|pulse_file| pulse_file.index.apertures.iter().copied())
+        .collect();
+    let mut writer = PulseFileWriter::create(
+        new_file_name,
+        &new_metadata,
+        &pulse_files[0].record_types,
+        overwrite,
+    )?;
+    for ap in apertures {
+        let mut records: Vec<RawRecord> = Vec::new();
+        let mut aperture_header: Option<ApertureHeader> = None;
+        let mut end: u64 = 0;
+        for (input, (pulse_file, start)) in pulse_files.iter().zip(starts.iter()).enumerate() {
+            if pulse_file.index.get(ap).is_err() {
+                continue;
+            }
+            let (input_records, input_header) = pulse_file.get_raw_records(ap)?;
+            if let Some(header) = &aperture_header
+                && (header.x, header.y) != (input_header.x, input_header.y)
+            {
+                return Err(anyhow!(
+                    "Aperture {} is at ({}, {}) in input {}, but at ({}, {}) in an earlier input",
+                    ap,
+                    input_header.y,
+                    input_header.x,
+                    input,
+                    header.y,
+                    header.x
+                ));
+            }
+            aperture_header.get_or_insert(input_header);
+            end = append_records(&mut records, &input_records, end, *start)
+                .with_context(|| format!("Failed to join aperture {} of input {}", ap, input))?;
+        }
+        if let Some(aperture_header) = aperture_header {
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+    }
+    writer.finish()
+}
+
+/// Appends the records of an aperture from one input to the records of the joined aperture
+///
+/// `end` is the frame at which the last record in `output` ends, and `start` is the frame at
+/// which the input starts, both in the combined timeline. Leading padding records are
+/// replaced by new padding records that fill the gap between `end` and the first record of
+/// the input. Returns the frame at which the last appended record ends.
+fn append_records(
+    output: &mut Vec<RawRecord>,
+    records: &[RawRecord],
+    end: u64,
+    start: u64,
+) -> Result<u64> {
+    let Some(first) = records.iter().position(|record| !record.is_padding()) else {
+        return Ok(end);
+    };
+
+    // Frames are counted from the start of the input, including any leading padding
+    let mut input_end = first as u64 * PADDING_FRAMES_SINCE_LAST as u64;
+    let first_start = start + input_end + records[first].frames_since_last as u64;
+    if first_start < end {
+        return Err(anyhow!(
+            "Records of the previous input end at frame {}, after the first record of this input starts at frame {}",
+            end,
+            first_start
+        ));
+    }
+    let gap = RawRecord::pad_gap(output, first_start - end);
+
+    for (idx, record) in records[first..].iter().enumerate() {
+        input_end += record.frames_since_last as u64 + record.duration as u64;
+        let mut record = record.clone();
+        if idx == 0 {
+            record.frames_since_last = gap;
+        }
+        shift_event_frame(&mut record, start)?;
+        output.push(record);
+    }
+    Ok(start + input_end)
+}
+
+/// Shifts the frame stored in a step record by `shift` frames
+///
+/// Other records are unchanged.
+fn shift_event_frame(record: &mut RawRecord, shift: u64) -> Result<()> {
+    let Some(event_frame) = record.event_frame() else {
+        return Ok(());
+    };
+    let event_frame = u32::try_from(event_frame as u64 + shift)
+        .map_err(|_| anyhow!("Event frame {} is too large to be shifted", event_frame))?;
+    record.set_event_frame(event_frame);
+    Ok(())
+}
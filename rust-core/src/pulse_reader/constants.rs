@@ -7,6 +7,11 @@ pub const PULSE_SIZE: usize = 16;
 pub const INDEX_SECTION_MAGIC: u64 = 724275076598221092; // equivalent to "$INDX$\r\n"
 pub const INDEX_RECORD_SIZE: usize = 12;
 pub const BINARY_PULSE_FILE_MAGIC: u32 = 1349079889;
+pub const PULSE_FILE_VERSION: u32 = 4;
+
+pub const BUFFER_SIZE: usize = 1024 * 1024; // 1MB buffer size for reading and writing
+
+pub const PADDING_FRAMES_SINCE_LAST: u16 = 65535;
 
 pub const NON_PULSE_RECORD_LONG_PULSE_DROPPED: i16 = -2;
 pub const NON_PULSE_RECORD_LONG_PULSE_UPDATE: i16 = -3;
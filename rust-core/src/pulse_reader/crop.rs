@@ -0,0 +1,212 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::records::{
+    FormattedRecord, FormattedRecordType, PulseNormalizer, RawRecord, RecordBuilder,
+};
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::Value;
+
+/// What to do with pulses that straddle the start or end of a cropped time window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BoundaryPolicy {
+    /// Drop pulses that are not entirely inside the window
+    #[default]
+    Drop,
+    /// Shorten pulses so that they only cover the part inside the window
+    Clip,
+    /// Keep pulses whole
+    ///
+    /// The cropped file starts at the start of the earliest pulse that straddles the start
+    /// of the window, and its duration is extended to cover pulses that straddle the end.
+    Keep,
+}
+
+impl PulseReader {
+    /// Create a new pulses.bin file with only the records within a time window
+    ///
+    /// The window runs from `start_s` to `end_s` seconds after the start of the run, and
+    /// is rounded to the nearest frames. Every aperture is copied to the new file with
+    /// only the records inside the window, and the start of the window becomes the start
+    /// of the new file, so the start frame of every record is reduced by the same amount.
+    /// Pulses that straddle the start or end of the window are handled according to
+    /// `boundary`. With `BoundaryPolicy::Keep`, the new file instead starts at the start of
+    /// the earliest pulse that straddles the start of the window, in any aperture, so that
+    /// those pulses can be kept whole. The `event_frame` of step records is shifted along
+    /// with the records, and is set to the start of the new file if the step happened
+    /// before it.
+    ///
+    /// The `duration` in the metadata of the new file is set to the time from its start to
+    /// the end of the window, ending at the end of the run if the window extends past it,
+    /// or to the end of the last pulse kept with `BoundaryPolicy::Keep` if that is later.
+    /// With `BoundaryPolicy::Keep`, the file is read twice: once to find the start and
+    /// duration of the new file, and once to write the cropped apertures. An existing file
+    /// is handled according to [`OverwritePolicy`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::crop::BoundaryPolicy;
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let new_pulse_file_path = temp_dir.path().join("cropped_pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// // Keep minutes 10 to 40 of the run
+    /// pulse_reader
+    ///     .write_cropped(
+    ///         600.0,
+    ///         2400.0,
+    ///         BoundaryPolicy::Drop,
+    ///         &new_pulse_file_path,
+    ///         OverwritePolicy::Fail,
+    ///     )
+    ///     .unwrap();
+    ///
+    /// let cropped_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+    /// assert_eq!(cropped_reader.run_metadata.duration, Some(1800.0));
+    /// ```
+    pub fn write_cropped<P: AsRef<Path>>(
+        &self,
+        start_s: f64,
+        end_s: f64,
+        boundary: BoundaryPolicy,
+        file_name: P,
+        overwrite: OverwritePolicy,
+    ) -> Result<()> {
+        if !(0.0..end_s).contains(&start_s) {
+            return Err(PulseError::invalid_argument(format!(
+                "Invalid time window: {} s to {} s",
+                start_s, end_s
+            ))
+            .into());
+        }
+        let end_s = self.run_metadata.duration.map_or(end_s, |d| end_s.min(d));
+        if start_s >= end_s {
+            return Err(PulseError::invalid_argument(format!(
+                "Time window starts at {} s, after the run ends at {} s",
+                start_s, end_s
+            ))
+            .into());
+        }
+        let fps = self.fps as f64;
+        let window = ((start_s * fps).round() as u32, (end_s * fps).round() as u32);
+
+        // Kept pulses may start before the window and end after it, and the duration is
+        // written before the apertures, so find the extent of every kept pulse first
+        let (mut origin, mut end) = window;
+        if boundary == BoundaryPolicy::Keep {
+            for &ap in self.index.apertures.iter() {
+                let (first, last) = straddling_extent(self, ap, window)?;
+                origin = origin.min(first);
+                end = end.max(last);
+            }
+        }
+        let origin_s = if origin < window.0 {
+            origin as f64 / fps
+        } else {
+            start_s
+        };
+        let mut duration = end_s - origin_s;
+        if end > window.1 {
+            duration = duration.max((end - origin) as f64 / fps);
+        }
+
+        let mut metadata = self.metadata.clone();
+        metadata["duration"] = Value::from(duration);
+        let mut writer =
+            PulseFileWriter::create(file_name, &metadata, &self.record_types, overwrite)?;
+        for &ap in self.index.apertures.iter() {
+            let (raw_records, aperture_header) = self.get_raw_records(ap)?;
+            let records = crop_records(self, &raw_records, window, origin, boundary)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Finds the frames at which the pulses of an aperture that straddle the window
+/// `(start, end)` start and end
+///
+/// Returns the start of the first pulse that straddles the start of the window, or the
+/// start of the window if none does, and the end of the last pulse that straddles the end
+/// of the window, or the end of the window if none does. Records are read in bounded
+/// chunks, so memory use does not depend on the size of the aperture.
+fn straddling_extent(
+    pulse_file: &PulseReader,
+    aperture: usize,
+    (window_start, window_end): (u32, u32),
+) -> Result<(u32, u32)> {
+    let (records, _) = pulse_file.iter_aperture_records(aperture)?;
+    let mut normalizer = PulseNormalizer::new(pulse_file.fps);
+    let (mut start, mut end) = (window_start, window_end);
+    for record in records {
+        if let Some(pulse) = normalizer.push(&record?)
+            && pulse.end_f > window_start
+            && pulse.start_f < window_end
+        {
+            start = start.min(pulse.start_f);
+            end = end.max(pulse.end_f);
+        }
+    }
+    Ok((start, end))
+}
+
+/// Keeps the records of an aperture inside the window `(start, end)`, given in frames
+///
+/// The records are placed relative to frame `origin`, the start of the new file, which is
+/// no later than the start of the window or any pulse kept with `BoundaryPolicy::Keep`.
+fn crop_records(
+    pulse_file: &PulseReader,
+    raw_records: &[RawRecord],
+    (window_start, window_end): (u32, u32),
+    origin: u32,
+    boundary: BoundaryPolicy,
+) -> Result<Vec<RawRecord>> {
+    let mut output = RecordBuilder::new(origin as u64);
+    let mut normalizer = PulseNormalizer::new(pulse_file.fps);
+    for (idx, raw_record) in raw_records.iter().enumerate() {
+        let record = FormattedRecord::from_raw_with_encoding(raw_record, &pulse_file.encoding, idx);
+        let (start, end) = match normalizer.push(&record) {
+            Some(pulse) => (pulse.start_f, pulse.end_f),
+            None => (normalizer.last_record_end(), normalizer.last_record_end()),
+        };
+        if record.record_type == FormattedRecordType::Padding {
+            continue;
+        }
+
+        let mut raw_record = raw_record.clone();
+        let mut start = start;
+        if record.record_type == FormattedRecordType::Pulse {
+            if end <= window_start || start >= window_end {
+                continue;
+            }
+            let straddles = start < window_start || end > window_end;
+            let end = match boundary {
+                BoundaryPolicy::Drop if straddles => continue,
+                BoundaryPolicy::Drop | BoundaryPolicy::Keep => end,
+                BoundaryPolicy::Clip => {
+                    start = start.max(window_start);
+                    end.min(window_end)
+                }
+            };
+            raw_record.duration = (end - start) as u16;
+        } else if !(window_start..window_end).contains(&start) {
+            continue;
+        }
+
+        if let Some(event_frame) = raw_record.event_frame() {
+            raw_record.set_event_frame(event_frame.saturating_sub(origin));
+        }
+        output.push(start as u64, raw_record)?;
+    }
+    Ok(output.into_records())
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// Errors raised while reading, validating or writing pulses.bin files
+///
+/// Functions in this crate return `anyhow::Result`, but failures that callers may want to
+/// handle programmatically are created as a `PulseError`, which can be recovered from the
+/// returned error with `downcast_ref`.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::error::PulseError;
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+///
+/// let err = pulse_reader.get_all_records(usize::MAX).err().unwrap();
+/// assert!(matches!(
+///     err.downcast_ref::<PulseError>(),
+///     Some(PulseError::ApertureNotFound { index: usize::MAX })
+/// ));
+/// ```
+#[derive(Debug)]
+pub enum PulseError {
+    /// The file does not start with the pulses.bin magic number
+    BadMagic { magic: u32 },
+    /// The file header describes a record encoding that this library cannot parse
+    UnsupportedEncoding { field: &'static str, value: u64 },
+    /// The aperture index does not start with the index section magic number
+    IndexMagicMismatch { magic: u64 },
+    /// The requested aperture index is not contained in the file
+    ApertureNotFound { index: usize },
+    /// The file ended before a read starting at the given byte offset could complete
+    Truncated { offset: u64 },
+    /// A required metadata field is missing or has the wrong type
+    MetadataField { name: String },
+    /// The metadata section is not valid UTF-8 encoded JSON
+    InvalidMetadata { reason: String },
+    /// An argument is outside of the values accepted by the operation
+    InvalidArgument { message: String },
+    /// The pulse files passed to a merge disagree on properties that must match
+    IncompatibleInputs { conflicts: Vec<MergeConflict> },
+    /// Any other I/O error
+    Io(std::io::Error),
+}
+
+impl PulseError {
+    /// The name of the error variant, e.g. `"ApertureNotFound"`
+    ///
+    /// Used by the language bindings to select the exception or condition class to raise.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PulseError::BadMagic { .. } => "BadMagic",
+            PulseError::UnsupportedEncoding { .. } => "UnsupportedEncoding",
+            PulseError::IndexMagicMismatch { .. } => "IndexMagicMismatch",
+            PulseError::ApertureNotFound { .. } => "ApertureNotFound",
+            PulseError::Truncated { .. } => "Truncated",
+            PulseError::MetadataField { .. } => "MetadataField",
+            PulseError::InvalidMetadata { .. } => "InvalidMetadata",
+            PulseError::InvalidArgument { .. } => "InvalidArgument",
+            PulseError::IncompatibleInputs { .. } => "IncompatibleInputs",
+            PulseError::Io(_) => "Io",
+        }
+    }
+
+    /// Converts an error from a read starting at `offset`
+    ///
+    /// Reads that hit the end of the file are reported as `Truncated`.
+    pub(crate) fn from_read(err: std::io::Error, offset: u64) -> Self {
+        if err.kind() == std::io::ErrorKind::UnexpectedEof {
+            PulseError::Truncated { offset }
+        } else {
+            PulseError::Io(err)
+        }
+    }
+
+    /// Shorthand for a missing or invalid metadata field
+    pub(crate) fn metadata_field(name: &str) -> Self {
+        PulseError::MetadataField {
+            name: name.to_string(),
+        }
+    }
+
+    /// Shorthand for an invalid argument
+    pub(crate) fn invalid_argument(message: impl Into<String>) -> Self {
+        PulseError::InvalidArgument {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for PulseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PulseError::BadMagic { magic } => {
+                write!(
+                    f,
+                    "Pulse file header is invalid! Bad magic number: {:#x}",
+                    magic
+                )
+            }
+            PulseError::UnsupportedEncoding { field, value } => {
+                write!(f, "Unsupported {}: {}", field, value)
+            }
+            PulseError::IndexMagicMismatch { magic } => {
+                write!(f, "Index magic number mismatch: {:#x}", magic)
+            }
+            PulseError::ApertureNotFound { index } => write!(
+                f,
+                "This file does not contain the provided aperture index: {}",
+                index
+            ),
+            PulseError::Truncated { offset } => write!(
+                f,
+                "Pulse file is truncated: unexpected end of file reading at byte {}",
+                offset
+            ),
+            PulseError::MetadataField { name } => {
+                write!(f, "Missing or invalid '{}' field in metadata", name)
+            }
+            PulseError::InvalidMetadata { reason } => {
+                write!(f, "Failed to parse metadata: {}", reason)
+            }
+            PulseError::InvalidArgument { message } => write!(f, "{}", message),
+            PulseError::IncompatibleInputs { conflicts } => {
+                write!(f, "Pulse files cannot be merged: ")?;
+                for (i, conflict) in conflicts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", conflict)?;
+                }
+                Ok(())
+            }
+            PulseError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+/// A property of one of the inputs to a merge that prevents it from being merged
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MergeConflict {
+    /// The position of the input in the list of files being merged
+    pub input: usize,
+    pub file_name: PathBuf,
+    pub property: &'static str,
+    /// The value of the property in the first input, if it must match
+    pub expected: Option<String>,
+    /// The value of the property in this input, or None if it is missing
+    pub found: Option<String>,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input {} ({}) ", self.input, self.file_name.display())?;
+        match (&self.expected, &self.found) {
+            (Some(expected), Some(found)) => write!(
+                f,
+                "has {} {}, but input 0 has {}",
+                self.property, found, expected
+            ),
+            (None, Some(found)) => write!(
+                f,
+                "has {} {}, but input 0 is missing it",
+                self.property, found
+            ),
+            (Some(expected), None) => write!(
+                f,
+                "is missing {}, but input 0 has {}",
+                self.property, expected
+            ),
+            (None, None) => write!(f, "is missing {}", self.property),
+        }
+    }
+}
+
+impl std::error::Error for PulseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PulseError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PulseError {
+    fn from(err: std::io::Error) -> Self {
+        PulseError::Io(err)
+    }
+}
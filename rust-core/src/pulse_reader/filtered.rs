@@ -0,0 +1,124 @@
+use crate::pulse_filter::PulseFilter;
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::records::{
+    FormattedRecord, FormattedRecordType, PulseNormalizer, RawRecord, RecordBuilder,
+};
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::{Value, json};
+
+impl PulseReader {
+    /// Create a new pulses.bin file with only the pulses that pass a filter
+    ///
+    /// Every aperture is copied to the new file with the pulse records that fail
+    /// `pulse_filter` removed. Non-pulse records are removed as well, unless
+    /// `keep_non_pulse_records` is true. The `frames_since_last` of the remaining records
+    /// is updated, and padding records are inserted where needed, so that every record
+    /// keeps its original start frame. Apertures with no remaining records are kept.
+    ///
+    /// The filter is recorded in the metadata of the new file, by appending its
+    /// parameters to the `pulseFilters` list. An existing file is handled according to
+    /// [`OverwritePolicy`].
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_filter::PulseFilter;
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let new_pulse_file_path = temp_dir.path().join("filtered_pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let pulse_filter = PulseFilter {
+    ///     min_dur_f: Some(3),
+    ///     ..Default::default()
+    /// };
+    /// pulse_reader
+    ///     .write_filtered(&pulse_filter, &new_pulse_file_path, false, OverwritePolicy::Fail)
+    ///     .unwrap();
+    ///
+    /// let filtered_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+    /// let ap = pulse_reader.index.apertures[0];
+    /// let (pulses, _) = filtered_reader.get_pulses(ap, None).unwrap();
+    /// assert!(pulses.iter().all(|pulse| pulse.dur_f >= 3));
+    /// ```
+    pub fn write_filtered<P: AsRef<Path>>(
+        &self,
+        pulse_filter: &PulseFilter,
+        file_name: P,
+        keep_non_pulse_records: bool,
+        overwrite: OverwritePolicy,
+    ) -> Result<()> {
+        let mut filter_params = serde_json::to_value(pulse_filter)?;
+        filter_params["keep_non_pulse_records"] = Value::from(keep_non_pulse_records);
+        let mut metadata = self.metadata.clone();
+        match metadata.get_mut("pulseFilters") {
+            Some(Value::Array(filters)) => filters.push(filter_params),
+            Some(_) => return Err(PulseError::metadata_field("pulseFilters").into()),
+            None => metadata["pulseFilters"] = json!([filter_params]),
+        }
+
+        let mut writer =
+            PulseFileWriter::create(file_name, &metadata, &self.record_types, overwrite)?;
+        for &ap in self.index.apertures.iter() {
+            let (raw_records, aperture_header) = self.get_raw_records(ap)?;
+            let records = filter_records(self, &raw_records, pulse_filter, keep_non_pulse_records)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()
+    }
+}
+
+/// Removes the records of an aperture that fail a filter, preserving the timing of the rest
+fn filter_records(
+    pulse_file: &PulseReader,
+    raw_records: &[RawRecord],
+    pulse_filter: &PulseFilter,
+    keep_non_pulse_records: bool,
+) -> Result<Vec<RawRecord>> {
+    let records: Vec<FormattedRecord> = raw_records
+        .iter()
+        .enumerate()
+        .map(|(idx, raw_record)| {
+            FormattedRecord::from_raw_with_encoding(raw_record, &pulse_file.encoding, idx)
+        })
+        .collect();
+
+    // Find the records of the pulses that pass the filter
+    let mut normalizer = PulseNormalizer::new(pulse_file.fps);
+    let pulses: Vec<_> = records
+        .iter()
+        .filter_map(|record| normalizer.push(record))
+        .collect();
+    let passing: HashSet<usize> = pulse_filter
+        .filter_pulses(&pulses, pulse_file.fps)?
+        .iter()
+        .map(|pulse| pulse.index)
+        .collect();
+
+    // Walk the original timeline, and re-base each kept record on the last kept record
+    let mut output = RecordBuilder::new(0);
+    let mut end: u64 = 0;
+    for (raw_record, record) in raw_records.iter().zip(records.iter()) {
+        let start = end + raw_record.frames_since_last as u64;
+        end = start + raw_record.duration as u64;
+        let keep = match record.record_type {
+            FormattedRecordType::Pulse => passing.contains(&record.index),
+            FormattedRecordType::Padding => false,
+            _ => keep_non_pulse_records,
+        };
+        if keep {
+            output.push(start, raw_record.clone())?;
+        }
+    }
+    Ok(output.into_records())
+}
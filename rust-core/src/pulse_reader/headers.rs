@@ -1,12 +1,86 @@
 use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::read_exact_from;
 use anyhow::{Result, anyhow};
 use serde::Serialize;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// The versions of the pulses.bin format that can be read
+///
+/// Every version starts with the same 16-byte partial header (magic number, version and
+/// number of reads), which is used to determine how the rest of the file is laid out.
+/// Files are always written using the latest version.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum FormatVersion {
+    /// Files written by earlier instrument software, which only have the partial header
+    ///
+    /// The partial header is followed by the metadata length as a u32, then the metadata.
+    /// The record encodings are fixed (see `FormatVersion::default_record_types`), the data
+    /// section starts at the next 16-byte boundary, and the index fills the end of the file.
+    V3,
+    /// The current version, with a 48-byte header followed by a table of record encodings
+    V4,
+}
+
+impl FormatVersion {
+    /// Look up the format of a version number from a file header
+    pub fn from_version(version: u32) -> Result<Self, PulseError> {
+        match version {
+            3 => Ok(FormatVersion::V3),
+            4 => Ok(FormatVersion::V4),
+            _ => Err(PulseError::UnsupportedEncoding {
+                field: "file version",
+                value: version as u64,
+            }),
+        }
+    }
+
+    /// The version number stored in the file header
+    pub fn version(&self) -> u32 {
+        match self {
+            FormatVersion::V3 => 3,
+            FormatVersion::V4 => 4,
+        }
+    }
+
+    /// Whether the file header contains the full 48-byte layout, including section offsets
+    pub fn has_full_header(&self) -> bool {
+        *self >= FormatVersion::V4
+    }
+
+    /// Whether the file contains a table describing its record encodings
+    pub fn has_encoding_table(&self) -> bool {
+        *self >= FormatVersion::V4
+    }
+
+    /// The record encodings used by files without an encoding table
+    pub fn default_record_types() -> Vec<PulseRecordType> {
+        [
+            (0, 0, 0),
+            (1, 0, 0),
+            (2, 7, 245),
+            (3, 7, 245),
+            (4, 5, 0),
+            (5, 5, 0),
+            (6, 7, 0),
+            (7, 7, 0),
+        ]
+        .iter()
+        .map(|&(record_type, bits, offset): &(u8, u8, u16)| {
+            let offset = offset.to_le_bytes();
+            // The fixed encodings all have a representable scale
+            PulseRecordType::new(&[record_type, bits, offset[0], offset[1]]).unwrap()
+        })
+        .collect()
+    }
+}
 
 /// The header of a pulses.bin file
 ///
 /// This struct contains the raw header information contained within the first
-/// 48 bytes of every pulses.bin file.
+/// 48 bytes of every pulses.bin file. For files with an earlier `FormatVersion`,
+/// fields that are not stored in the file are derived from its layout.
 #[derive(Serialize, Debug)]
 pub struct PulseFileHeader {
     pub magic: u32,
@@ -86,35 +160,117 @@ impl PulseFileHeader {
         })
     }
 
+    /// Reads the file header and record encodings from the start of a file
+    ///
+    /// Reads the partial header to determine the format version, then reads the rest of
+    /// the header and the record encodings according to that version. The header is
+    /// validated, and the file is left positioned at the start of the metadata.
+    pub fn read<R: Read + Seek>(file: &mut R) -> Result<(Self, Vec<PulseRecordType>)> {
+        let mut buffer = [0; FILE_HEADER_SIZE_FULL];
+        read_exact_from(file, &mut buffer[..FILE_HEADER_SIZE_PARTIAL], 0)?;
+        let magic = u32::from_le_bytes(buffer[0..4].try_into()?);
+        if magic != BINARY_PULSE_FILE_MAGIC {
+            return Err(PulseError::BadMagic { magic }.into());
+        }
+        let version = u32::from_le_bytes(buffer[4..8].try_into()?);
+
+        let header = match FormatVersion::from_version(version)? {
+            FormatVersion::V4 => {
+                read_exact_from(
+                    file,
+                    &mut buffer[FILE_HEADER_SIZE_PARTIAL..],
+                    FILE_HEADER_SIZE_PARTIAL as u64,
+                )?;
+                PulseFileHeader::new(&buffer)?
+            }
+            FormatVersion::V3 => {
+                let num_reads = u64::from_le_bytes(buffer[8..16].try_into()?);
+                let mut length_buffer = [0; 4];
+                read_exact_from(file, &mut length_buffer, FILE_HEADER_SIZE_PARTIAL as u64)?;
+                let metadata_length = u32::from_le_bytes(length_buffer);
+
+                // The index fills the end of the file
+                let file_size = file.seek(SeekFrom::End(0)).map_err(PulseError::Io)?;
+                let index_size = num_reads
+                    .checked_mul(INDEX_RECORD_SIZE as u64)
+                    .and_then(|size| size.checked_add(8))
+                    .ok_or(PulseError::Truncated { offset: file_size })?;
+                let index_offset = file_size
+                    .checked_sub(index_size)
+                    .ok_or(PulseError::Truncated { offset: file_size })?;
+                let metadata_offset = (FILE_HEADER_SIZE_PARTIAL + length_buffer.len()) as u64;
+                file.seek(SeekFrom::Start(metadata_offset))
+                    .map_err(PulseError::Io)?;
+
+                PulseFileHeader {
+                    magic,
+                    version,
+                    num_reads,
+                    metadata_length,
+                    encoding_record_type: 1,
+                    encoding_record_size: 4,
+                    num_encoding_records: FormatVersion::default_record_types().len() as u16,
+                    record_header_size: READ_HEADER_SIZE as u32,
+                    record_size: PULSE_SIZE as u32,
+                    data_offset: (metadata_offset + metadata_length as u64 + 15) & !15,
+                    index_offset,
+                }
+            }
+        };
+        header.validate()?;
+
+        let record_types = if header.format_version()?.has_encoding_table() {
+            let mut record_buffer = vec![0; 4 * header.num_encoding_records as usize];
+            read_exact_from(file, &mut record_buffer, FILE_HEADER_SIZE_FULL as u64)?;
+            // chunks_exact guarantees that every chunk is exactly 4 bytes long
+            record_buffer
+                .chunks_exact(4)
+                .map(|buffer| PulseRecordType::new(buffer.try_into().unwrap()))
+                .collect::<Result<_, _>>()?
+        } else {
+            FormatVersion::default_record_types()
+        };
+        Ok((header, record_types))
+    }
+
+    /// The format version of the file
+    pub fn format_version(&self) -> Result<FormatVersion, PulseError> {
+        FormatVersion::from_version(self.version)
+    }
+
+    /// The byte offset of the metadata in the file
+    pub fn metadata_offset(&self) -> u64 {
+        match self.format_version() {
+            Ok(FormatVersion::V3) => (FILE_HEADER_SIZE_PARTIAL + 4) as u64,
+            _ => (FILE_HEADER_SIZE_FULL + 4 * self.num_encoding_records as usize) as u64,
+        }
+    }
+
     /// Validates whether a pulse file header is valid
     ///
     /// The V4 spec of the binary pulse file type has flexibility for future
     /// revisions that would change how the pulse records are parsed. However,
     /// methods for parsing future revisions of the V4 spec will need to be
     /// implemented later. For now, we validate whether the data contained
-    /// within the pulse file header abides our expectations for the current
-    /// version of the V4 spec, and failing that, we return an error.
-    pub fn validate(&self) -> Result<()> {
+    /// within the pulse file header abides our expectations for a supported
+    /// `FormatVersion`, and failing that, we return an error. The encoding
+    /// table itself is checked when building a `RecordEncoding`.
+    pub fn validate(&self) -> Result<(), PulseError> {
         if self.magic != BINARY_PULSE_FILE_MAGIC {
-            return Err(anyhow!("Pulse file header is invalid!"));
+            return Err(PulseError::BadMagic { magic: self.magic });
         }
-        if self.encoding_record_type != 1 {
-            return Err(anyhow!(
-                "Invalid encoding record type: {}",
-                self.encoding_record_type
-            ));
+        self.format_version()?;
+        let unsupported = if self.encoding_record_type != 1 {
+            Some(("encoding record type", self.encoding_record_type as u64))
         } else if self.encoding_record_size != 4 {
-            return Err(anyhow!(
-                "Invalid encoding record size: {}",
-                self.encoding_record_size
-            ));
+            Some(("encoding record size", self.encoding_record_size as u64))
         } else if self.record_size != 16 {
-            return Err(anyhow!("Invalid record size: {}", self.record_size));
-        } else if self.num_encoding_records != 8 {
-            return Err(anyhow!(
-                "Invalid number of encoding records: {}",
-                self.num_encoding_records
-            ));
+            Some(("record size", self.record_size as u64))
+        } else {
+            None
+        };
+        if let Some((field, value)) = unsupported {
+            return Err(PulseError::UnsupportedEncoding { field, value });
         }
         Ok(())
     }
@@ -157,34 +313,45 @@ impl PulseFileIndex {
     /// Parses a byte buffer containing `num_reads * 12` bytes to create a
     /// PulseFileIndex instance.
     pub fn new(buffer: &[u8], num_reads: usize) -> Result<Self> {
-        let min = u32::from_le_bytes(
-            buffer[0..4]
-                .try_into()
-                .map_err(|_| anyhow!("Failed to parse min aperture index from buffer"))?,
-        ) as usize;
-        let max = u32::from_le_bytes(
-            buffer
-                [((num_reads - 1) * INDEX_RECORD_SIZE)..((num_reads - 1) * INDEX_RECORD_SIZE + 4)]
-                .try_into()
-                .map_err(|_| anyhow!("Failed to parse max aperture index from buffer"))?,
-        ) as usize;
-
-        let mut apertures = vec![0usize; num_reads];
+        if buffer.len() < num_reads * INDEX_RECORD_SIZE {
+            return Err(anyhow!(
+                "Index buffer of {} bytes is too short for {} reads",
+                buffer.len(),
+                num_reads
+            ));
+        }
+        // chunks_exact guarantees that every chunk is exactly INDEX_RECORD_SIZE bytes long
+        let entries: Vec<(usize, u64)> = buffer
+            .chunks_exact(INDEX_RECORD_SIZE)
+            .take(num_reads)
+            .map(|chunk| {
+                (
+                    u32::from_le_bytes(chunk[0..4].try_into().unwrap()) as usize,
+                    u64::from_le_bytes(chunk[4..12].try_into().unwrap()),
+                )
+            })
+            .collect();
+        Self::from_entries(&entries)
+    }
+
+    /// Creates a new pulse file index from a list of (aperture index, byte location) pairs
+    ///
+    /// The order of the entries is preserved in `apertures`, which is expected to be sorted.
+    /// Returns an error if no entries are provided.
+    pub fn from_entries(entries: &[(usize, u64)]) -> Result<Self> {
+        let min = entries
+            .iter()
+            .map(|(ap, _)| *ap)
+            .min()
+            .ok_or_else(|| anyhow!("Cannot create an index without any apertures"))?;
+        let max = entries.iter().map(|(ap, _)| *ap).max().unwrap_or(min);
+
         let mut index_map = vec![0u64; max - min + 1];
-        for idx in 0..num_reads {
-            apertures[idx] = u32::from_le_bytes(
-                buffer[(idx * INDEX_RECORD_SIZE)..(idx * INDEX_RECORD_SIZE + 4)]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse aperture index at position {}", idx))?,
-            ) as usize;
-            index_map[apertures[idx] - min] = u64::from_le_bytes(
-                buffer[(idx * INDEX_RECORD_SIZE + 4)..(idx * INDEX_RECORD_SIZE + 12)]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse byte location at position {}", idx))?,
-            );
+        for (ap, byte_loc) in entries.iter() {
+            index_map[ap - min] = *byte_loc;
         }
         Ok(PulseFileIndex {
-            apertures,
+            apertures: entries.iter().map(|(ap, _)| *ap).collect(),
             index_map,
             min,
         })
@@ -194,21 +361,15 @@ impl PulseFileIndex {
     ///
     /// Will return the byte location of the provided aperture index, or will
     /// return an error if the aperture index is not contained in the index.
-    pub fn get(&self, ap: usize) -> Result<u64> {
+    pub fn get(&self, ap: usize) -> Result<u64, PulseError> {
         // If the aperture index is out of bounds, return an error
         if ap < self.min || ap >= self.min + self.index_map.len() {
-            return Err(anyhow!(
-                "This file does not contain the provided aperture index: {}",
-                ap
-            ));
+            return Err(PulseError::ApertureNotFound { index: ap });
         }
         let offset = self.index_map[ap - self.min];
         // If the offset is zero, that means the aperture is not present, so return an error
         if offset == 0u64 {
-            return Err(anyhow!(
-                "This file does not contain the provided aperture index: {}",
-                ap
-            ));
+            return Err(PulseError::ApertureNotFound { index: ap });
         };
         Ok(offset)
     }
@@ -219,7 +380,7 @@ impl PulseFileIndex {
 /// This struct describes how the data contained within a single pulse record
 /// field is to be converted from its original integer type (u16 or i16) to
 /// a floating point representation.
-#[derive(Serialize)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
 pub struct PulseRecordType {
     pub record_type: u8,
     pub bits: u8,
@@ -228,6 +389,26 @@ pub struct PulseRecordType {
 }
 
 impl PulseRecordType {
+    /// Instantiates a record type from a 4-byte encoding record
+    ///
+    /// Returns an error if the number of fractional bits is too large for the scale to be
+    /// represented.
+    pub fn new(buffer: &[u8; 4]) -> Result<Self, PulseError> {
+        let bits = buffer[1];
+        let scale = 1u16
+            .checked_shl(bits as u32)
+            .ok_or(PulseError::UnsupportedEncoding {
+                field: "record type bits",
+                value: bits as u64,
+            })?;
+        Ok(PulseRecordType {
+            record_type: buffer[0],
+            bits,
+            scale: scale as f32,
+            offset: u16::from_le_bytes([buffer[2], buffer[3]]) as f32,
+        })
+    }
+
     /// Format a raw record value
     ///
     /// Converts the raw i16 value of a particular field from a raw record
@@ -249,6 +430,120 @@ impl PulseRecordType {
             fval
         }
     }
+
+    /// Encode a formatted value
+    ///
+    /// Converts an f32 value back into the raw i16 fixed-point format described by the
+    /// PulseRecordType. This is the inverse of `format_value`: NaN and infinite values are
+    /// mapped to their sentinel values, and finite values that fall outside the representable
+    /// range saturate to `V4_NORM_LO` or `V4_NORM_HI`.
+    pub fn encode_value(&self, val: f32) -> i16 {
+        if val.is_nan() {
+            V4_NAN_VAL
+        } else if val == f32::NEG_INFINITY {
+            V4_NEG_INF
+        } else if val == f32::INFINITY {
+            V4_POS_INF
+        } else {
+            ((val - self.offset) * self.scale)
+                .round()
+                .clamp(V4_NORM_LO as f32, V4_NORM_HI as f32) as i16
+        }
+    }
+
+    pub fn write_all<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let mut buffer = [0u8; 4];
+        buffer[0] = self.record_type;
+        buffer[1] = self.bits;
+        buffer[2..4].copy_from_slice(&(self.offset as u16).to_le_bytes());
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+/// The encodings of the formatted fields of a raw record
+///
+/// Each field is looked up in the encoding table of a file by its record type ID, so
+/// the table may list encodings in any order and may contain additional encodings,
+/// which are ignored. Record types 0 and 1 (frames since last and duration) are never
+/// scaled, and so are not required.
+#[derive(Serialize, Debug, Clone)]
+pub struct RecordEncoding {
+    pub m0: PulseRecordType,
+    pub m1: PulseRecordType,
+    pub bk0: PulseRecordType,
+    pub bk1: PulseRecordType,
+    pub std0: PulseRecordType,
+    pub std1: PulseRecordType,
+}
+
+impl RecordEncoding {
+    pub const M0: u8 = 2;
+    pub const M1: u8 = 3;
+    pub const BK0: u8 = 4;
+    pub const BK1: u8 = 5;
+    pub const STD0: u8 = 6;
+    pub const STD1: u8 = 7;
+
+    /// Builds the field map from the encoding table of a file
+    ///
+    /// Returns an error if the table is missing an encoding for one of the formatted
+    /// fields, or contains more than one encoding for the same record type.
+    pub fn new(record_types: &[PulseRecordType]) -> Result<Self, PulseError> {
+        let find = |id: u8| -> Result<PulseRecordType, PulseError> {
+            let mut matches = record_types.iter().filter(|rt| rt.record_type == id);
+            let record_type = matches.next().ok_or(PulseError::UnsupportedEncoding {
+                field: "missing record type",
+                value: id as u64,
+            })?;
+            if matches.next().is_some() {
+                return Err(PulseError::UnsupportedEncoding {
+                    field: "duplicate record type",
+                    value: id as u64,
+                });
+            }
+            Ok(record_type.clone())
+        };
+        Ok(RecordEncoding {
+            m0: find(Self::M0)?,
+            m1: find(Self::M1)?,
+            bk0: find(Self::BK0)?,
+            bk1: find(Self::BK1)?,
+            std0: find(Self::STD0)?,
+            std1: find(Self::STD1)?,
+        })
+    }
+
+    /// Builds the field map from an encoding table that may be incomplete
+    ///
+    /// Like `new`, but never fails: a field without an encoding in the table is left
+    /// unscaled, and a field with more than one uses the first. Used by the conversions
+    /// that take an encoding table, which can't report an invalid table.
+    pub(crate) fn with_defaults(record_types: &[PulseRecordType]) -> Self {
+        let find = |id: u8| {
+            record_types
+                .iter()
+                .find(|rt| rt.record_type == id)
+                .cloned()
+                .unwrap_or(PulseRecordType {
+                    record_type: id,
+                    bits: 0,
+                    scale: 1.0,
+                    offset: 0.0,
+                })
+        };
+        RecordEncoding {
+            m0: find(Self::M0),
+            m1: find(Self::M1),
+            bk0: find(Self::BK0),
+            bk1: find(Self::BK1),
+            std0: find(Self::STD0),
+            std1: find(Self::STD1),
+        }
+    }
 }
 
 /// Metadata pertaining to a single aperture
@@ -305,3 +600,71 @@ impl ApertureHeader {
         Ok(())
     }
 }
+
+/// The headers of a list of apertures, stored as columns
+///
+/// Row `i` of every column describes the aperture `aperture[i]`. See `ApertureHeader` for
+/// the meaning of the other columns.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ApertureHeaderTable {
+    pub aperture: Vec<usize>,
+    pub x: Vec<u32>,
+    pub y: Vec<u32>,
+    pub well_id: Vec<u32>,
+    pub num_pulses: Vec<u32>,
+    pub byte_loc: Vec<u64>,
+}
+
+impl ApertureHeaderTable {
+    /// Creates an empty table with room for `capacity` apertures
+    pub fn with_capacity(capacity: usize) -> Self {
+        ApertureHeaderTable {
+            aperture: Vec::with_capacity(capacity),
+            x: Vec::with_capacity(capacity),
+            y: Vec::with_capacity(capacity),
+            well_id: Vec::with_capacity(capacity),
+            num_pulses: Vec::with_capacity(capacity),
+            byte_loc: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Appends the header of an aperture to the table
+    pub fn push(&mut self, aperture: usize, header: &ApertureHeader) {
+        self.aperture.push(aperture);
+        self.x.push(header.x);
+        self.y.push(header.y);
+        self.well_id.push(header.well_id);
+        self.num_pulses.push(header.num_pulses);
+        self.byte_loc.push(header.byte_loc);
+    }
+
+    /// The number of apertures in the table
+    pub fn len(&self) -> usize {
+        self.aperture.len()
+    }
+
+    /// Whether the table has no apertures
+    pub fn is_empty(&self) -> bool {
+        self.aperture.is_empty()
+    }
+
+    /// The aperture index and header in a row of the table
+    pub fn get(&self, row: usize) -> Option<(usize, ApertureHeader)> {
+        let aperture = *self.aperture.get(row)?;
+        Some((
+            aperture,
+            ApertureHeader {
+                x: self.x[row],
+                y: self.y[row],
+                well_id: self.well_id[row],
+                num_pulses: self.num_pulses[row],
+                byte_loc: self.byte_loc[row],
+            },
+        ))
+    }
+
+    /// Iterates over the aperture indices and headers in the table
+    pub fn iter(&self) -> impl Iterator<Item = (usize, ApertureHeader)> + '_ {
+        (0..self.len()).filter_map(|row| self.get(row))
+    }
+}
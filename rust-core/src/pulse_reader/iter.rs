@@ -0,0 +1,317 @@
+use crate::pulse_filter::PulseFilter;
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::{ApertureHeader, RecordEncoding};
+use crate::pulse_reader::records::*;
+use crate::pulse_reader::{PulseReader, ReadAt, ReadBackend};
+
+use anyhow::Result;
+
+/// A read-ahead buffer for reading a file sequentially using positional reads
+///
+/// Requests that fall within the currently buffered range are served from memory.
+/// Otherwise, the buffer is refilled starting at the requested offset with as many
+/// bytes as fit in the buffer, without reading past `end`.
+pub(super) struct ReadBuffer<'a, S: ReadAt = ReadBackend> {
+    source: &'a S,
+    end: u64,
+    buffer: Vec<u8>,
+    start: u64,
+    len: usize,
+}
+
+impl<'a, S: ReadAt> ReadBuffer<'a, S> {
+    pub(super) fn new(source: &'a S, end: u64) -> Self {
+        ReadBuffer {
+            source,
+            end,
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns `len` bytes starting at `offset`, refilling the buffer if necessary
+    ///
+    /// Returns `PulseError::Truncated` if the read would extend past the largest offset,
+    /// which a corrupt index can ask for.
+    pub(super) fn read(&mut self, offset: u64, len: usize) -> Result<&[u8]> {
+        let read_end = offset
+            .checked_add(len as u64)
+            .ok_or(PulseError::Truncated { offset })?;
+        let buffered = offset >= self.start && read_end <= self.start + self.len as u64;
+        if !buffered {
+            // Grow the buffer if a single request is larger than it
+            if len > self.buffer.len() {
+                self.buffer.resize(len, 0);
+            }
+            let fill_len = (self.end.saturating_sub(offset) as usize).clamp(len, self.buffer.len());
+            self.source
+                .read_exact_at(&mut self.buffer[..fill_len], offset)?;
+            self.start = offset;
+            self.len = fill_len;
+        }
+        let begin = (offset - self.start) as usize;
+        Ok(&self.buffer[begin..begin + len])
+    }
+}
+
+/// An iterator over the raw records of every aperture, in on-disk order
+///
+/// Created by `PulseReader::iter_raw_apertures`. Each item is the raw records and header
+/// of a single aperture.
+pub struct RawApertureIter<'a> {
+    byte_locs: std::vec::IntoIter<u64>,
+    buffer: ReadBuffer<'a>,
+}
+
+impl Iterator for RawApertureIter<'_> {
+    type Item = Result<(Vec<RawRecord>, ApertureHeader)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let byte_loc = self.byte_locs.next()?;
+        Some(self.read_aperture(byte_loc))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.byte_locs.size_hint()
+    }
+}
+
+impl RawApertureIter<'_> {
+    fn read_aperture(&mut self, byte_loc: u64) -> Result<(Vec<RawRecord>, ApertureHeader)> {
+        let header_buffer: &[u8; READ_HEADER_SIZE] =
+            self.buffer.read(byte_loc, READ_HEADER_SIZE)?.try_into()?;
+        let aperture_header = ApertureHeader::new(header_buffer, byte_loc)?;
+
+        // The header was read, so its end can't overflow
+        let records_buffer = self.buffer.read(
+            byte_loc + READ_HEADER_SIZE as u64,
+            aperture_header.num_pulses as usize * PULSE_SIZE,
+        )?;
+        // chunks_exact guarantees that every chunk is exactly PULSE_SIZE bytes long
+        let raw_records = records_buffer
+            .chunks_exact(PULSE_SIZE)
+            .map(|buffer| RawRecord::from_bytes(buffer.try_into().unwrap()))
+            .collect();
+        Ok((raw_records, aperture_header))
+    }
+}
+
+/// An iterator over the formatted records of a single aperture
+///
+/// Created by `PulseReader::iter_aperture_records`. Records are read from disk in chunks
+/// of a bounded number of records, so memory use does not depend on the size of the
+/// aperture. The iterator stops after the first read error.
+pub struct RecordIter<'a> {
+    backend: &'a ReadBackend,
+    encoding: &'a RecordEncoding,
+    offset: u64,
+    remaining: usize,
+    index: usize,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl RecordIter<'_> {
+    /// Sets the maximum number of records read from disk at once
+    ///
+    /// Defaults to as many records as fit in the reader's 1MB buffer. Must be called
+    /// before iteration begins.
+    pub fn with_chunk_size(mut self, num_records: usize) -> Self {
+        self.chunk_size = num_records.max(1);
+        self
+    }
+
+    /// Reads the next chunk of records into the buffer
+    fn fill(&mut self) -> Result<()> {
+        let chunk_len = self.remaining.min(self.chunk_size) * PULSE_SIZE;
+        self.buffer.resize(chunk_len, 0);
+        self.backend.read_exact_at(&mut self.buffer, self.offset)?;
+        self.offset += chunk_len as u64;
+        self.remaining -= chunk_len / PULSE_SIZE;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl Iterator for RecordIter<'_> {
+    type Item = Result<FormattedRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position == self.buffer.len() {
+            if self.remaining == 0 {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                // Don't attempt to read the rest of the aperture after an error
+                self.remaining = 0;
+                self.buffer.clear();
+                self.position = 0;
+                return Some(Err(e));
+            }
+        }
+        // The buffer always holds a whole number of records
+        let buffer = &self.buffer[self.position..self.position + PULSE_SIZE];
+        let raw_record = RawRecord::from_bytes(buffer.try_into().unwrap());
+        let record =
+            FormattedRecord::from_raw_with_encoding(&raw_record, self.encoding, self.index);
+        self.position += PULSE_SIZE;
+        self.index += 1;
+        Some(Ok(record))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.remaining + (self.buffer.len() - self.position) / PULSE_SIZE;
+        (len, Some(len))
+    }
+}
+
+impl PulseReader {
+    /// Iterate over the header and raw records of every aperture, in on-disk order
+    ///
+    /// Unlike repeated calls to `get_raw_records`, which visit apertures in index order,
+    /// this walks the data section from start to end and serves reads from a single large
+    /// buffer, so a full pass over the file reads it sequentially.
+    pub fn iter_raw_apertures(&self) -> Result<RawApertureIter<'_>> {
+        let mut byte_locs = self
+            .index
+            .apertures
+            .iter()
+            .map(|ap| self.index.get(*ap))
+            .collect::<Result<Vec<u64>, PulseError>>()?;
+        byte_locs.sort_unstable();
+        Ok(RawApertureIter {
+            byte_locs: byte_locs.into_iter(),
+            buffer: ReadBuffer::new(&self.backend, self.header.index_offset),
+        })
+    }
+
+    /// Iterate over the header and formatted records of every aperture, in on-disk order
+    ///
+    /// Yields the same records and header as `get_all_records` for every aperture in the
+    /// file, but reads the file sequentially. See `iter_raw_apertures`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let mut num_records = 0;
+    /// for item in pulse_reader.iter_apertures().unwrap() {
+    ///     let (records, aperture_header) = item.unwrap();
+    ///     assert!(aperture_header.num_pulses as usize == records.len());
+    ///     num_records += records.len();
+    /// }
+    /// ```
+    pub fn iter_apertures(
+        &self,
+    ) -> Result<impl Iterator<Item = Result<(Vec<FormattedRecord>, ApertureHeader)>> + '_> {
+        Ok(self.iter_raw_apertures()?.map(|item| {
+            let (raw_records, aperture_header) = item?;
+            let records = raw_records
+                .iter()
+                .enumerate()
+                .map(|(idx, raw_record)| {
+                    FormattedRecord::from_raw_with_encoding(raw_record, &self.encoding, idx)
+                })
+                .collect();
+            Ok((records, aperture_header))
+        }))
+    }
+
+    /// Iterate over the header and normalized pulses of every aperture, in on-disk order
+    ///
+    /// Yields the same pulses and header as `get_pulses` for every aperture in the file,
+    /// applying the pulse filter if one is provided, but reads the file sequentially.
+    /// See `iter_raw_apertures`.
+    pub fn iter_pulses<'a>(
+        &'a self,
+        pulse_filter: Option<&'a PulseFilter>,
+    ) -> Result<impl Iterator<Item = Result<(Vec<NormalizedPulse>, ApertureHeader)>> + 'a> {
+        Ok(self.iter_apertures()?.map(move |item| {
+            let (records, aperture_header) = item?;
+            let pulse_records = NormalizedPulse::from_formatted_records(&records, self.fps);
+            if let Some(filter) = pulse_filter {
+                Ok((
+                    filter.filter_pulses(&pulse_records, self.fps)?,
+                    aperture_header,
+                ))
+            } else {
+                Ok((pulse_records, aperture_header))
+            }
+        }))
+    }
+
+    /// Iterate over the formatted records of the given aperture index in bounded chunks
+    ///
+    /// Parses the aperture header and returns it along with a `RecordIter`, which yields
+    /// the same records as `get_all_records` while only holding a single chunk of records
+    /// in memory at a time. Combine with `PulseNormalizer` to build normalized pulses, or
+    /// use `iter_aperture_pulses`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let ap = pulse_reader.index.apertures[0];
+    /// let (records, aperture_header) = pulse_reader.iter_aperture_records(ap).unwrap();
+    ///
+    /// let mut num_records = 0;
+    /// for record in records.with_chunk_size(256) {
+    ///     let record = record.unwrap();
+    ///     assert!(record.index == num_records);
+    ///     num_records += 1;
+    /// }
+    /// assert!(aperture_header.num_pulses as usize == num_records);
+    /// ```
+    pub fn iter_aperture_records(
+        &self,
+        aperture: usize,
+    ) -> Result<(RecordIter<'_>, ApertureHeader)> {
+        let byte_loc = self.index.get(aperture)?;
+        let mut buffer = [0; READ_HEADER_SIZE];
+        self.backend.read_exact_at(&mut buffer, byte_loc)?;
+        let aperture_header = ApertureHeader::new(&buffer, byte_loc)?;
+
+        let records = RecordIter {
+            backend: &self.backend,
+            encoding: &self.encoding,
+            offset: byte_loc + READ_HEADER_SIZE as u64,
+            remaining: aperture_header.num_pulses as usize,
+            index: 0,
+            chunk_size: BUFFER_SIZE / PULSE_SIZE,
+            buffer: Vec::new(),
+            position: 0,
+        };
+        Ok((records, aperture_header))
+    }
+
+    /// Iterate over the normalized pulses of the given aperture index in bounded chunks
+    ///
+    /// Yields the same pulses as `get_pulses` without a pulse filter, using a
+    /// `PulseNormalizer` over `iter_aperture_records`.
+    pub fn iter_aperture_pulses(
+        &self,
+        aperture: usize,
+    ) -> Result<(
+        impl Iterator<Item = Result<NormalizedPulse>> + '_,
+        ApertureHeader,
+    )> {
+        let (records, aperture_header) = self.iter_aperture_records(aperture)?;
+        let mut normalizer = PulseNormalizer::new(self.fps);
+        let pulses = records.filter_map(move |record| match record {
+            Ok(record) => normalizer.push(&record).map(Ok),
+            Err(e) => Some(Err(e)),
+        });
+        Ok((pulses, aperture_header))
+    }
+}
@@ -0,0 +1,471 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::{MergeConflict, PulseError};
+use crate::pulse_reader::headers::{ApertureHeader, PulseRecordType};
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+/// How the inputs to a merge are arranged on the combined chip
+///
+/// Aperture positions are given as (row, column) pairs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum MergeLayout {
+    /// Stack the inputs vertically, in the order given
+    ///
+    /// Every input must have the same number of columns. Each aperture is moved down by
+    /// the rows of the inputs above it, and its well_id is offset by their `rows * cols`
+    /// apertures.
+    #[default]
+    Vertical,
+    /// Place each input at the given (row, col) offset on the combined chip
+    ///
+    /// Must contain one offset per input. Inputs may have different numbers of rows and
+    /// columns, e.g. to tile acquisitions side by side or in a grid, but may not overlap.
+    /// The well_id of each aperture is recomputed as `y * cols + x`, where `cols` is the
+    /// width of the combined chip.
+    Offsets(Vec<(u32, u32)>),
+}
+
+/// The region of the combined chip covered by a single input
+struct Placement {
+    row: u32,
+    col: u32,
+    rows: u32,
+    cols: u32,
+}
+
+impl Placement {
+    fn overlaps(&self, other: &Placement) -> bool {
+        self.row < other.row + other.rows
+            && other.row < self.row + self.rows
+            && self.col < other.col + other.cols
+            && other.col < self.col + self.cols
+    }
+}
+
+/// Combine multiple pulses.bin files into a single file, stacking them vertically
+///
+/// Equivalent to `merge_pulse_files_with_layout` with `MergeLayout::Vertical`.
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::{PulseReader, merge_pulse_files};
+/// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+/// # use std::path::PathBuf;
+/// # use tempfile::tempdir;
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path1 = path.join("../example_files/pulses.bin");
+/// # let pulse_file_path2 = path.join("../example_files/pulses.bin");
+/// # let temp_dir = tempdir().unwrap();
+/// # let new_pulse_file_path = temp_dir.path().join("merged_pulses.bin");
+/// let pulse_readers = vec![
+///     PulseReader::open(pulse_file_path1).unwrap(),
+///     PulseReader::open(pulse_file_path2).unwrap(),
+/// ];
+/// merge_pulse_files(&pulse_readers, &new_pulse_file_path, OverwritePolicy::Fail).unwrap();
+/// ```
+pub fn merge_pulse_files<P: AsRef<Path>>(
+    pulse_files: &[PulseReader],
+    new_file_name: P,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    merge_pulse_files_with_layout(
+        pulse_files,
+        new_file_name,
+        &MergeLayout::Vertical,
+        overwrite,
+    )
+}
+
+/// Combine multiple pulses.bin files into a single file with all pulses from every file
+///
+/// Each input is placed on a combined chip according to `layout`, and every aperture is
+/// copied to the new file with its position and well_id updated to match. The metadata
+/// of the first input is used for the new file, with the dimensions of the combined chip,
+/// the bounding box of the non-empty regions of interest, and the sums of `validWells`,
+/// `validWellsLeft` and `validWellsRight`. With `MergeLayout::Vertical`, the region of
+/// interest spans every row of the combined chip.
+///
+/// The inputs are first checked with `check_merge_compatibility`, and nothing is written
+/// if they cannot be merged. An existing file is handled according to
+/// [`OverwritePolicy`].
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::merge::{MergeLayout, merge_pulse_files_with_layout};
+/// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+/// # use std::path::PathBuf;
+/// # use tempfile::tempdir;
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// # let temp_dir = tempdir().unwrap();
+/// # let new_pulse_file_path = temp_dir.path().join("merged_pulses.bin");
+/// let pulse_readers = vec![
+///     PulseReader::open(&pulse_file_path).unwrap(),
+///     PulseReader::open(&pulse_file_path).unwrap(),
+/// ];
+///
+/// // Place the second input to the right of the first
+/// let cols = pulse_readers[0].run_metadata.cols.unwrap();
+/// let layout = MergeLayout::Offsets(vec![(0, 0), (0, cols)]);
+/// merge_pulse_files_with_layout(&pulse_readers, &new_pulse_file_path, &layout, OverwritePolicy::Fail)
+///     .unwrap();
+///
+/// let merged_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+/// assert_eq!(merged_reader.run_metadata.cols, Some(2 * cols));
+/// ```
+pub fn merge_pulse_files_with_layout<P: AsRef<Path>>(
+    pulse_files: &[PulseReader],
+    new_file_name: P,
+    layout: &MergeLayout,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    if pulse_files.is_empty() {
+        return Err(PulseError::invalid_argument("No pulse files provided").into());
+    }
+    overwrite.check(&new_file_name)?;
+    check_merge_compatibility(pulse_files, layout)?;
+    let placements = place_inputs(pulse_files, layout)?;
+    let rows = placements.iter().map(|p| p.row + p.rows).max().unwrap_or(0);
+    let cols = placements.iter().map(|p| p.col + p.cols).max().unwrap_or(0);
+    // Every well_id is less than the number of apertures on the combined chip, so the
+    // positions and well_ids computed below can't overflow once this fits
+    if rows.checked_mul(cols).is_none() {
+        return Err(PulseError::invalid_argument(format!(
+            "The combined {}x{} chip has too many apertures to number with a u32 well_id",
+            rows, cols
+        ))
+        .into());
+    }
+
+    // Collect data needed for new metadata
+    let mut valid_wells: u64 = 0;
+    let mut valid_wells_left: u64 = 0;
+    let mut valid_wells_right: u64 = 0;
+    let mut roi: Option<(u32, u32, u32, u32)> = None;
+    for (pulse_file, placement) in pulse_files.iter().zip(placements.iter()) {
+        let run_metadata = &pulse_file.run_metadata;
+        valid_wells += required(run_metadata.valid_wells, "validWells")?;
+        valid_wells_left += required(run_metadata.valid_wells_left, "validWellsLeft")?;
+        valid_wells_right += required(run_metadata.valid_wells_right, "validWellsRight")?;
+
+        // The region of interest of each input, on the combined chip. Empty regions
+        // don't add to the union. Inputs stacked vertically cover every row of the
+        // combined chip, so only their columns are taken from the metadata.
+        let run_roi_offset_col = required(run_metadata.roi_offset_col, "roi_offset_col")?;
+        let run_roi_cols = required(run_metadata.roi_cols, "roi_cols")?;
+        let (run_roi_offset_row, run_roi_rows) = match layout {
+            MergeLayout::Vertical => (0, placement.rows),
+            MergeLayout::Offsets(_) => (
+                required(run_metadata.roi_offset_row, "roi_offset_row")?,
+                required(run_metadata.roi_rows, "roi_rows")?,
+            ),
+        };
+        if run_roi_cols == 0 || run_roi_rows == 0 {
+            continue;
+        }
+        let first_col = run_roi_offset_col
+            .checked_add(placement.col)
+            .ok_or_else(|| PulseError::metadata_field("roi_offset_col"))?;
+        let last_col = first_col
+            .checked_add(run_roi_cols)
+            .ok_or_else(|| PulseError::metadata_field("roi_cols"))?;
+        let first_row = run_roi_offset_row
+            .checked_add(placement.row)
+            .ok_or_else(|| PulseError::metadata_field("roi_offset_row"))?;
+        let last_row = first_row
+            .checked_add(run_roi_rows)
+            .ok_or_else(|| PulseError::metadata_field("roi_rows"))?;
+        roi = Some(match roi {
+            None => (first_col, last_col, first_row, last_row),
+            Some((col, end_col, row, end_row)) => (
+                col.min(first_col),
+                end_col.max(last_col),
+                row.min(first_row),
+                end_row.max(last_row),
+            ),
+        });
+    }
+    let (roi_offset_col, last_col, roi_offset_row, last_row) = roi.unwrap_or_default();
+
+    // Update metadata
+    // Edit the original JSON, rather than serializing `RunMetadata`, so that the
+    // representation of the other fields is preserved
+    let mut new_metadata: Value = pulse_files[0].metadata.clone();
+    new_metadata["rows"] = Value::from(rows);
+    new_metadata["cols"] = Value::from(cols);
+    new_metadata["validWells"] = Value::from(valid_wells);
+    new_metadata["validWellsLeft"] = Value::from(valid_wells_left);
+    new_metadata["validWellsRight"] = Value::from(valid_wells_right);
+    new_metadata["roi_cols"] = Value::from(last_col - roi_offset_col);
+    new_metadata["roi_rows"] = Value::from(last_row - roi_offset_row);
+    new_metadata["roi_offset_col"] = Value::from(roi_offset_col);
+    new_metadata["roi_offset_row"] = Value::from(roi_offset_row);
+
+    // Copy every aperture to the new file, updating its position and index as we go.
+    // The writer sorts the index, so apertures can be written in any order.
+    let mut writer = PulseFileWriter::create(
+        new_file_name,
+        &new_metadata,
+        &pulse_files[0].record_types,
+        overwrite,
+    )?;
+    let mut well_id_offset: u32 = 0;
+    for (input, (pulse_file, placement)) in pulse_files.iter().zip(placements.iter()).enumerate() {
+        for ap in pulse_file.index.apertures.iter() {
+            let aperture_header = pulse_file.get_aperture_header(*ap)?;
+            let new_aperture_header = match layout {
+                // Stacked inputs keep their well_ids, offset by the apertures of the inputs
+                // above them
+                MergeLayout::Vertical => {
+                    let (Some(y), Some(well_id)) = (
+                        aperture_header.y.checked_add(placement.row),
+                        aperture_header.well_id.checked_add(well_id_offset),
+                    ) else {
+                        return Err(PulseError::invalid_argument(format!(
+                            "Aperture {} of input {} can't be moved down by {} rows",
+                            ap, input, placement.row
+                        ))
+                        .into());
+                    };
+                    ApertureHeader {
+                        y,
+                        well_id,
+                        ..aperture_header
+                    }
+                }
+                MergeLayout::Offsets(_) => {
+                    if aperture_header.x >= placement.cols || aperture_header.y >= placement.rows {
+                        return Err(anyhow!(
+                            "Aperture {} of input {} at ({}, {}) lies outside of its {}x{} chip",
+                            ap,
+                            input,
+                            aperture_header.y,
+                            aperture_header.x,
+                            placement.rows,
+                            placement.cols
+                        ));
+                    }
+                    // Bounded by the size of the combined chip, checked above
+                    let x = aperture_header.x + placement.col;
+                    let y = aperture_header.y + placement.row;
+                    ApertureHeader {
+                        x,
+                        y,
+                        well_id: y * cols + x,
+                        ..aperture_header
+                    }
+                }
+            };
+            writer.copy_aperture(pulse_file, &new_aperture_header)?;
+        }
+        // The inputs above this one fit on the combined chip, checked above
+        well_id_offset += placement.rows * placement.cols;
+    }
+    writer.finish()
+}
+
+/// Finds the region of the combined chip covered by each input
+fn place_inputs(pulse_files: &[PulseReader], layout: &MergeLayout) -> Result<Vec<Placement>> {
+    if let MergeLayout::Offsets(offsets) = layout
+        && offsets.len() != pulse_files.len()
+    {
+        return Err(PulseError::invalid_argument(format!(
+            "Merge layout has {} offsets, but {} files were provided",
+            offsets.len(),
+            pulse_files.len()
+        ))
+        .into());
+    }
+
+    let mut placements: Vec<Placement> = Vec::with_capacity(pulse_files.len());
+    for (input, pulse_file) in pulse_files.iter().enumerate() {
+        let (row, col) = match layout {
+            MergeLayout::Vertical => (placements.last().map_or(0, |p| p.row + p.rows), 0),
+            MergeLayout::Offsets(offsets) => offsets[input],
+        };
+        let rows = required(pulse_file.run_metadata.rows, "rows")?;
+        let cols = required(pulse_file.run_metadata.cols, "cols")?;
+        if row.checked_add(rows).is_none() || col.checked_add(cols).is_none() {
+            return Err(PulseError::invalid_argument(format!(
+                "Input {} at offset ({}, {}) extends past the largest chip that can be addressed",
+                input, row, col
+            ))
+            .into());
+        }
+        placements.push(Placement {
+            row,
+            col,
+            rows,
+            cols,
+        });
+    }
+
+    for (i, placement) in placements.iter().enumerate() {
+        if let Some(j) = placements[i + 1..]
+            .iter()
+            .position(|other| placement.overlaps(other))
+        {
+            return Err(PulseError::invalid_argument(format!(
+                "Inputs {} and {} overlap in the merge layout",
+                i,
+                i + 1 + j
+            ))
+            .into());
+        }
+    }
+    Ok(placements)
+}
+
+/// Shorthand for a metadata field that is required to merge files
+pub(super) fn required<T>(value: Option<T>, name: &str) -> Result<T, PulseError> {
+    value.ok_or_else(|| PulseError::metadata_field(name))
+}
+
+/// Checks that the given pulse files can be merged into a single file
+///
+/// Every input must have the same format version, encoding records and frame rate as
+/// the first input, and must contain every metadata field needed to build the merged
+/// metadata. With `MergeLayout::Vertical`, every input must also have the same number
+/// of columns, but `roi_offset_row` and `roi_rows` are not needed, since the region of
+/// interest then spans every row. Returns `PulseError::IncompatibleInputs` listing every
+/// input and property that prevents the merge.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::merge::{MergeLayout, check_merge_compatibility};
+/// # use std::path::PathBuf;
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_readers = vec![
+///     PulseReader::open(&pulse_file_path).unwrap(),
+///     PulseReader::open(&pulse_file_path).unwrap(),
+/// ];
+/// assert!(check_merge_compatibility(&pulse_readers, &MergeLayout::Vertical).is_ok());
+/// ```
+pub fn check_merge_compatibility(
+    pulse_files: &[PulseReader],
+    layout: &MergeLayout,
+) -> Result<(), PulseError> {
+    check_inputs(
+        pulse_files,
+        |pulse_file| matched_properties(pulse_file, layout),
+        |pulse_file| {
+            // Properties that are combined into the merged metadata. The number of
+            // columns is only combined when it isn't required to match, and the rows of
+            // the region of interest only when the inputs aren't stacked vertically.
+            let run_metadata = &pulse_file.run_metadata;
+            let mut combined = vec![
+                ("rows", run_metadata.rows.is_some()),
+                ("roi_offset_col", run_metadata.roi_offset_col.is_some()),
+                ("roi_cols", run_metadata.roi_cols.is_some()),
+                ("validWells", run_metadata.valid_wells.is_some()),
+                ("validWellsLeft", run_metadata.valid_wells_left.is_some()),
+                ("validWellsRight", run_metadata.valid_wells_right.is_some()),
+            ];
+            if *layout != MergeLayout::Vertical {
+                combined.push(("cols", run_metadata.cols.is_some()));
+                combined.push(("roi_offset_row", run_metadata.roi_offset_row.is_some()));
+                combined.push(("roi_rows", run_metadata.roi_rows.is_some()));
+            }
+            combined
+        },
+    )
+}
+
+/// Checks the inputs to an operation that combines several pulse files
+///
+/// Every input must have the same `matched` properties as the first input, and every
+/// `required` property. Returns `PulseError::IncompatibleInputs` listing every conflict.
+pub(super) fn check_inputs<M, R>(
+    pulse_files: &[PulseReader],
+    matched: M,
+    required: R,
+) -> Result<(), PulseError>
+where
+    M: Fn(&PulseReader) -> Vec<(&'static str, Option<String>)>,
+    R: Fn(&PulseReader) -> Vec<(&'static str, bool)>,
+{
+    let Some(first) = pulse_files.first() else {
+        return Ok(());
+    };
+    let expected = matched(first);
+
+    let mut conflicts = Vec::new();
+    for (input, pulse_file) in pulse_files.iter().enumerate() {
+        let mut conflict = |property, expected, found| {
+            conflicts.push(MergeConflict {
+                input,
+                file_name: pulse_file.file_name.clone(),
+                property,
+                expected,
+                found,
+            })
+        };
+        for ((property, expected), (_, found)) in expected.iter().zip(matched(pulse_file)) {
+            match (expected, found) {
+                (_, None) => conflict(property, None, None),
+                (Some(expected), Some(found)) if *expected != found => {
+                    conflict(property, Some(expected.clone()), Some(found))
+                }
+                _ => {}
+            }
+        }
+        for (property, present) in required(pulse_file) {
+            if !present {
+                conflict(property, None, None);
+            }
+        }
+    }
+
+    if conflicts.is_empty() {
+        Ok(())
+    } else {
+        Err(PulseError::IncompatibleInputs { conflicts })
+    }
+}
+
+/// The properties of a pulse file that must be the same in every input to a merge
+///
+/// Each property is formatted as a string, or None if it is missing.
+pub(super) fn matched_properties(
+    pulse_file: &PulseReader,
+    layout: &MergeLayout,
+) -> Vec<(&'static str, Option<String>)> {
+    let mut properties = record_properties(pulse_file);
+    if *layout == MergeLayout::Vertical {
+        let cols = pulse_file.run_metadata.cols;
+        properties.push(("cols", cols.map(|cols| cols.to_string())));
+    }
+    properties
+}
+
+/// The properties of a pulse file that must be the same to copy its records into another
+///
+/// Each property is formatted as a string, or None if it is missing.
+pub(super) fn record_properties(pulse_file: &PulseReader) -> Vec<(&'static str, Option<String>)> {
+    vec![
+        ("version", Some(pulse_file.header.version.to_string())),
+        (
+            "encoding records",
+            Some(format_record_types(&pulse_file.record_types)),
+        ),
+        (
+            "fps",
+            pulse_file.run_metadata.fps.map(|fps| fps.to_string()),
+        ),
+    ]
+}
+
+/// Formats an encoding table as a list of `type:bits:offset`
+fn format_record_types(record_types: &[PulseRecordType]) -> String {
+    let record_types: Vec<String> = record_types
+        .iter()
+        .map(|rt| format!("{}:{}:{}", rt.record_type, rt.bits, rt.offset))
+        .collect();
+    format!("[{}]", record_types.join(", "))
+}
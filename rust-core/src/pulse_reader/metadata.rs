@@ -0,0 +1,287 @@
+use crate::pulse_reader::error::PulseError;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// The run metadata stored as JSON in a pulses.bin file
+///
+/// Every field is optional, since the metadata written by the pulse caller has
+/// changed over time, and any keys that are not described here are kept in `extra`.
+/// A field with the wrong type, e.g. a negative `roi_offset_col`, is read as missing, so
+/// that only the operations that need it fail. Serializing a `RunMetadata` reproduces the
+/// original JSON object, except that the order of its keys may differ, fields and pulse
+/// caller options with the wrong type are dropped, and `fps` and `duration` are always
+/// written as floats.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+///
+/// let run_metadata = &pulse_reader.run_metadata;
+/// assert_eq!(run_metadata.rows, Some(1024));
+/// assert_eq!(run_metadata.cols, Some(2048));
+/// assert!(!run_metadata.trimmed());
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RunMetadata {
+    /// Frame rate of the acquisition, in frames per second
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fps: Option<f64>,
+    /// Duration of the acquisition, in seconds
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub duration: Option<f64>,
+    /// Number of rows of apertures covered by the file
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rows: Option<u32>,
+    /// Number of columns of apertures on the chip, used to compute well IDs
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cols: Option<u32>,
+    /// Number of rows in the region of interest
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub roi_rows: Option<u32>,
+    /// Number of columns in the region of interest
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub roi_cols: Option<u32>,
+    /// First row of the region of interest on the chip
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub roi_offset_row: Option<u32>,
+    /// First column of the region of interest on the chip
+    #[serde(
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub roi_offset_col: Option<u32>,
+    /// Number of apertures with valid signal
+    ///
+    /// This and the counts for each half of the chip are treated as additive: merging
+    /// files sums them, and a subset or shard of a file gets a share in proportion to the
+    /// apertures it keeps, with the halves split as for `ChipHalf`.
+    #[serde(
+        rename = "validWells",
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub valid_wells: Option<u64>,
+    /// Number of apertures with valid signal in the left half of the chip
+    #[serde(
+        rename = "validWellsLeft",
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub valid_wells_left: Option<u64>,
+    /// Number of apertures with valid signal in the right half of the chip
+    #[serde(
+        rename = "validWellsRight",
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub valid_wells_right: Option<u64>,
+    /// Configuration of the pulse caller that produced the file
+    #[serde(
+        rename = "pulseCaller",
+        default,
+        deserialize_with = "lenient",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pulse_caller: Option<PulseCallerConfig>,
+    /// Any other metadata keys
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The configuration of the pulse caller that produced a pulses.bin file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PulseCallerConfig {
+    /// Options the pulse caller was run with, e.g. `"trim_boundary_frames"`
+    ///
+    /// Options that are not strings are skipped, without affecting the others.
+    #[serde(default, deserialize_with = "lenient_strings")]
+    pub options: Vec<String>,
+    /// Any other pulse caller keys
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Deserializes a field, falling back to its default if it has the wrong type
+fn lenient<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + Default,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(T::deserialize(value).unwrap_or_default())
+}
+
+/// Deserializes a list of strings, skipping any elements that are not strings
+fn lenient_strings<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    Ok(value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+impl RunMetadata {
+    /// Parses the run metadata from its JSON representation
+    ///
+    /// Returns an error only if the metadata is not a JSON object.
+    pub fn from_value(metadata: &Value) -> Result<Self> {
+        RunMetadata::deserialize(metadata).map_err(|e| {
+            PulseError::InvalidMetadata {
+                reason: e.to_string(),
+            }
+            .into()
+        })
+    }
+
+    /// Whether the trimmed pulse caller was used
+    ///
+    /// Trimmed files drop the partial pulses at the start and end of the acquisition.
+    pub fn trimmed(&self) -> bool {
+        self.pulse_caller
+            .as_ref()
+            .is_some_and(|pulse_caller| pulse_caller.has_option("trim_boundary_frames"))
+    }
+
+    /// The well ID of the aperture at column `x` and row `y` of the chip
+    ///
+    /// Well IDs number the apertures of the chip in row-major order, `y * cols + x`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// let run_metadata = &pulse_reader.run_metadata;
+    /// assert_eq!(run_metadata.well_id(547, 408).unwrap(), 836131);
+    /// assert_eq!(run_metadata.well_position(836131).unwrap(), (547, 408));
+    /// ```
+    pub fn well_id(&self, x: u32, y: u32) -> Result<u32> {
+        let cols = self
+            .cols
+            .ok_or_else(|| PulseError::metadata_field("cols"))?;
+        if x >= cols {
+            return Err(PulseError::invalid_argument(format!(
+                "Column {} is outside a chip of {} columns",
+                x, cols
+            ))
+            .into());
+        }
+        u32::try_from(y as u64 * cols as u64 + x as u64).map_err(|_| {
+            PulseError::invalid_argument(format!("Row {} is too large to have a well ID", y)).into()
+        })
+    }
+
+    /// The (x, y) position on the chip of the aperture with the given well ID
+    pub fn well_position(&self, well_id: u32) -> Result<(u32, u32)> {
+        match self.cols {
+            Some(0) | None => Err(PulseError::metadata_field("cols").into()),
+            Some(cols) => Ok((well_id % cols, well_id / cols)),
+        }
+    }
+
+    /// Converts a position on the chip to a position in the region of interest
+    ///
+    /// The region of interest is given by `roi_offset_col`, `roi_offset_row`, `roi_cols`
+    /// and `roi_rows`. Returns `None` if the position is outside of it.
+    pub fn roi_position(&self, x: u32, y: u32) -> Result<Option<(u32, u32)>> {
+        let (offset_col, offset_row, roi_cols, roi_rows) = self.roi()?;
+        let roi_x = x.checked_sub(offset_col).filter(|roi_x| *roi_x < roi_cols);
+        let roi_y = y.checked_sub(offset_row).filter(|roi_y| *roi_y < roi_rows);
+        Ok(roi_x.zip(roi_y))
+    }
+
+    /// Converts a position in the region of interest to a position on the chip
+    ///
+    /// This is the inverse of `roi_position`. Returns `PulseError::MetadataField` if the
+    /// position on the chip doesn't fit in a u32.
+    pub fn chip_position(&self, roi_x: u32, roi_y: u32) -> Result<(u32, u32)> {
+        let (offset_col, offset_row, roi_cols, roi_rows) = self.roi()?;
+        if roi_x >= roi_cols || roi_y >= roi_rows {
+            return Err(PulseError::invalid_argument(format!(
+                "Position ({}, {}) is outside a region of interest of {} columns and {} rows",
+                roi_x, roi_y, roi_cols, roi_rows
+            ))
+            .into());
+        }
+        // The region of interest must fit on a chip with u32 positions
+        let x = offset_col
+            .checked_add(roi_x)
+            .ok_or_else(|| PulseError::metadata_field("roi_offset_col"))?;
+        let y = offset_row
+            .checked_add(roi_y)
+            .ok_or_else(|| PulseError::metadata_field("roi_offset_row"))?;
+        Ok((x, y))
+    }
+
+    /// The offset column, offset row, number of columns and number of rows of the
+    /// region of interest
+    pub(super) fn roi(&self) -> Result<(u32, u32, u32, u32), PulseError> {
+        let field =
+            |value: Option<u32>, name| value.ok_or_else(|| PulseError::metadata_field(name));
+        Ok((
+            field(self.roi_offset_col, "roi_offset_col")?,
+            field(self.roi_offset_row, "roi_offset_row")?,
+            field(self.roi_cols, "roi_cols")?,
+            field(self.roi_rows, "roi_rows")?,
+        ))
+    }
+}
+
+impl PulseCallerConfig {
+    /// Whether the pulse caller was run with the given option
+    pub fn has_option(&self, option: &str) -> bool {
+        self.options.iter().any(|value| value == option)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::*;
+use crate::pulse_reader::records::*;
+use crate::pulse_reader::{FileSections, PulseReader, ReadBackend};
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::Result;
+use memmap2::Mmap;
+
+impl PulseReader {
+    /// Attempts to open a pulses.bin file for reading through a memory map
+    ///
+    /// Maps pulses.bin into memory and reads headers and aperture byte location index.
+    /// The returned reader provides the same functionality as one opened with `open`, but
+    /// reads records from the mapped file instead of with a separate system call for each
+    /// aperture, and `aperture` borrows records directly from the map. The file must not be
+    /// modified or truncated by another process while it is mapped.
+    ///
+    /// # Examples
+    /// ```
+    /// use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open_mmap(pulse_file_path).unwrap();
+    /// ```
+    pub fn open_mmap<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        let file = File::open(file_name.as_ref()).map_err(PulseError::Io)?;
+        // SAFETY: The mapping is read-only, and pulses.bin files are not modified after they
+        // have been written. Modifying the file while it is mapped is undefined behavior, which
+        // is documented above.
+        let mmap = unsafe { Mmap::map(&file).map_err(PulseError::Io)? };
+        let sections = FileSections::read(&mut Cursor::new(&mmap[..]))?;
+        Ok(Self::from_sections(
+            file_name.as_ref(),
+            ReadBackend::Mmap(mmap),
+            sections,
+        ))
+    }
+
+    /// Get a view over the records of the given aperture index
+    ///
+    /// Parses the aperture header and returns an `ApertureRecords` view over the encoded
+    /// records. If the file was opened with `open_mmap`, the records are borrowed directly
+    /// from the mapped file, and otherwise they are read into a single buffer. No records
+    /// are decoded until they are accessed.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let pulse_reader = PulseReader::open_mmap(pulse_file_path).unwrap();
+    /// let ap = pulse_reader.index.apertures[0];
+    ///
+    /// let aperture = pulse_reader.aperture(ap).unwrap();
+    /// let num_pulses = aperture.iter().filter(|record| record.duration > 0).count();
+    ///
+    /// assert!(aperture.header.num_pulses as usize == aperture.len());
+    /// assert!(num_pulses <= aperture.len());
+    /// ```
+    pub fn aperture(&self, aperture: usize) -> Result<ApertureRecords<'_>> {
+        let header = self.get_aperture_header(aperture)?;
+        let records_offset =
+            header
+                .byte_loc
+                .checked_add(READ_HEADER_SIZE as u64)
+                .ok_or(PulseError::Truncated {
+                    offset: header.byte_loc,
+                })?;
+        let data = self
+            .backend
+            .bytes_at(records_offset, header.num_pulses as usize * PULSE_SIZE)?;
+
+        Ok(ApertureRecords {
+            header,
+            data,
+            encoding: &self.encoding,
+        })
+    }
+}
+
+/// A view over the records of a single aperture
+///
+/// Records are stored in their on-disk encoding and are decoded lazily, either
+/// individually with `raw`/`get`, or in order with `iter_raw`/`iter`.
+pub struct ApertureRecords<'a> {
+    pub header: ApertureHeader,
+    data: Cow<'a, [u8]>,
+    encoding: &'a RecordEncoding,
+}
+
+impl ApertureRecords<'_> {
+    /// The number of records in the aperture
+    pub fn len(&self) -> usize {
+        self.data.len() / PULSE_SIZE
+    }
+
+    /// Whether the aperture contains no records
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The raw bytes of the aperture's records, as stored on disk
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Decode the raw record at the given position, if present
+    pub fn raw(&self, idx: usize) -> Option<RawRecord> {
+        self.data
+            .get((idx * PULSE_SIZE)..((idx + 1) * PULSE_SIZE))
+            .and_then(|buffer| buffer.try_into().ok())
+            .map(RawRecord::from_bytes)
+    }
+
+    /// Decode and format the record at the given position, if present
+    pub fn get(&self, idx: usize) -> Option<FormattedRecord> {
+        self.raw(idx).map(|raw_record| {
+            FormattedRecord::from_raw_with_encoding(&raw_record, self.encoding, idx)
+        })
+    }
+
+    /// Iterate over the raw records of the aperture
+    pub fn iter_raw(&self) -> impl Iterator<Item = RawRecord> + '_ {
+        // chunks_exact guarantees that every chunk is exactly PULSE_SIZE bytes long
+        self.data
+            .chunks_exact(PULSE_SIZE)
+            .map(|buffer| RawRecord::from_bytes(buffer.try_into().unwrap()))
+    }
+
+    /// Iterate over the formatted records of the aperture
+    pub fn iter(&self) -> impl Iterator<Item = FormattedRecord> + '_ {
+        let encoding = self.encoding;
+        self.iter_raw().enumerate().map(move |(idx, raw_record)| {
+            FormattedRecord::from_raw_with_encoding(&raw_record, encoding, idx)
+        })
+    }
+}
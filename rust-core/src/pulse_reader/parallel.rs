@@ -0,0 +1,93 @@
+use crate::pulse_filter::PulseFilter;
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::headers::ApertureHeader;
+use crate::pulse_reader::records::NormalizedPulse;
+
+use anyhow::Result;
+use rayon::prelude::*;
+
+impl PulseReader {
+    /// Apply a function to the pulses of every aperture in parallel
+    ///
+    /// Decodes the normalized pulses of every aperture in the index on the rayon thread pool,
+    /// optionally applying a pulse filter, and passes them to `f` along with the aperture
+    /// header. The results are returned in the same order as `index.apertures`, regardless of
+    /// the order in which apertures were processed. If reading any aperture fails, an error
+    /// is returned.
+    ///
+    /// This method is only available with the `rayon` feature enabled.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// // Count the pulses in every aperture
+    /// let pulse_counts = pulse_reader
+    ///     .par_map_apertures(None, |pulses, _aperture_header| pulses.len())
+    ///     .unwrap();
+    ///
+    /// assert_eq!(pulse_counts.len(), pulse_reader.index.apertures.len());
+    /// ```
+    pub fn par_map_apertures<T, F>(
+        &self,
+        pulse_filter: Option<&PulseFilter>,
+        f: F,
+    ) -> Result<Vec<T>>
+    where
+        T: Send,
+        F: Fn(Vec<NormalizedPulse>, ApertureHeader) -> T + Sync + Send,
+    {
+        self.index
+            .apertures
+            .par_iter()
+            .map(|ap| {
+                let (pulses, aperture_header) = self.get_pulses(*ap, pulse_filter)?;
+                Ok(f(pulses, aperture_header))
+            })
+            .collect()
+    }
+
+    /// Apply a function to the pulses of every aperture in parallel, then combine the results
+    ///
+    /// Maps every aperture in parallel with `par_map_apertures`, then folds the per-aperture
+    /// results into `identity` with `reduce`, in the order of `index.apertures`. Because the
+    /// fold is always performed in file order, the result is deterministic even when `reduce`
+    /// is not associative, as with floating point sums.
+    ///
+    /// This method is only available with the `rayon` feature enabled.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// // Count the pulses in the whole file
+    /// let total_pulses = pulse_reader
+    ///     .par_reduce_apertures(None, 0, |pulses, _aperture_header| pulses.len(), |a, b| a + b)
+    ///     .unwrap();
+    /// ```
+    pub fn par_reduce_apertures<T, F, R>(
+        &self,
+        pulse_filter: Option<&PulseFilter>,
+        identity: T,
+        f: F,
+        reduce: R,
+    ) -> Result<T>
+    where
+        T: Send,
+        F: Fn(Vec<NormalizedPulse>, ApertureHeader) -> T + Sync + Send,
+        R: Fn(T, T) -> T,
+    {
+        Ok(self
+            .par_map_apertures(pulse_filter, f)?
+            .into_iter()
+            .fold(identity, reduce))
+    }
+}
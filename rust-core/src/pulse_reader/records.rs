@@ -1,12 +1,14 @@
 use crate::pulse_reader::constants::*;
-use crate::pulse_reader::headers::PulseRecordType;
+use crate::pulse_reader::headers::{PulseRecordType, RecordEncoding};
 use anyhow::{Result, anyhow};
+use std::fmt;
+use std::io::Write;
 
 /// A single raw record
 ///
 /// This struct represents the raw integer-encoded data corresponding to
 /// a single record in pulses.bin.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RawRecord {
     pub frames_since_last: u16,
     pub duration: u16,
@@ -21,55 +23,212 @@ pub struct RawRecord {
 impl RawRecord {
     /// Creates a new raw pulse record from a buffer of exactly 16 bytes
     pub fn new(buffer: &[u8]) -> Result<Self> {
-        if buffer.len() != 16 {
-            return Err(anyhow!(
-                "Buffer must be exactly 16 bytes, got {}",
-                buffer.len()
-            ));
+        let buffer: &[u8; PULSE_SIZE] = buffer
+            .try_into()
+            .map_err(|_| anyhow!("Buffer must be exactly 16 bytes, got {}", buffer.len()))?;
+        Ok(RawRecord::from_bytes(buffer))
+    }
+
+    /// Creates a new raw pulse record from a 16-byte array
+    ///
+    /// Unlike `new`, this cannot fail, since the length of the buffer is known at compile time.
+    pub fn from_bytes(buffer: &[u8; PULSE_SIZE]) -> Self {
+        RawRecord {
+            frames_since_last: u16::from_le_bytes([buffer[0], buffer[1]]),
+            duration: u16::from_le_bytes([buffer[2], buffer[3]]),
+            m0: i16::from_le_bytes([buffer[4], buffer[5]]),
+            m1: i16::from_le_bytes([buffer[6], buffer[7]]),
+            bk0: i16::from_le_bytes([buffer[8], buffer[9]]),
+            bk1: i16::from_le_bytes([buffer[10], buffer[11]]),
+            std0: i16::from_le_bytes([buffer[12], buffer[13]]),
+            std1: i16::from_le_bytes([buffer[14], buffer[15]]),
         }
+    }
 
-        Ok(RawRecord {
-            frames_since_last: u16::from_le_bytes(
-                buffer[0..2]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse frames_since_last from raw record"))?,
-            ),
-            duration: u16::from_le_bytes(
-                buffer[2..4]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse duration from raw record"))?,
-            ),
-            m0: i16::from_le_bytes(
-                buffer[4..6]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse m0 from raw record"))?,
-            ),
-            m1: i16::from_le_bytes(
-                buffer[6..8]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse m1 from raw record"))?,
-            ),
-            bk0: i16::from_le_bytes(
-                buffer[8..10]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse bk0 from raw record"))?,
-            ),
-            bk1: i16::from_le_bytes(
-                buffer[10..12]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse bk1 from raw record"))?,
-            ),
-            std0: i16::from_le_bytes(
-                buffer[12..14]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse std0 from raw record"))?,
-            ),
-            std1: i16::from_le_bytes(
-                buffer[14..16]
-                    .try_into()
-                    .map_err(|_| anyhow!("Failed to parse std1 from raw record"))?,
-            ),
-        })
+    /// Creates a padding record, which advances the current frame by 65535 frames
+    ///
+    /// Padding records are used to represent gaps between records that are too long to
+    /// fit in `frames_since_last`.
+    pub fn padding() -> Self {
+        RawRecord {
+            frames_since_last: PADDING_FRAMES_SINCE_LAST,
+            duration: 0,
+            m0: 0,
+            m1: 0,
+            bk0: 0,
+            bk1: 0,
+            std0: 0,
+            std1: 0,
+        }
+    }
+
+    /// Whether this is a padding record
+    pub fn is_padding(&self) -> bool {
+        self.duration == 0 && self.frames_since_last == PADDING_FRAMES_SINCE_LAST
+    }
+
+    /// Appends the padding records needed to advance the current frame by `gap` frames
+    ///
+    /// Returns the remaining number of frames, which is small enough to be stored in the
+    /// `frames_since_last` of the next record.
+    pub(crate) fn pad_gap(output: &mut Vec<RawRecord>, mut gap: u64) -> u16 {
+        while gap >= PADDING_FRAMES_SINCE_LAST as u64 {
+            output.push(RawRecord::padding());
+            gap -= PADDING_FRAMES_SINCE_LAST as u64;
+        }
+        gap as u16
+    }
+
+    /// The frame stored in bk0 and bk1 of a step record, or None for any other record
+    pub fn event_frame(&self) -> Option<u32> {
+        let is_step =
+            self.std0 == NON_PULSE_RECORD_STEP_UP || self.std0 == NON_PULSE_RECORD_STEP_DOWN;
+        if self.duration > 0 || self.is_padding() || !is_step {
+            return None;
+        }
+        Some(((self.bk1 as u32 & 0xffff) << 16) | (self.bk0 as u32 & 0xffff))
+    }
+
+    /// Stores the frame of a step record in bk0 and bk1
+    pub(crate) fn set_event_frame(&mut self, event_frame: u32) {
+        self.bk0 = (event_frame & 0xffff) as u16 as i16;
+        self.bk1 = (event_frame >> 16) as u16 as i16;
+    }
+
+    /// Convert a formatted record back into a raw record
+    ///
+    /// Equivalent to `from_formatted_with_encoding`, with the field map built from the encoding
+    /// table by looking up each field by its record type ID. Fields without an encoding in
+    /// the table are left unscaled, and the first encoding is used for fields with more
+    /// than one. The table is searched for every record, so build a `RecordEncoding` once
+    /// instead when converting many records.
+    #[deprecated(note = "use `from_formatted_with_encoding` with a `RecordEncoding`")]
+    pub fn from_formatted(record: &FormattedRecord, record_types: &[PulseRecordType]) -> Self {
+        let encoding = RecordEncoding::with_defaults(record_types);
+        Self::from_formatted_with_encoding(record, &encoding)
+    }
+
+    /// Convert a formatted record back into a raw record
+    ///
+    /// This is the inverse of `FormattedRecord::from_raw_with_encoding`. Fields that were
+    /// formatted using the record types are re-encoded with `PulseRecordType::encode_value`,
+    /// while fields that were passed through unformatted are converted back to integers
+    /// directly. For long pulse and step records, the frame count is packed back into bk0
+    /// and bk1.
+    pub fn from_formatted_with_encoding(
+        record: &FormattedRecord,
+        encoding: &RecordEncoding,
+    ) -> Self {
+        let raw = RawRecord {
+            frames_since_last: record.frames_since_last,
+            duration: record.duration,
+            m0: record.intensity0 as i16,
+            m1: record.intensity1 as i16,
+            bk0: record.bg0 as i16,
+            bk1: record.bg1 as i16,
+            std0: record.sd0 as i16,
+            std1: record.sd1 as i16,
+        };
+        match record.record_type {
+            FormattedRecordType::Pulse => RawRecord {
+                m0: encoding.m0.encode_value(record.intensity0),
+                m1: encoding.m1.encode_value(record.intensity1),
+                bk0: encoding.bk0.encode_value(record.bg0),
+                bk1: encoding.bk1.encode_value(record.bg1),
+                std0: encoding.std0.encode_value(record.sd0),
+                std1: encoding.std1.encode_value(record.sd1),
+                ..raw
+            },
+            FormattedRecordType::LongPulseUpdate
+            | FormattedRecordType::LongPulseDropped
+            | FormattedRecordType::StepUp
+            | FormattedRecordType::StepDown => {
+                let (bk0, bk1) = match record.long_pulse_num_frames.or(record.event_frame) {
+                    Some(frames) => (
+                        (frames & 0xffff) as u16 as i16,
+                        (frames >> 16) as u16 as i16,
+                    ),
+                    None => (raw.bk0, raw.bk1),
+                };
+                RawRecord {
+                    m0: encoding.m0.encode_value(record.intensity0),
+                    m1: encoding.m1.encode_value(record.intensity1),
+                    bk0,
+                    bk1,
+                    ..raw
+                }
+            }
+            FormattedRecordType::Background => RawRecord {
+                bk0: encoding.bk0.encode_value(record.bg0),
+                bk1: encoding.bk1.encode_value(record.bg1),
+                std0: encoding.std0.encode_value(record.sd0),
+                std1: encoding.std1.encode_value(record.sd1),
+                ..raw
+            },
+            FormattedRecordType::Padding | FormattedRecordType::Unknown => raw,
+        }
+    }
+
+    pub fn write_all<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let mut buffer = [0u8; PULSE_SIZE];
+        buffer[0..2].copy_from_slice(&self.frames_since_last.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.duration.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.m0.to_le_bytes());
+        buffer[6..8].copy_from_slice(&self.m1.to_le_bytes());
+        buffer[8..10].copy_from_slice(&self.bk0.to_le_bytes());
+        buffer[10..12].copy_from_slice(&self.bk1.to_le_bytes());
+        buffer[12..14].copy_from_slice(&self.std0.to_le_bytes());
+        buffer[14..16].copy_from_slice(&self.std1.to_le_bytes());
+        writer.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+/// Builds the records of an aperture from records placed at known frames
+///
+/// Records are pushed in order with the frame at which they start, and their
+/// `frames_since_last` is set relative to the end of the previous record, starting from
+/// `origin`. Padding records are inserted wherever the gap is too long for a single record.
+pub(crate) struct RecordBuilder {
+    records: Vec<RawRecord>,
+    end: u64,
+}
+
+impl RecordBuilder {
+    /// Creates a builder whose first record is placed relative to frame `origin`
+    pub(crate) fn new(origin: u64) -> Self {
+        RecordBuilder {
+            records: Vec::new(),
+            end: origin,
+        }
+    }
+
+    /// Appends a record starting at frame `start`
+    ///
+    /// Returns an error if the record starts before the previous record ends.
+    pub(crate) fn push(&mut self, start: u64, record: RawRecord) -> Result<()> {
+        let gap = start.checked_sub(self.end).ok_or_else(|| {
+            anyhow!(
+                "Record starting at frame {} overlaps the previous record, which ends at frame {}",
+                start,
+                self.end
+            )
+        })?;
+        let frames_since_last = RawRecord::pad_gap(&mut self.records, gap);
+        self.end = start + record.duration as u64;
+        self.records.push(RawRecord {
+            frames_since_last,
+            ..record
+        });
+        Ok(())
+    }
+
+    /// The built records
+    pub(crate) fn into_records(self) -> Vec<RawRecord> {
+        self.records
     }
 }
 
@@ -85,18 +244,19 @@ pub enum FormattedRecordType {
     Unknown,
 }
 
-impl FormattedRecordType {
-    pub fn to_string(&self) -> String {
-        match self {
-            FormattedRecordType::Pulse => "pulse".to_string(),
-            FormattedRecordType::Padding => "padding".to_string(),
-            FormattedRecordType::LongPulseUpdate => "long_pulse_update".to_string(),
-            FormattedRecordType::LongPulseDropped => "long_pulse_dropped".to_string(),
-            FormattedRecordType::StepUp => "step_up".to_string(),
-            FormattedRecordType::StepDown => "step_down".to_string(),
-            FormattedRecordType::Background => "background".to_string(),
-            FormattedRecordType::Unknown => "unknown".to_string(),
-        }
+impl fmt::Display for FormattedRecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FormattedRecordType::Pulse => "pulse",
+            FormattedRecordType::Padding => "padding",
+            FormattedRecordType::LongPulseUpdate => "long_pulse_update",
+            FormattedRecordType::LongPulseDropped => "long_pulse_dropped",
+            FormattedRecordType::StepUp => "step_up",
+            FormattedRecordType::StepDown => "step_down",
+            FormattedRecordType::Background => "background",
+            FormattedRecordType::Unknown => "unknown",
+        };
+        f.write_str(name)
     }
 }
 
@@ -119,10 +279,27 @@ pub struct FormattedRecord {
 
 impl FormattedRecord {
     /// Convert a raw record into a formatted record
+    ///
+    /// Equivalent to `from_raw_with_encoding`, with the field map built from the encoding
+    /// table by looking up each field by its record type ID. Fields without an encoding in
+    /// the table are left unscaled, and the first encoding is used for fields with more
+    /// than one. The table is searched for every record, so build a `RecordEncoding` once
+    /// instead when converting many records.
+    #[deprecated(note = "use `from_raw_with_encoding` with a `RecordEncoding`")]
     pub fn from_raw(
         raw_pulse_record: &RawRecord,
         record_types: &[PulseRecordType],
         index: usize,
+    ) -> Self {
+        let encoding = RecordEncoding::with_defaults(record_types);
+        Self::from_raw_with_encoding(raw_pulse_record, &encoding, index)
+    }
+
+    /// Convert a raw record into a formatted record, using the field map of its file
+    pub fn from_raw_with_encoding(
+        raw_pulse_record: &RawRecord,
+        encoding: &RecordEncoding,
+        index: usize,
     ) -> Self {
         if raw_pulse_record.duration > 0 {
             FormattedRecord {
@@ -130,16 +307,16 @@ impl FormattedRecord {
                 record_type: FormattedRecordType::Pulse,
                 frames_since_last: raw_pulse_record.frames_since_last,
                 duration: raw_pulse_record.duration,
-                intensity0: record_types[2].format_value(raw_pulse_record.m0),
-                intensity1: record_types[3].format_value(raw_pulse_record.m1),
-                bg0: record_types[4].format_value(raw_pulse_record.bk0),
-                bg1: record_types[5].format_value(raw_pulse_record.bk1),
-                sd0: record_types[6].format_value(raw_pulse_record.std0),
-                sd1: record_types[7].format_value(raw_pulse_record.std1),
+                intensity0: encoding.m0.format_value(raw_pulse_record.m0),
+                intensity1: encoding.m1.format_value(raw_pulse_record.m1),
+                bg0: encoding.bk0.format_value(raw_pulse_record.bk0),
+                bg1: encoding.bk1.format_value(raw_pulse_record.bk1),
+                sd0: encoding.std0.format_value(raw_pulse_record.std0),
+                sd1: encoding.std1.format_value(raw_pulse_record.std1),
                 long_pulse_num_frames: None,
                 event_frame: None,
             }
-        } else if raw_pulse_record.frames_since_last == 65535 {
+        } else if raw_pulse_record.is_padding() {
             FormattedRecord {
                 index,
                 record_type: FormattedRecordType::Padding,
@@ -160,8 +337,8 @@ impl FormattedRecord {
                 record_type: FormattedRecordType::LongPulseUpdate,
                 frames_since_last: raw_pulse_record.frames_since_last,
                 duration: raw_pulse_record.duration,
-                intensity0: record_types[2].format_value(raw_pulse_record.m0),
-                intensity1: record_types[3].format_value(raw_pulse_record.m1),
+                intensity0: encoding.m0.format_value(raw_pulse_record.m0),
+                intensity1: encoding.m1.format_value(raw_pulse_record.m1),
                 bg0: raw_pulse_record.bk0 as f32,
                 bg1: raw_pulse_record.bk1 as f32,
                 sd0: raw_pulse_record.std0 as f32,
@@ -178,8 +355,8 @@ impl FormattedRecord {
                 record_type: FormattedRecordType::LongPulseDropped,
                 frames_since_last: raw_pulse_record.frames_since_last,
                 duration: raw_pulse_record.duration,
-                intensity0: record_types[2].format_value(raw_pulse_record.m0),
-                intensity1: record_types[3].format_value(raw_pulse_record.m1),
+                intensity0: encoding.m0.format_value(raw_pulse_record.m0),
+                intensity1: encoding.m1.format_value(raw_pulse_record.m1),
                 bg0: raw_pulse_record.bk0 as f32,
                 bg1: raw_pulse_record.bk1 as f32,
                 sd0: raw_pulse_record.std0 as f32,
@@ -196,8 +373,8 @@ impl FormattedRecord {
                 record_type: FormattedRecordType::StepUp,
                 frames_since_last: raw_pulse_record.frames_since_last,
                 duration: raw_pulse_record.duration,
-                intensity0: record_types[2].format_value(raw_pulse_record.m0),
-                intensity1: record_types[3].format_value(raw_pulse_record.m1),
+                intensity0: encoding.m0.format_value(raw_pulse_record.m0),
+                intensity1: encoding.m1.format_value(raw_pulse_record.m1),
                 bg0: raw_pulse_record.bk0 as f32,
                 bg1: raw_pulse_record.bk1 as f32,
                 sd0: raw_pulse_record.std0 as f32,
@@ -214,8 +391,8 @@ impl FormattedRecord {
                 record_type: FormattedRecordType::StepDown,
                 frames_since_last: raw_pulse_record.frames_since_last,
                 duration: raw_pulse_record.duration,
-                intensity0: record_types[2].format_value(raw_pulse_record.m0),
-                intensity1: record_types[3].format_value(raw_pulse_record.m1),
+                intensity0: encoding.m0.format_value(raw_pulse_record.m0),
+                intensity1: encoding.m1.format_value(raw_pulse_record.m1),
                 bg0: raw_pulse_record.bk0 as f32,
                 bg1: raw_pulse_record.bk1 as f32,
                 sd0: raw_pulse_record.std0 as f32,
@@ -234,10 +411,10 @@ impl FormattedRecord {
                 duration: raw_pulse_record.duration,
                 intensity0: raw_pulse_record.m0 as f32,
                 intensity1: raw_pulse_record.m1 as f32,
-                bg0: record_types[4].format_value(raw_pulse_record.bk0),
-                bg1: record_types[5].format_value(raw_pulse_record.bk1),
-                sd0: record_types[6].format_value(raw_pulse_record.std0),
-                sd1: record_types[7].format_value(raw_pulse_record.std1),
+                bg0: encoding.bk0.format_value(raw_pulse_record.bk0),
+                bg1: encoding.bk1.format_value(raw_pulse_record.bk1),
+                sd0: encoding.std0.format_value(raw_pulse_record.std0),
+                sd1: encoding.std1.format_value(raw_pulse_record.std1),
                 long_pulse_num_frames: None,
                 event_frame: None,
             }
@@ -320,6 +497,46 @@ impl NormalizedPulse {
         }
     }
 
+    /// Converts a normalized pulse back into a formatted pulse record
+    ///
+    /// `last_record_end` is the end frame of the record preceding this pulse, which is needed
+    /// to recover the pulse's frames_since_last. Returns an error if the pulse starts before
+    /// `last_record_end`, or if its frames_since_last or duration do not fit in a record.
+    pub fn to_formatted_record(&self, last_record_end: u32) -> Result<FormattedRecord> {
+        let frames_since_last = self.start_f.checked_sub(last_record_end).ok_or_else(|| {
+            anyhow!(
+                "Pulse {} starts before the previous record ends",
+                self.index
+            )
+        })?;
+        Ok(FormattedRecord {
+            index: self.index,
+            record_type: FormattedRecordType::Pulse,
+            frames_since_last: u16::try_from(frames_since_last).map_err(|_| {
+                anyhow!(
+                    "frames_since_last of pulse {} does not fit in a record: {}",
+                    self.index,
+                    frames_since_last
+                )
+            })?,
+            duration: u16::try_from(self.dur_f).map_err(|_| {
+                anyhow!(
+                    "Duration of pulse {} does not fit in a record: {}",
+                    self.index,
+                    self.dur_f
+                )
+            })?,
+            intensity0: self.bin0_intensity,
+            intensity1: self.intensity,
+            bg0: self.bin0_bg_mean,
+            bg1: self.bg_mean,
+            sd0: self.bin0_bg_std,
+            sd1: self.bg_std,
+            long_pulse_num_frames: None,
+            event_frame: None,
+        })
+    }
+
     /// Converts a buffer of records into a vector of normalized pulse records
     ///
     /// This method takes a collection of all formatted records from a given
@@ -328,27 +545,90 @@ impl NormalizedPulse {
     /// must be provided, otherwise several fields will be incorrect, including
     /// index, start_f/end_f, and ipd_f.
     pub fn from_formatted_records(records: &[FormattedRecord], fps: f32) -> Vec<Self> {
-        let mut last_record_end = 0u32;
-        let mut last_pulse_end: Option<u32> = None;
-        let mut norm_records: Vec<Self> = Vec::new();
-        for record in records {
-            match record.record_type {
-                FormattedRecordType::Pulse => {
-                    let norm_pulse = NormalizedPulse::from_formatted_record(
-                        record,
-                        last_record_end,
-                        last_pulse_end,
-                        fps,
-                    );
-                    last_record_end = norm_pulse.end_f;
-                    last_pulse_end = Some(norm_pulse.end_f);
-                    norm_records.push(norm_pulse);
-                }
-                _ => {
-                    last_record_end += record.frames_since_last as u32;
-                }
+        let mut normalizer = PulseNormalizer::new(fps);
+        records
+            .iter()
+            .filter_map(|record| normalizer.push(record))
+            .collect()
+    }
+}
+
+/// A streaming builder of normalized pulse records
+///
+/// Converts the formatted records of a single aperture into NormalizedPulses one record
+/// at a time, carrying the end of the last record and of the last pulse between calls.
+/// This produces the same pulses as `NormalizedPulse::from_formatted_records` without
+/// requiring every record of the aperture to be held in memory. Records must be pushed
+/// in order, starting from the first record of the aperture.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::records::PulseNormalizer;
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// # let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+/// let ap = pulse_reader.index.apertures[0];
+/// let (records, _) = pulse_reader.iter_aperture_records(ap).unwrap();
+///
+/// let mut normalizer = PulseNormalizer::new(pulse_reader.fps);
+/// let mut num_pulses = 0;
+/// for record in records {
+///     if let Some(_pulse) = normalizer.push(&record.unwrap()) {
+///         num_pulses += 1;
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PulseNormalizer {
+    fps: f32,
+    last_record_end: u32,
+    last_pulse_end: Option<u32>,
+}
+
+impl PulseNormalizer {
+    /// Creates a normalizer for a new aperture
+    pub fn new(fps: f32) -> Self {
+        PulseNormalizer {
+            fps,
+            last_record_end: 0,
+            last_pulse_end: None,
+        }
+    }
+
+    /// Processes the next record of the aperture
+    ///
+    /// Returns the normalized pulse if the record is a pulse, otherwise advances the
+    /// current frame past the record and returns None.
+    pub fn push(&mut self, record: &FormattedRecord) -> Option<NormalizedPulse> {
+        match record.record_type {
+            FormattedRecordType::Pulse => {
+                let norm_pulse = NormalizedPulse::from_formatted_record(
+                    record,
+                    self.last_record_end,
+                    self.last_pulse_end,
+                    self.fps,
+                );
+                self.last_record_end = norm_pulse.end_f;
+                self.last_pulse_end = Some(norm_pulse.end_f);
+                Some(norm_pulse)
+            }
+            _ => {
+                self.last_record_end += record.frames_since_last as u32;
+                None
             }
         }
-        norm_records
+    }
+
+    /// The frame at which the last processed record ended
+    pub fn last_record_end(&self) -> u32 {
+        self.last_record_end
+    }
+
+    /// The frame at which the last processed pulse ended, if any pulse has been processed
+    pub fn last_pulse_end(&self) -> Option<u32> {
+        self.last_pulse_end
     }
 }
@@ -0,0 +1,209 @@
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::{ApertureHeader, PulseFileHeader, PulseFileIndex};
+use crate::pulse_reader::iter::ReadBuffer;
+use crate::pulse_reader::metadata::RunMetadata;
+use crate::pulse_reader::writer::OverwritePolicy;
+use crate::pulse_reader::{FileSections, PulseReader, ReadBackend};
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+
+/// The reason that scanning the data section of a file stopped
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum ScanEnd {
+    /// The end of the file was reached between apertures
+    EndOfFile,
+    /// An intact index section magic number was found between apertures
+    IndexFound,
+    /// The records of the aperture starting at `byte_loc` extend past the end of the file
+    TruncatedAperture { aperture: usize, byte_loc: u64 },
+    /// The bytes at `byte_loc` are not a plausible aperture header
+    InvalidHeader { byte_loc: u64 },
+    /// The aperture starting at `byte_loc` was already found earlier in the file
+    DuplicateAperture { aperture: usize, byte_loc: u64 },
+    /// An aperture header of all zeros was found at `byte_loc`, after the first aperture
+    ///
+    /// Files that were preallocated but never completely written are zero-filled past the
+    /// last aperture, so this is taken as the end of the data.
+    ZeroFilled { byte_loc: u64 },
+}
+
+/// A summary of the apertures recovered by scanning the data section of a file
+#[derive(Serialize, Debug, Clone)]
+pub struct RecoveryReport {
+    /// The number of complete apertures found
+    pub num_apertures: usize,
+    /// The end of the last complete aperture, where the index of a repaired file starts
+    pub data_end: u64,
+    /// The number of bytes following `data_end`, which are not part of any recovered aperture
+    pub discarded_bytes: u64,
+    /// Why scanning stopped
+    pub scan_end: ScanEnd,
+}
+
+impl RecoveryReport {
+    /// Serializes the report as a JSON string
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+impl PulseReader {
+    /// Opens a pulses.bin file whose index is missing or corrupt
+    ///
+    /// Instead of reading the index at the end of the file, this walks the aperture headers
+    /// sequentially from the start of the data section and reconstructs the index in memory.
+    /// Scanning stops at the end of the file, at an intact index section, at an aperture
+    /// whose records extend past the end of the file (which is dropped), at an aperture
+    /// that was already found, at a header whose well_id does not match its position on
+    /// the chip, or at a header of all zeros after the first aperture. The file header and
+    /// metadata must be intact.
+    ///
+    /// The `num_reads` and `index_offset` of the returned reader's header describe the
+    /// recovered apertures, so the reader can be used as normal, e.g. to write a repaired
+    /// copy of the file with `copy_apertures_to_new_file`. See also `recover_file`.
+    ///
+    /// # Examples
+    /// ```
+    /// use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let (pulse_reader, report) = PulseReader::open_recovered(pulse_file_path).unwrap();
+    /// assert!(report.num_apertures == pulse_reader.index.apertures.len());
+    /// ```
+    pub fn open_recovered<P: AsRef<Path>>(file_name: P) -> Result<(Self, RecoveryReport)> {
+        let mut file = File::open(file_name.as_ref()).map_err(PulseError::Io)?;
+        let file_size = file.metadata().map_err(PulseError::Io)?.len();
+        let mut report = None;
+        let sections = FileSections::read_with(&mut file, |file, header, metadata| {
+            let (index, scan_report) = scan_apertures(file, header, metadata, file_size)?;
+            report = Some(scan_report);
+            Ok(index)
+        })?;
+        let report = report.ok_or_else(|| anyhow!("Aperture scan did not run"))?;
+
+        Ok((
+            PulseReader::from_sections(file_name.as_ref(), ReadBackend::File(file), sections),
+            report,
+        ))
+    }
+}
+
+/// Writes a repaired copy of a pulses.bin file whose index is missing or corrupt
+///
+/// Recovers as many complete apertures as possible with `PulseReader::open_recovered`,
+/// then writes them to a new file with a correct header and index. An existing file is
+/// handled according to [`OverwritePolicy`].
+///
+/// # Examples
+/// ```
+/// use qsi_pulse_reader::pulse_reader::recover::recover_file;
+/// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+/// # use std::path::PathBuf;
+/// # use tempfile::tempdir;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// # let temp_dir = tempdir().unwrap();
+/// # let repaired_file_path = temp_dir.path().join("pulses_repaired.bin");
+/// let report = recover_file(&pulse_file_path, &repaired_file_path, OverwritePolicy::Fail).unwrap();
+/// println!("Recovered {} apertures", report.num_apertures);
+/// ```
+pub fn recover_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    file_name: P,
+    new_file_name: Q,
+    overwrite: OverwritePolicy,
+) -> Result<RecoveryReport> {
+    let (pulse_reader, report) = PulseReader::open_recovered(file_name)?;
+    pulse_reader.copy_apertures_to_new_file(
+        &pulse_reader.index.apertures,
+        new_file_name,
+        overwrite,
+    )?;
+    Ok(report)
+}
+
+/// Walks the aperture headers from the start of the data section
+///
+/// Returns the reconstructed index, and updates the number of reads and index offset
+/// in `header` to match the recovered apertures.
+fn scan_apertures(
+    file: &File,
+    header: &mut PulseFileHeader,
+    metadata: &RunMetadata,
+    file_size: u64,
+) -> Result<(PulseFileIndex, RecoveryReport)> {
+    // Apertures are only accepted if their well_id matches their position, when known
+    let cols = metadata.cols.map(u64::from);
+
+    let mut buffer = ReadBuffer::new(file, file_size);
+    let mut entries: Vec<(usize, u64)> = Vec::new();
+    let mut seen: HashSet<usize> = HashSet::new();
+    let mut offset = header.data_offset;
+    let scan_end = loop {
+        if offset
+            .checked_add(READ_HEADER_SIZE as u64)
+            .is_none_or(|header_end| header_end > file_size)
+        {
+            break ScanEnd::EndOfFile;
+        }
+        let header_buffer: &[u8; READ_HEADER_SIZE] =
+            buffer.read(offset, READ_HEADER_SIZE)?.try_into()?;
+        if u64::from_le_bytes(header_buffer[0..8].try_into()?) == INDEX_SECTION_MAGIC {
+            break ScanEnd::IndexFound;
+        }
+        // A zero-filled tail would otherwise be taken for an empty aperture 0
+        if !entries.is_empty() && header_buffer.iter().all(|&byte| byte == 0) {
+            break ScanEnd::ZeroFilled { byte_loc: offset };
+        }
+        let aperture_header = ApertureHeader::new(header_buffer, offset)?;
+        let aperture = aperture_header.well_id as usize;
+        if let Some(cols) = cols
+            && (aperture_header.x as u64 >= cols
+                || aperture_header.well_id as u64
+                    != aperture_header.x as u64 + cols * aperture_header.y as u64)
+        {
+            break ScanEnd::InvalidHeader { byte_loc: offset };
+        }
+        if !seen.insert(aperture) {
+            break ScanEnd::DuplicateAperture {
+                aperture,
+                byte_loc: offset,
+            };
+        }
+        let end = offset
+            + READ_HEADER_SIZE as u64
+            + aperture_header.num_pulses as u64 * PULSE_SIZE as u64;
+        if end > file_size {
+            break ScanEnd::TruncatedAperture {
+                aperture,
+                byte_loc: offset,
+            };
+        }
+        entries.push((aperture, offset));
+        offset = end;
+    };
+
+    // The index must be sorted by aperture index
+    entries.sort_unstable();
+    let index = PulseFileIndex::from_entries(&entries)
+        .map_err(|_| anyhow!("No complete apertures were found in the data section"))?;
+    header.num_reads = entries.len() as u64;
+    header.index_offset = offset;
+
+    let report = RecoveryReport {
+        num_apertures: entries.len(),
+        data_end: offset,
+        discarded_bytes: file_size.saturating_sub(offset),
+        scan_end,
+    };
+    Ok((index, report))
+}
@@ -0,0 +1,399 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::records::{
+    FormattedRecord, FormattedRecordType, RawRecord, RecordBuilder,
+};
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// How many apertures to sample
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleSize {
+    /// A fixed number of apertures
+    Count(usize),
+    /// A fraction of the apertures in the file, between 0 and 1, rounded to the nearest
+    /// number of apertures
+    Fraction(f64),
+}
+
+impl SampleSize {
+    /// The number of apertures to sample from a file with `num_apertures` apertures
+    ///
+    /// Returns an error if the sample would be empty or larger than the file.
+    fn resolve(&self, num_apertures: usize) -> Result<usize> {
+        let n = match *self {
+            SampleSize::Count(n) if n > num_apertures => {
+                return Err(PulseError::invalid_argument(format!(
+                    "Cannot sample {} apertures from a file with {} apertures",
+                    n, num_apertures
+                ))
+                .into());
+            }
+            SampleSize::Count(n) => n,
+            SampleSize::Fraction(fraction) if !(0.0..=1.0).contains(&fraction) => {
+                return Err(PulseError::invalid_argument(format!(
+                    "Sample fraction must be between 0 and 1, got {}",
+                    fraction
+                ))
+                .into());
+            }
+            SampleSize::Fraction(fraction) => (fraction * num_apertures as f64).round() as usize,
+        };
+        if n == 0 {
+            return Err(PulseError::invalid_argument(format!(
+                "A sample of {:?} from a file with {} apertures would be empty",
+                self, num_apertures
+            ))
+            .into());
+        }
+        Ok(n)
+    }
+}
+
+/// How apertures are chosen when sampling
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingStrategy {
+    /// Every aperture is equally likely to be chosen
+    #[default]
+    Uniform,
+    /// Apertures are grouped into strata by activity, and sampled from every stratum
+    ///
+    /// The activity of an aperture is the number of records given by
+    /// `ApertureHeader.num_pulses`. The apertures are divided at the quantiles of their
+    /// activity into `num_strata` groups, with apertures of equal activity always in the
+    /// same group, so some groups may be merged. The sample is then split as evenly as
+    /// possible between the groups, so that the few most active apertures are represented
+    /// as well as the many quiet ones.
+    Stratified { num_strata: usize },
+}
+
+/// A reproducible random sample of the apertures in a pulses.bin file
+///
+/// The same sample is chosen from the same file for the same `seed`, on any platform and
+/// from any of the language bindings.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::sample::{ApertureSample, SampleSize, SamplingStrategy};
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+///
+/// let sample = ApertureSample {
+///     size: SampleSize::Fraction(0.5),
+///     strategy: SamplingStrategy::Uniform,
+///     seed: 42,
+/// };
+/// let apertures = pulse_reader.sample_apertures(&sample).unwrap();
+/// assert_eq!(apertures.len(), 5);
+/// assert_eq!(apertures, pulse_reader.sample_apertures(&sample).unwrap());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ApertureSample {
+    pub size: SampleSize,
+    pub strategy: SamplingStrategy,
+    /// The seed of the random number generator
+    ///
+    /// R numbers are only exact up to 2^53, so the R bindings reject larger seeds. Keep
+    /// seeds at or below 2^53 for samples that need to be reproduced from R.
+    pub seed: u64,
+}
+
+/// A reproducible random sample of the pulses in a pulses.bin file
+///
+/// Each pulse is kept with probability `fraction`, independently of the others, so the
+/// number of pulses kept varies around that fraction of the total. The same pulses are
+/// kept from the same file for the same `seed`, on any platform and from any of the
+/// language bindings.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PulseSample {
+    /// The probability of keeping each pulse, between 0 and 1
+    pub fraction: f64,
+    /// The seed of the random number generator, limited to 2^53 from R as for
+    /// `ApertureSample`
+    pub seed: u64,
+}
+
+/// The SplitMix64 random number generator
+///
+/// Implemented here rather than taken from a crate, so that the sequence produced for a
+/// seed, and therefore every sample, can never change with a dependency update.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A random number in `0..bound`
+    ///
+    /// Uses a multiply and shift rather than a modulo, which has a bias far too small to
+    /// matter for the number of apertures in a file.
+    fn below(&mut self, bound: usize) -> usize {
+        ((self.next_u64() as u128 * bound as u128) >> 64) as usize
+    }
+
+    /// Returns true with probability `p`, from the top 53 bits of the next number
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64) < p * (1u64 << 53) as f64
+    }
+}
+
+/// Chooses `n` of `apertures` at random, with a partial Fisher-Yates shuffle
+fn choose(apertures: &[usize], n: usize, rng: &mut SplitMix64) -> Vec<usize> {
+    let mut apertures = apertures.to_vec();
+    for i in 0..n {
+        let j = i + rng.below(apertures.len() - i);
+        apertures.swap(i, j);
+    }
+    apertures.truncate(n);
+    apertures
+}
+
+/// Divides apertures into strata at the quantiles of their activity
+///
+/// `activity` holds the (activity, aperture) pairs of every aperture, sorted. Each cut is
+/// moved past any apertures with the same activity as the one before it, so the returned
+/// strata are never empty, but there may be fewer than `num_strata` of them.
+fn activity_strata(activity: &[(u32, usize)], num_strata: usize) -> Vec<Vec<usize>> {
+    let mut strata = Vec::new();
+    let mut start = 0;
+    for k in 1..=num_strata {
+        let mut end = (k * activity.len() / num_strata).max(start);
+        while end > 0 && end < activity.len() && activity[end].0 == activity[end - 1].0 {
+            end += 1;
+        }
+        if end > start {
+            strata.push(activity[start..end].iter().map(|(_, ap)| *ap).collect());
+            start = end;
+        }
+    }
+    strata
+}
+
+/// Splits `n` samples as evenly as possible between strata of the given sizes
+///
+/// The smallest strata are filled first, and any samples they can't take are shared
+/// between the larger ones. `n` must not be more than the total size of the strata.
+fn allocate(sizes: &[usize], n: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| (sizes[i], i));
+    let mut counts = vec![0; sizes.len()];
+    let mut remaining = n;
+    for (pos, &i) in order.iter().enumerate() {
+        counts[i] = (remaining / (sizes.len() - pos)).min(sizes[i]);
+        remaining -= counts[i];
+    }
+    counts
+}
+
+/// Keeps each pulse record of an aperture with probability `fraction`
+///
+/// Padding records are dropped and non-pulse records are kept, and the timing of the
+/// remaining records is preserved as in `write_filtered`. A random number is drawn for
+/// each pulse record only.
+fn sample_records(
+    pulse_file: &PulseReader,
+    raw_records: &[RawRecord],
+    fraction: f64,
+    rng: &mut SplitMix64,
+) -> Result<Vec<RawRecord>> {
+    let mut output = RecordBuilder::new(0);
+    let mut end: u64 = 0;
+    for (idx, raw_record) in raw_records.iter().enumerate() {
+        let start = end + raw_record.frames_since_last as u64;
+        end = start + raw_record.duration as u64;
+        let record = FormattedRecord::from_raw_with_encoding(raw_record, &pulse_file.encoding, idx);
+        let keep = match record.record_type {
+            FormattedRecordType::Pulse => rng.chance(fraction),
+            FormattedRecordType::Padding => false,
+            _ => true,
+        };
+        if keep {
+            output.push(start, raw_record.clone())?;
+        }
+    }
+    Ok(output.into_records())
+}
+
+impl PulseReader {
+    /// Choose a reproducible random sample of the apertures in this file
+    ///
+    /// Returns the sampled aperture indices in index order. Stratified sampling reads the
+    /// header of every aperture in the file. Returns an error if the sample would be empty
+    /// or larger than the file.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::sample::{ApertureSample, SampleSize, SamplingStrategy};
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// // Sample 4 apertures, from the least to the most active
+    /// let sample = ApertureSample {
+    ///     size: SampleSize::Count(4),
+    ///     strategy: SamplingStrategy::Stratified { num_strata: 4 },
+    ///     seed: 1234,
+    /// };
+    /// let apertures = pulse_reader.sample_apertures(&sample).unwrap();
+    /// assert_eq!(apertures.len(), 4);
+    /// ```
+    pub fn sample_apertures(&self, sample: &ApertureSample) -> Result<Vec<usize>> {
+        let n = sample.size.resolve(self.index.apertures.len())?;
+        let mut rng = SplitMix64(sample.seed);
+        let mut apertures = match sample.strategy {
+            SamplingStrategy::Uniform => choose(&self.index.apertures, n, &mut rng),
+            SamplingStrategy::Stratified { num_strata: 0 } => {
+                return Err(PulseError::invalid_argument(
+                    "Stratified sampling requires at least one stratum",
+                )
+                .into());
+            }
+            SamplingStrategy::Stratified { num_strata } => {
+                let headers = self.aperture_headers()?;
+                let mut activity: Vec<(u32, usize)> = headers
+                    .num_pulses
+                    .into_iter()
+                    .zip(headers.aperture)
+                    .collect();
+                activity.sort();
+                let strata = activity_strata(&activity, num_strata);
+                let sizes: Vec<usize> = strata.iter().map(|stratum| stratum.len()).collect();
+                strata
+                    .iter()
+                    .zip(allocate(&sizes, n))
+                    .flat_map(|(stratum, count)| choose(stratum, count, &mut rng))
+                    .collect()
+            }
+        };
+        apertures.sort();
+        Ok(apertures)
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the apertures
+    ///
+    /// The apertures chosen by `sample_apertures` are copied to the new file, as with
+    /// `copy_apertures_to_new_file`, and the sample is recorded in the `apertureSample`
+    /// field of its metadata. An existing file is handled according to
+    /// [`OverwritePolicy`]. Returns the sampled aperture indices in index order.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::sample::{ApertureSample, SampleSize, SamplingStrategy};
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let new_pulse_file_path = temp_dir.path().join("sampled_pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let sample = ApertureSample {
+    ///     size: SampleSize::Count(3),
+    ///     strategy: SamplingStrategy::Uniform,
+    ///     seed: 7,
+    /// };
+    /// let apertures = pulse_reader
+    ///     .write_sampled_apertures(&sample, &new_pulse_file_path, OverwritePolicy::Fail)
+    ///     .unwrap();
+    ///
+    /// let sampled_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+    /// assert_eq!(sampled_reader.index.apertures, apertures);
+    /// ```
+    pub fn write_sampled_apertures<P: AsRef<Path>>(
+        &self,
+        sample: &ApertureSample,
+        file_name: P,
+        overwrite: OverwritePolicy,
+    ) -> Result<Vec<usize>> {
+        let apertures = self.sample_apertures(sample)?;
+        let sample_params = serde_json::to_value(sample)?;
+        self.copy_subset(
+            &apertures,
+            Some(&|metadata: &mut Value| metadata["apertureSample"] = sample_params.clone()),
+            file_name,
+            overwrite,
+        )?;
+        Ok(apertures)
+    }
+
+    /// Create a new pulses.bin file with a reproducible random sample of the pulses
+    ///
+    /// Every aperture is copied to the new file, keeping each of its pulses with
+    /// probability `sample.fraction`. Non-pulse records are kept, and the remaining
+    /// records keep their original start frames, as with `write_filtered`. Apertures are
+    /// sampled in index order from a single random sequence, and the sample is recorded in
+    /// the `pulseSample` field of the metadata of the new file. Returns an error if the
+    /// fraction is not between 0 and 1.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::sample::PulseSample;
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let new_pulse_file_path = temp_dir.path().join("sampled_pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let sample = PulseSample {
+    ///     fraction: 0.1,
+    ///     seed: 7,
+    /// };
+    /// pulse_reader
+    ///     .write_sampled_pulses(&sample, &new_pulse_file_path, OverwritePolicy::Fail)
+    ///     .unwrap();
+    ///
+    /// let sampled_reader = PulseReader::open(&new_pulse_file_path).unwrap();
+    /// assert_eq!(sampled_reader.index.apertures, pulse_reader.index.apertures);
+    /// ```
+    pub fn write_sampled_pulses<P: AsRef<Path>>(
+        &self,
+        sample: &PulseSample,
+        file_name: P,
+        overwrite: OverwritePolicy,
+    ) -> Result<()> {
+        if !(0.0..=1.0).contains(&sample.fraction) {
+            return Err(PulseError::invalid_argument(format!(
+                "Pulse sample fraction must be between 0 and 1, got {}",
+                sample.fraction
+            ))
+            .into());
+        }
+        let mut metadata = self.metadata.clone();
+        metadata["pulseSample"] = serde_json::to_value(sample)?;
+
+        let mut rng = SplitMix64(sample.seed);
+        let mut writer =
+            PulseFileWriter::create(file_name, &metadata, &self.record_types, overwrite)?;
+        for &ap in self.index.apertures.iter() {
+            let (raw_records, aperture_header) = self.get_raw_records(ap)?;
+            let records = sample_records(self, &raw_records, sample.fraction, &mut rng)?;
+            writer.write_aperture(&aperture_header, &records)?;
+        }
+        writer.finish()
+    }
+}
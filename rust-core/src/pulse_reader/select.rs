@@ -0,0 +1,393 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::ApertureHeader;
+use crate::pulse_reader::metadata::RunMetadata;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::Value;
+
+/// One half of the chip, split down the middle column
+///
+/// These are also the halves whose apertures `validWellsLeft` and `validWellsRight` count
+/// when a file is subset or sharded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipHalf {
+    /// Apertures with `x < cols / 2`
+    Left,
+    /// Apertures with `x >= cols / 2`
+    Right,
+}
+
+/// A set of apertures to select from a pulses.bin file
+///
+/// Positions are given as `x` (column) and `y` (row), matching `ApertureHeader.x` and
+/// `ApertureHeader.y`. Apertures that are not present in the file are never selected.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// use qsi_pulse_reader::pulse_reader::select::{ApertureSelection, ChipHalf};
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+///
+/// let left = pulse_reader
+///     .select_apertures(&ApertureSelection::ChipHalf(ChipHalf::Left))
+///     .unwrap();
+/// let right = pulse_reader
+///     .select_apertures(&ApertureSelection::ChipHalf(ChipHalf::Right))
+///     .unwrap();
+/// assert_eq!(left.len() + right.len(), pulse_reader.index.apertures.len());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApertureSelection {
+    /// Apertures with the given aperture indices
+    Indices(Vec<usize>),
+    /// Apertures at the given (x, y) positions
+    Positions(Vec<(u32, u32)>),
+    /// Apertures inside a rectangle, including its edges
+    BoundingBox {
+        min_x: u32,
+        min_y: u32,
+        max_x: u32,
+        max_y: u32,
+    },
+    /// Apertures inside or on the edge of a polygon, given as a list of (x, y) vertices
+    Polygon(Vec<(f64, f64)>),
+    /// Apertures in one half of the chip
+    ChipHalf(ChipHalf),
+}
+
+impl ApertureSelection {
+    /// Loads a list of apertures from a CSV file
+    ///
+    /// The file must either have an `aperture` column of aperture indices, or `x` and `y`
+    /// columns of aperture positions. A file without a header is read as a single column
+    /// of aperture indices. Other columns are ignored.
+    ///
+    /// # Examples
+    /// ```
+    /// use qsi_pulse_reader::pulse_reader::select::ApertureSelection;
+    /// # use tempfile::tempdir;
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let csv_path = temp_dir.path().join("roi.csv");
+    /// std::fs::write(&csv_path, "x,y\n10,20\n11,20\n").unwrap();
+    ///
+    /// let selection = ApertureSelection::from_csv(&csv_path).unwrap();
+    /// assert_eq!(selection, ApertureSelection::Positions(vec![(10, 20), (11, 20)]));
+    /// ```
+    pub fn from_csv<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        let contents = fs::read_to_string(&file_name).map_err(PulseError::Io)?;
+        Self::parse_csv(&contents)
+            .with_context(|| format!("Failed to read {}", file_name.as_ref().display()))
+    }
+
+    fn parse_csv(contents: &str) -> Result<Self> {
+        let mut lines = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .peekable();
+        let Some((_, first)) = lines.peek() else {
+            return Ok(ApertureSelection::Indices(Vec::new()));
+        };
+
+        // A header is any first line that isn't a number
+        let columns: Vec<String> = split_csv_line(first)
+            .map(|column| column.to_lowercase())
+            .collect();
+        let has_header = columns[0].parse::<u64>().is_err();
+        if has_header {
+            lines.next();
+        }
+        let column = |name: &str| columns.iter().position(|column| column == name);
+        let field = |fields: &[&str], idx: usize, line_number: usize| -> Result<u32> {
+            let field = fields
+                .get(idx)
+                .ok_or_else(|| anyhow!("Line {} has too few columns", line_number + 1))?;
+            field.parse::<u32>().map_err(|e| {
+                anyhow!(
+                    "Invalid value '{}' on line {}: {}",
+                    field,
+                    line_number + 1,
+                    e
+                )
+            })
+        };
+
+        match (has_header, column("aperture"), column("x"), column("y")) {
+            (false, ..) | (true, Some(_), ..) => {
+                let idx = column("aperture").filter(|_| has_header).unwrap_or(0);
+                let mut apertures = Vec::new();
+                for (line_number, line) in lines {
+                    let fields: Vec<&str> = split_csv_line(line).collect();
+                    apertures.push(field(&fields, idx, line_number)? as usize);
+                }
+                Ok(ApertureSelection::Indices(apertures))
+            }
+            (true, None, Some(x_idx), Some(y_idx)) => {
+                let mut positions = Vec::new();
+                for (line_number, line) in lines {
+                    let fields: Vec<&str> = split_csv_line(line).collect();
+                    positions.push((
+                        field(&fields, x_idx, line_number)?,
+                        field(&fields, y_idx, line_number)?,
+                    ));
+                }
+                Ok(ApertureSelection::Positions(positions))
+            }
+            _ => Err(anyhow!(
+                "CSV must have an 'aperture' column, or 'x' and 'y' columns"
+            )),
+        }
+    }
+}
+
+/// Splits a line of a CSV file into its trimmed fields
+///
+/// Quoted fields are not supported, since the files we read only contain numbers and
+/// column names.
+fn split_csv_line(line: &str) -> impl Iterator<Item = &str> {
+    line.split(',')
+        .map(|field| field.trim().trim_matches('"').trim())
+}
+
+/// Whether the point (x, y) is inside or on the edge of a polygon
+///
+/// Uses the even-odd rule, so self-intersecting polygons are supported.
+fn polygon_contains(vertices: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let edges = vertices.iter().zip(vertices.iter().cycle().skip(1));
+
+    let mut inside = false;
+    for (&(x0, y0), &(x1, y1)) in edges {
+        // Points on an edge are always inside
+        let cross = (x1 - x0) * (y - y0) - (y1 - y0) * (x - x0);
+        if cross == 0.0 && x0.min(x1) <= x && x <= x0.max(x1) && y0.min(y1) <= y && y <= y0.max(y1)
+        {
+            return true;
+        }
+        if (y0 > y) != (y1 > y) && x < x0 + (y - y0) * (x1 - x0) / (y1 - y0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+impl PulseReader {
+    /// Find the apertures in this file that are part of a selection
+    ///
+    /// Returns the selected aperture indices in index order. Selections by position read
+    /// the header of every aperture in the file. Selecting a chip half requires `cols`
+    /// in the metadata.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::select::ApertureSelection;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// // Select the apertures in the top left corner of the chip
+    /// let selection = ApertureSelection::BoundingBox {
+    ///     min_x: 0,
+    ///     min_y: 0,
+    ///     max_x: 511,
+    ///     max_y: 511,
+    /// };
+    /// let apertures = pulse_reader.select_apertures(&selection).unwrap();
+    /// ```
+    pub fn select_apertures(&self, selection: &ApertureSelection) -> Result<Vec<usize>> {
+        let is_selected: Box<dyn Fn(&ApertureHeader) -> bool> = match selection {
+            ApertureSelection::Indices(apertures) => {
+                // No need to read the aperture headers
+                let apertures: HashSet<&usize> = apertures.iter().collect();
+                return Ok(self
+                    .index
+                    .apertures
+                    .iter()
+                    .filter(|ap| apertures.contains(ap))
+                    .copied()
+                    .collect());
+            }
+            ApertureSelection::Positions(positions) => {
+                let positions: HashSet<&(u32, u32)> = positions.iter().collect();
+                Box::new(move |header| positions.contains(&(header.x, header.y)))
+            }
+            ApertureSelection::BoundingBox {
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+            } => Box::new(|header| {
+                (*min_x..=*max_x).contains(&header.x) && (*min_y..=*max_y).contains(&header.y)
+            }),
+            ApertureSelection::Polygon(vertices) => {
+                Box::new(|header| polygon_contains(vertices, header.x as f64, header.y as f64))
+            }
+            ApertureSelection::ChipHalf(half) => {
+                let cols = self
+                    .run_metadata
+                    .cols
+                    .ok_or_else(|| PulseError::metadata_field("cols"))?;
+                Box::new(move |header| (header.x < cols / 2) == (*half == ChipHalf::Left))
+            }
+        };
+
+        Ok(self
+            .aperture_headers()?
+            .iter()
+            .filter(|(_, header)| is_selected(header))
+            .map(|(ap, _)| ap)
+            .collect())
+    }
+}
+
+/// The number of apertures in part of a file, in the whole chip and in each half
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct WellCounts {
+    all: u64,
+    left: u64,
+    right: u64,
+}
+
+impl WellCounts {
+    /// Counts the apertures in the given columns of a chip with `cols` columns
+    pub(super) fn new(columns: impl IntoIterator<Item = u32>, cols: u32) -> Self {
+        let mut counts = Self::default();
+        for x in columns {
+            counts.all += 1;
+            if x < cols / 2 {
+                counts.left += 1;
+            } else {
+                counts.right += 1;
+            }
+        }
+        counts
+    }
+}
+
+/// Scales `value` by the fraction `part / whole`, rounding to the nearest integer
+///
+/// `value` is returned unchanged when all of `whole` is kept, including when it is empty.
+fn share(value: u64, part: u64, whole: u64) -> u64 {
+    if part >= whole {
+        return value;
+    }
+    ((value as u128 * part as u128 + whole as u128 / 2) / whole as u128) as u64
+}
+
+/// Rewrites `validWells`, `validWellsLeft` and `validWellsRight` for a subset of a file
+///
+/// Each half's count is scaled by the fraction of that half's apertures in the file which
+/// are kept, or of all apertures if the file has none in that half, and `validWells` is scaled by the same factor as the sum of the two halves,
+/// matching the files this is read from, where `validWells` is the mean of the two. Copying
+/// every aperture therefore leaves all three unchanged. When the file has no counts for the
+/// halves, `validWells` is scaled by the fraction of all apertures kept. Counts missing from
+/// the metadata are left out.
+pub(super) fn set_valid_wells(
+    metadata: &mut Value,
+    run_metadata: &RunMetadata,
+    file: WellCounts,
+    kept: WellCounts,
+) {
+    // A half with no apertures in the file is scaled by the share of all apertures kept,
+    // so that the counts of the parts of a file add up to those of the file
+    let half_share = |count: u64, kept_half: u64, file_half: u64| match file_half {
+        0 => share(count, kept.all, file.all),
+        _ => share(count, kept_half, file_half),
+    };
+    let left = run_metadata
+        .valid_wells_left
+        .map(|left| half_share(left, kept.left, file.left));
+    let right = run_metadata
+        .valid_wells_right
+        .map(|right| half_share(right, kept.right, file.right));
+    let halves = match (
+        run_metadata.valid_wells_left,
+        run_metadata.valid_wells_right,
+        left,
+        right,
+    ) {
+        (Some(old_left), Some(old_right), Some(new_left), Some(new_right)) => {
+            Some((new_left + new_right, old_left + old_right))
+        }
+        _ => None,
+    };
+    let all = run_metadata.valid_wells.map(|all| match halves {
+        Some((part, whole)) => share(all, part, whole),
+        None => share(all, kept.all, file.all),
+    });
+
+    for (name, count) in [
+        ("validWells", all),
+        ("validWellsLeft", left),
+        ("validWellsRight", right),
+    ] {
+        if let Some(count) = count {
+            metadata[name] = Value::from(count);
+        }
+    }
+}
+
+/// Rewrites the metadata of a file to describe a subset of its apertures
+///
+/// The region of interest is set to the bounding box of the subset, and `rows` is
+/// reduced to the last row in the subset, while `cols` is kept so that the well IDs of
+/// the subset remain valid. An empty subset has no rows and an empty region of interest.
+/// `validWells`, `validWellsLeft` and `validWellsRight` are scaled by the share of the
+/// file's apertures in each `ChipHalf` that are in the subset.
+///
+/// Returns `None` if the metadata is missing any of `rows`, `cols` or the region of
+/// interest, since the subset could then not be described.
+pub(super) fn subset_metadata(
+    pulse_file: &PulseReader,
+    headers: &[ApertureHeader],
+) -> Option<Value> {
+    let run_metadata = &pulse_file.run_metadata;
+    let cols = run_metadata.cols.filter(|&cols| cols > 0)?;
+    run_metadata.rows?;
+    run_metadata.roi_rows?;
+    run_metadata.roi_cols?;
+    run_metadata.roi_offset_row?;
+    run_metadata.roi_offset_col?;
+
+    // Edit the original JSON, as in `merge_pulse_files_with_layout`, so that the
+    // representation of the other fields is preserved
+    let mut metadata = pulse_file.metadata.clone();
+    let (min_x, end_x, min_y, end_y) = match (
+        headers.iter().map(|h| h.x).min(),
+        headers.iter().map(|h| h.x).max(),
+        headers.iter().map(|h| h.y).min(),
+        headers.iter().map(|h| h.y).max(),
+    ) {
+        (Some(min_x), Some(max_x), Some(min_y), Some(max_y)) => {
+            (min_x, max_x + 1, min_y, max_y + 1)
+        }
+        _ => (0, 0, 0, 0),
+    };
+    metadata["rows"] = Value::from(end_y);
+    metadata["roi_rows"] = Value::from(end_y - min_y);
+    metadata["roi_cols"] = Value::from(end_x - min_x);
+    metadata["roi_offset_row"] = Value::from(min_y);
+    metadata["roi_offset_col"] = Value::from(min_x);
+    let file_counts = WellCounts::new(
+        pulse_file
+            .index
+            .apertures
+            .iter()
+            .map(|&ap| (ap % cols as usize) as u32),
+        cols,
+    );
+    let kept_counts = WellCounts::new(headers.iter().map(|h| h.x), cols);
+    set_valid_wells(&mut metadata, run_metadata, file_counts, kept_counts);
+    Some(metadata)
+}
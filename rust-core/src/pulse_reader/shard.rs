@@ -0,0 +1,281 @@
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::merge::{check_inputs, record_properties};
+use crate::pulse_reader::writer::{OverwritePolicy, PulseFileWriter, write_file_atomically};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+/// A description of the shards a pulses.bin file was split into
+///
+/// Written as JSON next to the shards by `PulseReader::split_into_shards`, and used by
+/// `merge_shards` to reassemble them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardManifest {
+    /// The file name of the pulses.bin file that was split
+    pub source: String,
+    /// The metadata of the original file, exactly as it was stored
+    pub metadata: String,
+    /// The total number of apertures in all shards
+    pub num_apertures: usize,
+    /// The shards, in the order of the apertures they contain
+    pub shards: Vec<ShardInfo>,
+}
+
+/// A single shard of a pulses.bin file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShardInfo {
+    /// The file name of the shard, relative to the manifest
+    pub file_name: String,
+    /// The index of the first aperture in the shard
+    pub first_aperture: usize,
+    /// The index of the last aperture in the shard
+    pub last_aperture: usize,
+    /// The number of apertures in the shard
+    pub num_apertures: usize,
+    /// The size of the apertures in the shard, in bytes
+    pub num_bytes: u64,
+}
+
+impl ShardManifest {
+    /// Reads a manifest from a JSON file
+    pub fn load<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        let contents = fs::read_to_string(&file_name).map_err(PulseError::Io)?;
+        serde_json::from_str(&contents).map_err(|e| {
+            anyhow!(
+                "Failed to parse shard manifest {}: {}",
+                file_name.as_ref().display(),
+                e
+            )
+        })
+    }
+
+    /// Serializes the manifest as a JSON string
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl PulseReader {
+    /// Split this file into shards of roughly equal size, for processing in parallel
+    ///
+    /// The apertures are divided into `num_shards` contiguous groups in index order,
+    /// balanced by the number of bytes they take up on disk rather than by the number of
+    /// apertures. Each group is written to `output_dir` with `copy_apertures_to_new_file`,
+    /// as a standalone pulses.bin file named after this file with a `_shard_NNN` suffix, so
+    /// its apertures keep their positions and well IDs.
+    ///
+    /// A manifest describing the shards is written to `output_dir` with a `_shards.json`
+    /// suffix, and returned. It holds the original metadata and the range of apertures in
+    /// each shard, which `merge_shards` uses to reassemble exactly the file that was split.
+    ///
+    /// Returns an error if `num_shards` is 0 or larger than the number of apertures.
+    /// Existing files are handled according to [`OverwritePolicy`], and nothing is
+    /// written if any of the files already exist and may not be replaced. If a later file
+    /// can't be written, the shards created by this call are removed, but files that
+    /// existed before it are left in place.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::shard::merge_shards;
+    /// use qsi_pulse_reader::pulse_reader::writer::OverwritePolicy;
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let manifest = pulse_reader
+    ///     .split_into_shards(3, temp_dir.path(), OverwritePolicy::Fail)
+    ///     .unwrap();
+    /// assert_eq!(manifest.shards.len(), 3);
+    ///
+    /// let manifest_path = temp_dir.path().join("pulses_shards.json");
+    /// let merged_path = temp_dir.path().join("merged.pulses.bin");
+    /// merge_shards(&manifest_path, &merged_path, OverwritePolicy::Fail).unwrap();
+    ///
+    /// let merged_reader = PulseReader::open(&merged_path).unwrap();
+    /// assert_eq!(merged_reader.raw_metadata, pulse_reader.raw_metadata);
+    /// ```
+    pub fn split_into_shards<P: AsRef<Path>>(
+        &self,
+        num_shards: usize,
+        output_dir: P,
+        overwrite: OverwritePolicy,
+    ) -> Result<ShardManifest> {
+        let apertures = &self.index.apertures;
+        if num_shards == 0 || num_shards > apertures.len() {
+            return Err(PulseError::invalid_argument(format!(
+                "Cannot split {} apertures into {} shards",
+                apertures.len(),
+                num_shards
+            ))
+            .into());
+        }
+        let aperture_bytes: Vec<u64> = self
+            .aperture_headers()?
+            .num_pulses
+            .iter()
+            .map(|&num_pulses| (READ_HEADER_SIZE + PULSE_SIZE * num_pulses as usize) as u64)
+            .collect();
+        let cuts = balance_bytes(&aperture_bytes, num_shards);
+
+        // Check every output before writing anything
+        let stem = self
+            .file_name
+            .file_stem()
+            .map_or("pulses".into(), |stem| stem.to_string_lossy());
+        let output_dir = output_dir.as_ref();
+        let shard_file_names: Vec<String> = (0..num_shards)
+            .map(|shard| format!("{}_shard_{:03}.bin", stem, shard))
+            .collect();
+        let manifest_path = output_dir.join(format!("{}_shards.json", stem));
+        let mut existing: Vec<bool> = Vec::with_capacity(num_shards);
+        for file_name in shard_file_names.iter() {
+            let path = output_dir.join(file_name);
+            overwrite.check(&path)?;
+            existing.push(path.exists());
+        }
+        overwrite.check(&manifest_path)?;
+
+        // Don't leave a partial set of new shards behind if any of them can't be written.
+        // Files that were already there are never removed, even if they were replaced.
+        let mut written: Vec<PathBuf> = Vec::with_capacity(num_shards);
+        let result = (|| -> Result<ShardManifest> {
+            let mut shards = Vec::with_capacity(num_shards);
+            for (shard, file_name) in shard_file_names.into_iter().enumerate() {
+                let (start, end) = (cuts[shard], cuts[shard + 1]);
+                let path = output_dir.join(&file_name);
+                self.copy_apertures_to_new_file(&apertures[start..end], &path, overwrite)?;
+                if !existing[shard] {
+                    written.push(path);
+                }
+
+                shards.push(ShardInfo {
+                    file_name,
+                    first_aperture: apertures[start],
+                    last_aperture: apertures[end - 1],
+                    num_apertures: end - start,
+                    num_bytes: aperture_bytes[start..end].iter().sum(),
+                });
+            }
+
+            let manifest = ShardManifest {
+                source: self.file_name.to_string_lossy().into_owned(),
+                metadata: self.raw_metadata.clone(),
+                num_apertures: apertures.len(),
+                shards,
+            };
+            write_file_atomically(&manifest_path, manifest.to_json()?.as_bytes(), overwrite)?;
+            Ok(manifest)
+        })();
+        if result.is_err() {
+            for path in written {
+                let _ = fs::remove_file(path);
+            }
+        }
+        result
+    }
+}
+
+/// Divides apertures of the given sizes into `num_shards` contiguous, non-empty groups of
+/// similar size
+///
+/// Returns the index of the first aperture of each group, followed by the number of
+/// apertures.
+fn balance_bytes(aperture_bytes: &[u64], num_shards: usize) -> Vec<usize> {
+    let mut cumulative_bytes = vec![0u64];
+    for bytes in aperture_bytes {
+        cumulative_bytes.push(cumulative_bytes[cumulative_bytes.len() - 1] + bytes);
+    }
+    let total_bytes = cumulative_bytes[aperture_bytes.len()];
+
+    let mut cuts = vec![0];
+    for shard in 1..num_shards {
+        // Cut at the aperture boundary closest to an equal share of the bytes, leaving at
+        // least one aperture for this and every remaining shard
+        let target = total_bytes as f64 * shard as f64 / num_shards as f64;
+        let after = cumulative_bytes.partition_point(|&bytes| (bytes as f64) < target);
+        let cut = if after > 0
+            && target - cumulative_bytes[after - 1] as f64
+                <= cumulative_bytes
+                    .get(after)
+                    .map_or(f64::MAX, |&b| b as f64 - target)
+        {
+            after - 1
+        } else {
+            after
+        };
+        let min_cut = cuts[shard - 1] + 1;
+        let max_cut = aperture_bytes.len() - (num_shards - shard);
+        cuts.push(cut.clamp(min_cut, max_cut));
+    }
+    cuts.push(aperture_bytes.len());
+    cuts
+}
+
+/// Reassemble the shards described by a manifest into a single pulses.bin file
+///
+/// Every aperture of every shard is copied, in the order of the manifest, with its
+/// position and well ID unchanged, and the metadata of the new file is the metadata of the
+/// original file, so the new file contains exactly the same metadata and apertures as the
+/// file that was split. Returns an error if a shard doesn't hold the apertures listed for
+/// it in the manifest, or if the shards have different encodings. An existing file is
+/// handled according to [`OverwritePolicy`].
+pub fn merge_shards<P: AsRef<Path>, Q: AsRef<Path>>(
+    manifest_file: P,
+    new_file_name: Q,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    let manifest = ShardManifest::load(&manifest_file)?;
+    let dir = manifest_file
+        .as_ref()
+        .parent()
+        .map_or_else(PathBuf::new, Path::to_path_buf);
+    if manifest.shards.is_empty() {
+        return Err(PulseError::invalid_argument("The manifest lists no shards").into());
+    }
+    overwrite.check(&new_file_name)?;
+
+    let mut pulse_files = Vec::with_capacity(manifest.shards.len());
+    let mut last_aperture: Option<usize> = None;
+    for shard in manifest.shards.iter() {
+        let pulse_file = PulseReader::open(dir.join(&shard.file_name))?;
+        let apertures = &pulse_file.index.apertures;
+        if apertures.len() != shard.num_apertures
+            || apertures.first() != Some(&shard.first_aperture)
+            || apertures.last() != Some(&shard.last_aperture)
+            || last_aperture.is_some_and(|last| shard.first_aperture <= last)
+        {
+            return Err(anyhow!(
+                "Shard {} does not hold the {} apertures from {} to {} listed in the manifest",
+                shard.file_name,
+                shard.num_apertures,
+                shard.first_aperture,
+                shard.last_aperture
+            ));
+        }
+        last_aperture = Some(shard.last_aperture);
+        pulse_files.push(pulse_file);
+    }
+    check_inputs(&pulse_files, record_properties, |_| Vec::new())?;
+
+    let mut writer = PulseFileWriter::create_with_raw_metadata(
+        new_file_name,
+        &manifest.metadata,
+        &pulse_files[0].record_types,
+        overwrite,
+    )?;
+    for pulse_file in pulse_files.iter() {
+        for &ap in pulse_file.index.apertures.iter() {
+            writer.copy_aperture(pulse_file, &pulse_file.get_aperture_header(ap)?)?;
+        }
+    }
+    writer.finish()
+}
@@ -0,0 +1,190 @@
+use crate::pulse_reader::PulseReader;
+
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+
+/// A lookup of the apertures in a pulses.bin file by their position on the chip
+///
+/// The position of an aperture is only stored in its header, so building the index reads
+/// the header of every aperture in the file. `PulseReader::spatial_index` builds it the
+/// first time it is needed and keeps it for later lookups. Positions are given as `x`
+/// (column) and `y` (row), matching `ApertureHeader.x` and `ApertureHeader.y`.
+///
+/// # Examples
+/// ```
+/// # use qsi_pulse_reader::pulse_reader::PulseReader;
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+/// let spatial_index = pulse_reader.spatial_index().unwrap();
+///
+/// assert_eq!(spatial_index.get_by_xy(547, 408), Some(836131));
+/// assert_eq!(spatial_index.position(836131), Some((547, 408)));
+/// assert_eq!(spatial_index.get_by_xy(0, 0), None);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SpatialIndex {
+    /// Aperture index of each position, keyed by (y, x) so that rows are contiguous
+    by_position: BTreeMap<(u32, u32), usize>,
+    /// Position of each aperture index, as (x, y)
+    positions: HashMap<usize, (u32, u32)>,
+    /// The smallest rectangle containing every aperture, as (min_x, min_y, max_x, max_y)
+    bounds: Option<(u32, u32, u32, u32)>,
+}
+
+impl SpatialIndex {
+    /// Builds the index by reading the header of every aperture in a file
+    pub fn build(pulse_file: &PulseReader) -> Result<Self> {
+        let mut spatial_index = SpatialIndex::default();
+        for (ap, header) in pulse_file.aperture_headers()?.iter() {
+            spatial_index.by_position.insert((header.y, header.x), ap);
+            spatial_index.positions.insert(ap, (header.x, header.y));
+            spatial_index.bounds = Some(match spatial_index.bounds {
+                Some((min_x, min_y, max_x, max_y)) => (
+                    min_x.min(header.x),
+                    min_y.min(header.y),
+                    max_x.max(header.x),
+                    max_y.max(header.y),
+                ),
+                None => (header.x, header.y, header.x, header.y),
+            });
+        }
+        Ok(spatial_index)
+    }
+
+    /// The number of apertures in the index
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Whether the index has no apertures
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// The aperture at column `x` and row `y`, if there is one in the file
+    pub fn get_by_xy(&self, x: u32, y: u32) -> Option<usize> {
+        self.by_position.get(&(y, x)).copied()
+    }
+
+    /// The (x, y) position of an aperture, if it is in the file
+    pub fn position(&self, aperture: usize) -> Option<(u32, u32)> {
+        self.positions.get(&aperture).copied()
+    }
+
+    /// The apertures inside a rectangle, including its edges, in row-major order
+    pub fn in_rect(&self, min_x: u32, min_y: u32, max_x: u32, max_y: u32) -> Vec<usize> {
+        self.rect_positions(min_x, min_y, max_x, max_y)
+            .map(|(_, ap)| ap)
+            .collect()
+    }
+
+    /// The apertures in the 8 positions surrounding column `x` and row `y`, in row-major order
+    ///
+    /// The aperture at (x, y) itself is not included, and need not be in the file.
+    pub fn neighbors(&self, x: u32, y: u32) -> Vec<usize> {
+        self.rect_positions(
+            x.saturating_sub(1),
+            y.saturating_sub(1),
+            x.saturating_add(1),
+            y.saturating_add(1),
+        )
+        .filter(|&(position, _)| position != (x, y))
+        .map(|(_, ap)| ap)
+        .collect()
+    }
+
+    /// The `k` apertures closest to column `x` and row `y`, nearest first
+    ///
+    /// Distances are Euclidean, and apertures at the same distance are ordered by row and
+    /// then by column. The aperture at (x, y) itself is not included. Fewer than `k`
+    /// apertures are returned if there aren't enough in the file.
+    pub fn nearest(&self, x: u32, y: u32, k: usize) -> Vec<usize> {
+        let Some((min_x, min_y, max_x, max_y)) = self.bounds else {
+            return Vec::new();
+        };
+        // The furthest any aperture can be in either direction
+        let max_radius = [
+            x.abs_diff(min_x),
+            x.abs_diff(max_x),
+            y.abs_diff(min_y),
+            y.abs_diff(max_y),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0);
+
+        // Search squares of doubling size until they contain the k nearest apertures.
+        // Every aperture within `radius` of (x, y) is inside the square, so once there are
+        // k of them, no aperture outside the square can be nearer.
+        let mut radius: u32 = 1;
+        loop {
+            let mut candidates: Vec<(u64, (u32, u32), usize)> = self
+                .rect_positions(
+                    x.saturating_sub(radius),
+                    y.saturating_sub(radius),
+                    x.saturating_add(radius),
+                    y.saturating_add(radius),
+                )
+                .filter(|&(position, _)| position != (x, y))
+                .map(|((ap_x, ap_y), ap)| {
+                    let (dx, dy) = (ap_x.abs_diff(x) as u64, ap_y.abs_diff(y) as u64);
+                    (dx * dx + dy * dy, (ap_y, ap_x), ap)
+                })
+                .collect();
+            let radius_squared = radius as u64 * radius as u64;
+            let num_within = candidates
+                .iter()
+                .filter(|(distance, ..)| *distance <= radius_squared)
+                .count();
+            if num_within >= k || radius >= max_radius {
+                candidates.sort_unstable();
+                return candidates
+                    .into_iter()
+                    .take(k)
+                    .map(|(_, _, ap)| ap)
+                    .collect();
+            }
+            radius = radius.saturating_mul(2);
+        }
+    }
+
+    /// The (x, y) positions and apertures inside a rectangle, in row-major order
+    fn rect_positions(
+        &self,
+        min_x: u32,
+        min_y: u32,
+        max_x: u32,
+        max_y: u32,
+    ) -> impl Iterator<Item = ((u32, u32), usize)> + '_ {
+        // Only visit the rows that have apertures, and the columns of the rectangle in each
+        let rows = self
+            .bounds
+            .filter(|_| min_x <= max_x)
+            .map(|(_, first_row, _, last_row)| min_y.max(first_row)..=max_y.min(last_row));
+        rows.into_iter().flatten().flat_map(move |y| {
+            self.by_position
+                .range((y, min_x)..=(y, max_x))
+                .map(|(&(y, x), &ap)| ((x, y), ap))
+        })
+    }
+}
+
+impl PulseReader {
+    /// The spatial index of the apertures in this file
+    ///
+    /// The index is built from the aperture headers the first time this is called, and
+    /// the same index is returned by every later call.
+    pub fn spatial_index(&self) -> Result<&SpatialIndex> {
+        if let Some(spatial_index) = self.spatial_index.get() {
+            return Ok(spatial_index);
+        }
+        // Another thread may build the index at the same time, in which case the first
+        // one to finish is kept
+        let spatial_index = SpatialIndex::build(self)?;
+        Ok(self.spatial_index.get_or_init(|| spatial_index))
+    }
+}
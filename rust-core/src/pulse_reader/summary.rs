@@ -0,0 +1,286 @@
+use crate::pulse_filter::PulseFilter;
+use crate::pulse_reader::PulseReader;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::ApertureHeader;
+use crate::pulse_reader::records::{
+    FormattedRecord, FormattedRecordType, NormalizedPulse, PulseNormalizer,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Summary statistics of the pulses and records of one aperture
+///
+/// The pulse statistics describe the normalized pulses of the aperture, after the pulse
+/// filter if one was used. The interpulse durations leave out the first pulse if it has
+/// no interpulse duration, because it is the first pulse of the aperture, or because the
+/// filter recalculates them. Statistics of an aperture without any pulses are NaN. Quantiles
+/// interpolate linearly between the nearest values, as `numpy.quantile` and R's `quantile`
+/// do by default.
+///
+/// The record counts describe every record of the aperture in the file, whether or not
+/// it passes the pulse filter.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ApertureSummary {
+    pub aperture_index: usize,
+    pub x: u32,
+    pub y: u32,
+    /// Number of pulses
+    pub num_pulses: usize,
+    /// Pulses per second of the run
+    pub pulse_rate: f64,
+    pub mean_dur_s: f64,
+    pub median_dur_s: f64,
+    /// Mean interpulse duration, excluding a first pulse without a previous pulse
+    pub mean_ipd_s: f64,
+    /// Median interpulse duration, excluding a first pulse without a previous pulse
+    pub median_ipd_s: f64,
+    pub intensity_q10: f64,
+    pub intensity_q50: f64,
+    pub intensity_q90: f64,
+    pub binratio_q10: f64,
+    pub binratio_q50: f64,
+    pub binratio_q90: f64,
+    pub median_snr: f64,
+    /// Time at which the first pulse starts, in seconds from the start of the run
+    pub first_pulse_s: f64,
+    /// Time at which the last pulse ends, in seconds from the start of the run
+    pub last_pulse_s: f64,
+    /// Fraction of the frames of the run covered by a pulse
+    pub on_time_fraction: f64,
+    pub num_pulse_records: usize,
+    pub num_padding_records: usize,
+    pub num_long_pulse_update_records: usize,
+    pub num_long_pulse_dropped_records: usize,
+    pub num_step_up_records: usize,
+    pub num_step_down_records: usize,
+    pub num_background_records: usize,
+    pub num_unknown_records: usize,
+}
+
+impl ApertureSummary {
+    /// Summarizes an aperture from its records, in a single pass
+    ///
+    /// `run_dur_s` is the duration of the run, and `fps` its frame rate.
+    fn from_records<I>(
+        aperture_index: usize,
+        aperture_header: &ApertureHeader,
+        records: I,
+        pulse_filter: Option<&PulseFilter>,
+        fps: f32,
+        run_dur_s: f64,
+    ) -> Result<Self>
+    where
+        I: IntoIterator<Item = Result<FormattedRecord>>,
+    {
+        let mut summary = ApertureSummary {
+            aperture_index,
+            x: aperture_header.x,
+            y: aperture_header.y,
+            ..Default::default()
+        };
+        let mut normalizer = PulseNormalizer::new(fps);
+        let mut pulses = Vec::new();
+        for record in records {
+            let record = record?;
+            let count = match record.record_type {
+                FormattedRecordType::Pulse => &mut summary.num_pulse_records,
+                FormattedRecordType::Padding => &mut summary.num_padding_records,
+                FormattedRecordType::LongPulseUpdate => &mut summary.num_long_pulse_update_records,
+                FormattedRecordType::LongPulseDropped => {
+                    &mut summary.num_long_pulse_dropped_records
+                }
+                FormattedRecordType::StepUp => &mut summary.num_step_up_records,
+                FormattedRecordType::StepDown => &mut summary.num_step_down_records,
+                FormattedRecordType::Background => &mut summary.num_background_records,
+                FormattedRecordType::Unknown => &mut summary.num_unknown_records,
+            };
+            *count += 1;
+            pulses.extend(normalizer.push(&record));
+        }
+        let first_index = pulses.first().map(|pulse| pulse.index);
+        if let Some(pulse_filter) = pulse_filter {
+            pulses = pulse_filter.filter_pulses(&pulses, fps)?;
+        }
+        // Unless they are recalculated, the filtered pulses keep the interpulse durations
+        // of the aperture, where only the first pulse has none
+        let has_first_ipd = pulse_filter.is_none_or(|pulse_filter| !pulse_filter.recalc_ipd)
+            && pulses.first().map(|pulse| pulse.index) != first_index;
+        summary.summarize_pulses(&pulses, has_first_ipd, fps, run_dur_s);
+        Ok(summary)
+    }
+
+    /// Fills in the pulse statistics
+    ///
+    /// `has_first_ipd` is whether the interpulse duration of the first pulse is defined.
+    fn summarize_pulses(
+        &mut self,
+        pulses: &[NormalizedPulse],
+        has_first_ipd: bool,
+        fps: f32,
+        run_dur_s: f64,
+    ) {
+        let column = |value: fn(&NormalizedPulse) -> f32, skip: usize| {
+            let mut values: Vec<f64> = pulses
+                .iter()
+                .skip(skip)
+                .map(|pulse| value(pulse) as f64)
+                .collect();
+            values.sort_unstable_by(f64::total_cmp);
+            values
+        };
+        let dur_s = column(|pulse| pulse.dur_s, 0);
+        let ipd_s = column(|pulse| pulse.ipd_s, if has_first_ipd { 0 } else { 1 });
+        let intensity = column(|pulse| pulse.intensity, 0);
+        let binratio = column(|pulse| pulse.binratio, 0);
+        let snr = column(|pulse| pulse.snr, 0);
+
+        self.num_pulses = pulses.len();
+        self.pulse_rate = pulses.len() as f64 / run_dur_s;
+        self.mean_dur_s = mean(&dur_s);
+        self.median_dur_s = quantile(&dur_s, 0.5);
+        self.mean_ipd_s = mean(&ipd_s);
+        self.median_ipd_s = quantile(&ipd_s, 0.5);
+        self.intensity_q10 = quantile(&intensity, 0.1);
+        self.intensity_q50 = quantile(&intensity, 0.5);
+        self.intensity_q90 = quantile(&intensity, 0.9);
+        self.binratio_q10 = quantile(&binratio, 0.1);
+        self.binratio_q50 = quantile(&binratio, 0.5);
+        self.binratio_q90 = quantile(&binratio, 0.9);
+        self.median_snr = quantile(&snr, 0.5);
+        let frame_to_s = |frame: u32| frame as f64 / fps as f64;
+        self.first_pulse_s = pulses.first().map_or(f64::NAN, |p| frame_to_s(p.start_f));
+        self.last_pulse_s = pulses.last().map_or(f64::NAN, |p| frame_to_s(p.end_f));
+        let on_frames: u64 = pulses.iter().map(|pulse| pulse.dur_f as u64).sum();
+        self.on_time_fraction = on_frames as f64 / (run_dur_s * fps as f64).ceil();
+    }
+}
+
+/// The mean of a list of values, or NaN if it is empty
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// The `q` quantile of a sorted list of values, or NaN if it is empty
+///
+/// Interpolates linearly between the two nearest values.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let position = q * (sorted.len() - 1) as f64;
+    let (below, above) = (position.floor() as usize, position.ceil() as usize);
+    sorted[below] + (sorted[above] - sorted[below]) * (position - below as f64)
+}
+
+impl PulseReader {
+    /// Compute summary statistics of the pulses and records of one aperture
+    ///
+    /// The records are streamed from the file in chunks, as by `iter_aperture_records`.
+    /// Pulses that fail `pulse_filter` are left out of the pulse statistics. Requires a
+    /// positive `duration` in the metadata.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// let summary = pulse_reader.summarize_aperture(836131, None).unwrap();
+    /// assert_eq!(summary.num_pulses, summary.num_pulse_records);
+    /// println!("{} pulses per second", summary.pulse_rate);
+    /// ```
+    pub fn summarize_aperture(
+        &self,
+        aperture: usize,
+        pulse_filter: Option<&PulseFilter>,
+    ) -> Result<ApertureSummary> {
+        let run_dur_s = self.run_dur_s()?;
+        let (records, aperture_header) = self.iter_aperture_records(aperture)?;
+        ApertureSummary::from_records(
+            aperture,
+            &aperture_header,
+            records,
+            pulse_filter,
+            self.fps,
+            run_dur_s,
+        )
+    }
+
+    /// Compute summary statistics of the pulses and records of every aperture
+    ///
+    /// Reads the file sequentially in a single pass, as by `iter_raw_apertures`, and
+    /// returns the summaries in index order, matching `index.apertures`. See
+    /// `summarize_aperture`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_filter::PulseFilter;
+    /// # use std::path::PathBuf;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    ///
+    /// let pulse_filter = PulseFilter {
+    ///     min_dur_f: Some(3),
+    ///     ..Default::default()
+    /// };
+    /// let summaries = pulse_reader.summarize_apertures(Some(&pulse_filter)).unwrap();
+    /// assert_eq!(summaries.len(), pulse_reader.index.apertures.len());
+    /// ```
+    pub fn summarize_apertures(
+        &self,
+        pulse_filter: Option<&PulseFilter>,
+    ) -> Result<Vec<ApertureSummary>> {
+        let run_dur_s = self.run_dur_s()?;
+
+        // Apertures are read in on-disk order, so find the row of each one in the index
+        let mut rows = self
+            .index
+            .apertures
+            .iter()
+            .enumerate()
+            .map(|(row, &ap)| Ok((self.index.get(ap)?, row)))
+            .collect::<Result<Vec<_>, PulseError>>()?;
+        rows.sort_unstable();
+
+        let mut summaries: Vec<Option<ApertureSummary>> = (0..rows.len()).map(|_| None).collect();
+        for ((_, row), item) in rows.into_iter().zip(self.iter_raw_apertures()?) {
+            let (raw_records, aperture_header) = item?;
+            let records = raw_records.iter().enumerate().map(|(idx, raw_record)| {
+                Ok(FormattedRecord::from_raw_with_encoding(
+                    raw_record,
+                    &self.encoding,
+                    idx,
+                ))
+            });
+            summaries[row] = Some(ApertureSummary::from_records(
+                self.index.apertures[row],
+                &aperture_header,
+                records,
+                pulse_filter,
+                self.fps,
+                run_dur_s,
+            )?);
+        }
+        Ok(summaries.into_iter().flatten().collect())
+    }
+
+    /// The duration of the run in seconds, from the metadata
+    ///
+    /// The rates in a summary are divided by the duration, so it must be positive.
+    fn run_dur_s(&self) -> Result<f64, PulseError> {
+        self.run_metadata
+            .duration
+            .filter(|duration| duration.is_finite() && *duration > 0.0)
+            .ok_or_else(|| PulseError::metadata_field("duration"))
+    }
+}
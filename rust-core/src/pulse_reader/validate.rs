@@ -0,0 +1,524 @@
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::{ApertureHeader, PulseFileHeader, RecordEncoding};
+use crate::pulse_reader::metadata::RunMetadata;
+use crate::pulse_reader::read_exact_at;
+use crate::pulse_reader::records::RawRecord;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Metadata fields that must be present, and numeric, in every pulses.bin file
+pub const REQUIRED_METADATA_FIELDS: [&str; 11] = [
+    "fps",
+    "duration",
+    "rows",
+    "cols",
+    "roi_rows",
+    "roi_cols",
+    "roi_offset_row",
+    "roi_offset_col",
+    "validWells",
+    "validWellsLeft",
+    "validWellsRight",
+];
+
+/// The category of a problem found by `validate_file`
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// The file header has a bad magic number or describes an unsupported encoding
+    InvalidHeader,
+    /// The file ends before a section described by the header
+    Truncated,
+    /// The section offsets in the header are inconsistent with each other or the file size
+    InvalidOffsets,
+    /// The metadata is not valid JSON, or a required field is missing or invalid
+    InvalidMetadata,
+    /// The index section does not start with the index magic number
+    IndexMagicMismatch,
+    /// The index entries are not sorted by aperture index
+    IndexUnsorted,
+    /// An aperture index appears more than once in the index
+    IndexDuplicate,
+    /// An index entry points outside of the data section
+    ByteLocOutOfRange,
+    /// The records of an aperture extend past the end of the data section
+    RecordsOutOfRange,
+    /// The well_id in an aperture header does not match its index entry
+    WellIdMismatch,
+    /// Two apertures occupy overlapping bytes of the data section
+    ApertureOverlap,
+    /// Bytes of the data section are not part of any aperture
+    DataGap,
+    /// The records of an aperture extend past the duration of the run
+    FrameOutOfRange,
+}
+
+/// A single problem found by `validate_file`
+#[derive(Serialize, Debug, Clone)]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    /// The aperture index the problem relates to, if any
+    pub aperture: Option<usize>,
+    /// The byte offset in the file at which the problem was found, if any
+    pub offset: Option<u64>,
+    pub message: String,
+}
+
+/// A report of every problem found in a pulses.bin file
+///
+/// Serializes to JSON with `to_json`, e.g. for consumption by other services.
+#[derive(Serialize, Debug, Clone)]
+pub struct ValidationReport {
+    pub file_name: PathBuf,
+    pub file_size: u64,
+    pub num_apertures: u64,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// Whether no problems were found
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Serializes the report as a JSON string
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    fn push(
+        &mut self,
+        kind: IssueKind,
+        aperture: Option<usize>,
+        offset: Option<u64>,
+        message: String,
+    ) {
+        self.issues.push(ValidationIssue {
+            kind,
+            aperture,
+            offset,
+            message,
+        });
+    }
+}
+
+/// Deeply validates the structure of a pulses.bin file
+///
+/// Unlike `PulseReader::open`, which stops at the first problem it encounters, this
+/// checks every section of the file and collects every problem found into a
+/// `ValidationReport`. The following are checked:
+/// - the file header, and that the section offsets it contains are consistent
+/// - that the metadata is valid JSON and contains every `REQUIRED_METADATA_FIELDS`
+/// - that the index is sorted and contains each aperture index only once
+/// - that every index entry lies within the data section, and that the aperture header
+///   it points to has a matching well_id and records that fit within the data section
+/// - that the apertures exactly tile the data section, without gaps or overlaps
+/// - that no aperture has records past the end of the run, given its `duration`
+///
+/// Problems that prevent later sections from being located, such as a truncated header,
+/// end validation early. An error is only returned if the file cannot be read at all.
+///
+/// # Examples
+/// ```
+/// use qsi_pulse_reader::pulse_reader::validate::validate_file;
+/// # use std::path::PathBuf;
+///
+/// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+/// # let pulse_file_path = path.join("../example_files/pulses.bin");
+/// let report = validate_file(pulse_file_path).unwrap();
+/// assert!(report.is_valid(), "{}", report.to_json().unwrap());
+/// ```
+pub fn validate_file<P: AsRef<Path>>(file_name: P) -> Result<ValidationReport> {
+    let file = File::open(file_name.as_ref()).map_err(PulseError::Io)?;
+    let file_size = file.metadata().map_err(PulseError::Io)?.len();
+    let mut report = ValidationReport {
+        file_name: file_name.as_ref().to_path_buf(),
+        file_size,
+        num_apertures: 0,
+        issues: Vec::new(),
+    };
+    validate_sections(&file, &mut report)?;
+    Ok(report)
+}
+
+/// Validates each section of the file, returning early if a section can't be located
+///
+/// Every read is bounds checked against the file size first, so any error returned
+/// from here is a genuine I/O error rather than a problem with the file.
+fn validate_sections(file: &File, report: &mut ValidationReport) -> Result<()> {
+    let file_size = report.file_size;
+
+    // Header
+    let (header, record_types) = match PulseFileHeader::read(&mut &*file) {
+        Ok(sections) => sections,
+        Err(e) => {
+            let kind = match e.downcast_ref::<PulseError>() {
+                Some(PulseError::Truncated { .. }) => IssueKind::Truncated,
+                Some(PulseError::BadMagic { .. })
+                | Some(PulseError::UnsupportedEncoding { .. }) => IssueKind::InvalidHeader,
+                _ => return Err(e),
+            };
+            report.push(kind, None, Some(0), e.to_string());
+            return Ok(());
+        }
+    };
+    // The record layout is fixed, so the rest of the file can be checked even if the
+    // encoding table is invalid
+    if let Err(e) = RecordEncoding::new(&record_types) {
+        report.push(IssueKind::InvalidHeader, None, Some(0), e.to_string());
+    }
+    if header.record_header_size != READ_HEADER_SIZE as u32 {
+        report.push(
+            IssueKind::InvalidHeader,
+            None,
+            Some(0),
+            format!(
+                "Unsupported record header size: {}",
+                header.record_header_size
+            ),
+        );
+        return Ok(());
+    }
+    report.num_apertures = header.num_reads;
+
+    // Section offsets
+    let metadata_offset = header.metadata_offset();
+    let metadata_end = metadata_offset + header.metadata_length as u64;
+    if metadata_end > file_size {
+        report.push(
+            IssueKind::Truncated,
+            None,
+            Some(metadata_offset),
+            format!(
+                "Metadata ends at byte {}, past the end of the file",
+                metadata_end
+            ),
+        );
+        return Ok(());
+    }
+    if header.data_offset < metadata_end {
+        report.push(
+            IssueKind::InvalidOffsets,
+            None,
+            Some(header.data_offset),
+            format!(
+                "Data section starts at byte {}, before the end of the metadata at byte {}",
+                header.data_offset, metadata_end
+            ),
+        );
+    }
+
+    // Metadata
+    let mut metadata_buffer = vec![0; header.metadata_length as usize];
+    read_exact_at(file, &mut metadata_buffer, metadata_offset)?;
+    let metadata = validate_metadata(&metadata_buffer, metadata_offset, report);
+    let num_frames = metadata.and_then(|metadata| {
+        let fps = metadata.fps?;
+        let duration = metadata.duration?;
+        Some((duration * fps).ceil() as u64)
+    });
+
+    // Index
+    if header.index_offset < header.data_offset.max(metadata_end) {
+        report.push(
+            IssueKind::InvalidOffsets,
+            None,
+            Some(header.index_offset),
+            format!(
+                "Index starts at byte {}, before the start of the data section",
+                header.index_offset
+            ),
+        );
+        return Ok(());
+    }
+    // The index offset and number of reads come straight from the header, so may be
+    // large enough to overflow
+    let index_start = header.index_offset.checked_add(8);
+    let index_end = index_start.and_then(|start| {
+        header
+            .num_reads
+            .checked_mul(INDEX_RECORD_SIZE as u64)
+            .and_then(|length| start.checked_add(length))
+    });
+    let (Some(index_start), Some(index_end)) = (index_start, index_end) else {
+        report.push(
+            IssueKind::InvalidOffsets,
+            None,
+            Some(header.index_offset),
+            format!(
+                "Index of {} apertures at byte {} extends past the largest possible file size",
+                header.num_reads, header.index_offset
+            ),
+        );
+        return Ok(());
+    };
+    if index_end > file_size {
+        report.push(
+            IssueKind::Truncated,
+            None,
+            Some(header.index_offset),
+            format!(
+                "Index of {} apertures ends at byte {}, past the end of the file",
+                header.num_reads, index_end
+            ),
+        );
+        return Ok(());
+    } else if index_end < file_size {
+        report.push(
+            IssueKind::InvalidOffsets,
+            None,
+            Some(index_end),
+            format!("{} unexpected bytes after the index", file_size - index_end),
+        );
+    }
+    let mut index_magic_buffer = [0; 8];
+    read_exact_at(file, &mut index_magic_buffer, header.index_offset)?;
+    let index_magic = u64::from_le_bytes(index_magic_buffer);
+    if index_magic != INDEX_SECTION_MAGIC {
+        report.push(
+            IssueKind::IndexMagicMismatch,
+            None,
+            Some(header.index_offset),
+            PulseError::IndexMagicMismatch { magic: index_magic }.to_string(),
+        );
+        return Ok(());
+    }
+    // The index fits within the file, so its length fits in memory
+    let mut index_buffer = vec![0; (index_end - index_start) as usize];
+    read_exact_at(file, &mut index_buffer, index_start)?;
+    // chunks_exact guarantees that every chunk is exactly INDEX_RECORD_SIZE bytes long
+    let entries: Vec<(usize, u64)> = index_buffer
+        .chunks_exact(INDEX_RECORD_SIZE)
+        .map(|buffer| {
+            (
+                u32::from_le_bytes(buffer[0..4].try_into().unwrap()) as usize,
+                u64::from_le_bytes(buffer[4..12].try_into().unwrap()),
+            )
+        })
+        .collect();
+    let mut seen: HashSet<usize> = HashSet::with_capacity(entries.len());
+    for (idx, &(ap, _)) in entries.iter().enumerate() {
+        let offset = Some(index_start + (idx * INDEX_RECORD_SIZE) as u64);
+        if idx > 0 && ap < entries[idx - 1].0 {
+            report.push(
+                IssueKind::IndexUnsorted,
+                Some(ap),
+                offset,
+                format!(
+                    "Aperture {} follows aperture {} in the index",
+                    ap,
+                    entries[idx - 1].0
+                ),
+            );
+        }
+        if !seen.insert(ap) {
+            report.push(
+                IssueKind::IndexDuplicate,
+                Some(ap),
+                offset,
+                format!("Aperture {} appears more than once in the index", ap),
+            );
+        }
+    }
+
+    // Apertures
+    let data_range = header.data_offset..header.index_offset;
+    let mut blocks: Vec<(u64, u64, usize)> = Vec::with_capacity(entries.len());
+    let mut header_buffer = [0; READ_HEADER_SIZE];
+    let mut record_buffer = Vec::new();
+    for &(ap, byte_loc) in entries.iter() {
+        if !data_range.contains(&byte_loc) {
+            report.push(
+                IssueKind::ByteLocOutOfRange,
+                Some(ap),
+                Some(byte_loc),
+                format!(
+                    "Aperture {} starts at byte {}, outside of the data section [{}, {})",
+                    ap, byte_loc, data_range.start, data_range.end
+                ),
+            );
+            continue;
+        }
+        if byte_loc + READ_HEADER_SIZE as u64 > data_range.end {
+            report.push(
+                IssueKind::RecordsOutOfRange,
+                Some(ap),
+                Some(byte_loc),
+                format!("Header of aperture {} overlaps the index", ap),
+            );
+            continue;
+        }
+        read_exact_at(file, &mut header_buffer, byte_loc)?;
+        let aperture_header = ApertureHeader::new(&header_buffer, byte_loc)?;
+        if aperture_header.well_id as usize != ap {
+            report.push(
+                IssueKind::WellIdMismatch,
+                Some(ap),
+                Some(byte_loc),
+                format!(
+                    "Index entry for aperture {} points to a header with well_id {}",
+                    ap, aperture_header.well_id
+                ),
+            );
+        }
+        let records_start = byte_loc + READ_HEADER_SIZE as u64;
+        let records_end = records_start + aperture_header.num_pulses as u64 * PULSE_SIZE as u64;
+        if records_end > data_range.end {
+            report.push(
+                IssueKind::RecordsOutOfRange,
+                Some(ap),
+                Some(byte_loc),
+                format!(
+                    "{} records of aperture {} end at byte {}, past the end of the data section",
+                    aperture_header.num_pulses, ap, records_end
+                ),
+            );
+            continue;
+        }
+        blocks.push((byte_loc, records_end, ap));
+
+        if let Some(num_frames) = num_frames {
+            let last_frame = count_frames(
+                file,
+                records_start,
+                aperture_header.num_pulses as usize,
+                &mut record_buffer,
+            )?;
+            if last_frame > num_frames {
+                report.push(
+                    IssueKind::FrameOutOfRange,
+                    Some(ap),
+                    Some(byte_loc),
+                    format!(
+                        "Records of aperture {} end at frame {}, past the end of the run at frame {}",
+                        ap, last_frame, num_frames
+                    ),
+                );
+            }
+        }
+    }
+
+    // Apertures must exactly tile the data section
+    blocks.sort_unstable();
+    let mut expected_start = header.data_offset;
+    for &(start, end, ap) in blocks.iter() {
+        if start > expected_start {
+            report.push(
+                IssueKind::DataGap,
+                None,
+                Some(expected_start),
+                format!(
+                    "Bytes {} to {} of the data section are not part of any aperture",
+                    expected_start, start
+                ),
+            );
+        } else if start < expected_start {
+            report.push(
+                IssueKind::ApertureOverlap,
+                Some(ap),
+                Some(start),
+                format!(
+                    "Aperture {} starts at byte {}, inside the preceding aperture",
+                    ap, start
+                ),
+            );
+        }
+        expected_start = expected_start.max(end);
+    }
+    if expected_start < header.index_offset {
+        report.push(
+            IssueKind::DataGap,
+            None,
+            Some(expected_start),
+            format!(
+                "Bytes {} to {} of the data section are not part of any aperture",
+                expected_start, header.index_offset
+            ),
+        );
+    }
+    Ok(())
+}
+
+/// Parses the metadata and checks that every required field is present
+///
+/// Returns the parsed run metadata, or None if it isn't valid JSON or can't be parsed as
+/// `RunMetadata`.
+fn validate_metadata(
+    buffer: &[u8],
+    offset: u64,
+    report: &mut ValidationReport,
+) -> Option<RunMetadata> {
+    let metadata = std::str::from_utf8(buffer)
+        .map_err(|e| e.to_string())
+        .and_then(|raw_metadata| {
+            serde_json::from_str::<Value>(raw_metadata).map_err(|e| e.to_string())
+        });
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            report.push(
+                IssueKind::InvalidMetadata,
+                None,
+                Some(offset),
+                format!("Failed to parse metadata: {}", e),
+            );
+            return None;
+        }
+    };
+    for name in REQUIRED_METADATA_FIELDS {
+        if !metadata[name].is_number() {
+            report.push(
+                IssueKind::InvalidMetadata,
+                None,
+                Some(offset),
+                PulseError::metadata_field(name).to_string(),
+            );
+        }
+    }
+    match RunMetadata::from_value(&metadata) {
+        Ok(run_metadata) => Some(run_metadata),
+        Err(e) => {
+            report.push(
+                IssueKind::InvalidMetadata,
+                None,
+                Some(offset),
+                e.to_string(),
+            );
+            None
+        }
+    }
+}
+
+/// Finds the frame at which the last record of an aperture ends
+///
+/// Equivalent to the end of the last record seen by `PulseNormalizer`, but reads the
+/// records in bounded chunks and sums frames without overflowing.
+fn count_frames(
+    file: &File,
+    mut offset: u64,
+    num_records: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<u64> {
+    let mut last_frame = 0u64;
+    let mut remaining = num_records * PULSE_SIZE;
+    while remaining > 0 {
+        let chunk_len = remaining.min(BUFFER_SIZE);
+        buffer.resize(chunk_len, 0);
+        read_exact_at(file, buffer, offset)?;
+        // chunks_exact guarantees that every chunk is exactly PULSE_SIZE bytes long
+        for record in buffer.chunks_exact(PULSE_SIZE) {
+            let record = RawRecord::from_bytes(record.try_into().unwrap());
+            last_frame += record.frames_since_last as u64 + record.duration as u64;
+        }
+        offset += chunk_len as u64;
+        remaining -= chunk_len;
+    }
+    Ok(last_frame)
+}
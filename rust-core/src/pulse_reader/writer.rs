@@ -0,0 +1,382 @@
+use crate::pulse_reader::constants::*;
+use crate::pulse_reader::error::PulseError;
+use crate::pulse_reader::headers::{ApertureHeader, PulseFileHeader, PulseRecordType};
+use crate::pulse_reader::records::RawRecord;
+use crate::pulse_reader::{PulseReader, ReadAt};
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufWriter, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+use tempfile::NamedTempFile;
+
+/// What to do when the output file of a write already exists
+///
+/// Every function that writes a new pulses.bin file takes an `overwrite` policy. The new
+/// file is written to a temporary file next to the output and only moved into place once
+/// it is complete, so an existing file is never left partially written, whichever policy
+/// is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Fail without writing anything if the file already exists
+    #[default]
+    Fail,
+    /// Replace the existing file once the new file has been completely written
+    Overwrite,
+}
+
+impl OverwritePolicy {
+    /// Returns `Overwrite` if `overwrite` is true, and `Fail` otherwise
+    pub fn from_bool(overwrite: bool) -> Self {
+        if overwrite {
+            OverwritePolicy::Overwrite
+        } else {
+            OverwritePolicy::Fail
+        }
+    }
+
+    /// Checks whether a file may be written to `file_name` under this policy
+    pub fn check<P: AsRef<Path>>(&self, file_name: P) -> Result<(), PulseError> {
+        if *self == OverwritePolicy::Fail && file_name.as_ref().exists() {
+            return Err(already_exists(file_name.as_ref()));
+        }
+        Ok(())
+    }
+}
+
+fn already_exists(file_name: &Path) -> PulseError {
+    PulseError::Io(std::io::Error::new(
+        ErrorKind::AlreadyExists,
+        format!("File {} already exists", file_name.display()),
+    ))
+}
+
+/// A pulses.bin writer
+///
+/// This struct is used to create a new pulses.bin file from scratch. The file header,
+/// record encodings and metadata are written when the writer is created, apertures are
+/// then appended to the data section one at a time, and the aperture index is written
+/// when the writer is finished.
+///
+/// Everything is written to a temporary file in the same directory as the output file,
+/// which is synced to disk and renamed into place by `finish`. The output file is never
+/// left partially written: if the writer is dropped before `finish` is called, or the
+/// process crashes, the temporary file is discarded and any existing file is untouched.
+pub struct PulseFileWriter {
+    file: BufWriter<NamedTempFile>,
+    file_name: PathBuf,
+    overwrite: OverwritePolicy,
+    header: PulseFileHeader,
+    offset: u64,
+    index: Vec<(u32, u64)>,
+    copy_buffer: Vec<u8>,
+}
+
+impl PulseFileWriter {
+    /// Creates a new pulses.bin file for writing
+    ///
+    /// Writes the file header, the record encodings and the metadata to a new file, padding
+    /// the end of the metadata so that the data section starts on a 16-byte boundary.
+    /// Returns an error if the file already exists and `overwrite` is `OverwritePolicy::Fail`.
+    ///
+    /// # Examples
+    /// ```
+    /// # use qsi_pulse_reader::pulse_reader::PulseReader;
+    /// use qsi_pulse_reader::pulse_reader::writer::{OverwritePolicy, PulseFileWriter};
+    /// # use std::path::PathBuf;
+    /// # use tempfile::tempdir;
+    ///
+    /// # let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    /// # let pulse_file_path = path.join("../example_files/pulses.bin");
+    /// # let temp_dir = tempdir().unwrap();
+    /// # let new_pulse_file_path = temp_dir.path().join("pulses_written.bin");
+    /// let pulse_reader = PulseReader::open(pulse_file_path).unwrap();
+    /// let mut writer = PulseFileWriter::create(
+    ///     &new_pulse_file_path,
+    ///     &pulse_reader.metadata,
+    ///     &pulse_reader.record_types,
+    ///     OverwritePolicy::Fail,
+    /// )
+    /// .unwrap();
+    ///
+    /// // Write the first aperture to the new file
+    /// let (records, aperture_header) = pulse_reader
+    ///     .get_raw_records(pulse_reader.index.apertures[0])
+    ///     .unwrap();
+    /// writer.write_aperture(&aperture_header, &records).unwrap();
+    /// writer.finish().unwrap();
+    /// ```
+    pub fn create<P: AsRef<Path>>(
+        file_name: P,
+        metadata: &Value,
+        record_types: &[PulseRecordType],
+        overwrite: OverwritePolicy,
+    ) -> Result<Self> {
+        Self::create_with_raw_metadata(file_name, &metadata.to_string(), record_types, overwrite)
+    }
+
+    /// Creates a new pulses.bin file for writing, with metadata that is already serialized
+    ///
+    /// Equivalent to `create`, but writes `raw_metadata` to the file as is, which preserves
+    /// the formatting of metadata copied from another file.
+    pub fn create_with_raw_metadata<P: AsRef<Path>>(
+        file_name: P,
+        raw_metadata: &str,
+        record_types: &[PulseRecordType],
+        overwrite: OverwritePolicy,
+    ) -> Result<Self> {
+        let file_name = file_name.as_ref().to_path_buf();
+        overwrite.check(&file_name)?;
+
+        let metadata_length = u32::try_from(raw_metadata.len()).map_err(|_| {
+            PulseError::invalid_argument(format!(
+                "Metadata of {} bytes is too long to store in a pulses.bin file",
+                raw_metadata.len()
+            ))
+        })?;
+        let num_encoding_records = u16::try_from(record_types.len()).map_err(|_| {
+            PulseError::invalid_argument(format!(
+                "{} record encodings are too many to store in a pulses.bin file",
+                record_types.len()
+            ))
+        })?;
+
+        // Find the data offset, rounded up to the next multiple of 16
+        // We do this so that each aperture header and pulse record will be aligned to an integer multiple of its length
+        let data_offset = {
+            let offset =
+                (FILE_HEADER_SIZE_FULL + record_types.len() * 4 + metadata_length as usize) as u64;
+            (offset + 15) & !15 // Align to 16-byte boundary
+        };
+
+        // The number of reads and the index offset are not known until all apertures have
+        // been written, so they are filled in when the writer is finished
+        let header = PulseFileHeader {
+            magic: BINARY_PULSE_FILE_MAGIC,
+            version: PULSE_FILE_VERSION,
+            num_reads: 0,
+            metadata_length,
+            encoding_record_type: 1,
+            encoding_record_size: 4,
+            num_encoding_records,
+            record_header_size: READ_HEADER_SIZE as u32,
+            record_size: PULSE_SIZE as u32,
+            data_offset,
+            index_offset: 0,
+        };
+
+        // Open a temporary file next to the new file, so that it can be renamed into place,
+        // and write everything preceding the data section
+        let mut file = BufWriter::with_capacity(BUFFER_SIZE, create_temp_file(&file_name)?);
+        header.write_all(&mut file)?;
+        for record_type in record_types {
+            record_type.write_all(&mut file)?;
+        }
+        file.write_all(raw_metadata.as_bytes())?;
+
+        // Write zeros until we hit position data_offset
+        let stream_position = file.stream_position()?;
+        if stream_position > data_offset {
+            return Err(anyhow!("Stream position exceeds data offset"));
+        }
+        let zero_buffer: Vec<u8> = vec![0; (data_offset - stream_position) as usize];
+        file.write_all(&zero_buffer)?;
+
+        Ok(PulseFileWriter {
+            file,
+            file_name,
+            overwrite,
+            header,
+            offset: data_offset,
+            index: Vec::new(),
+            copy_buffer: Vec::new(),
+        })
+    }
+
+    /// Appends a single aperture to the data section
+    ///
+    /// Writes the aperture header followed by its records. The position and index of the
+    /// aperture are taken from the provided header, while its number of records is taken
+    /// from the length of `records`. Apertures may be written in any order, but each
+    /// aperture index may only be written once. Returns `PulseError::InvalidArgument` if
+    /// there are too many records to count in an aperture header.
+    pub fn write_aperture(
+        &mut self,
+        aperture_header: &ApertureHeader,
+        records: &[RawRecord],
+    ) -> Result<()> {
+        let num_pulses = u32::try_from(records.len()).map_err(|_| {
+            PulseError::invalid_argument(format!(
+                "{} records are too many to store in a single aperture",
+                records.len()
+            ))
+        })?;
+        let new_aperture_header = ApertureHeader {
+            num_pulses,
+            byte_loc: self.offset,
+            ..*aperture_header
+        };
+        new_aperture_header.write_all(&mut self.file)?;
+        for record in records {
+            record.write_all(&mut self.file)?;
+        }
+
+        self.index.push((new_aperture_header.well_id, self.offset));
+        self.offset += (READ_HEADER_SIZE + records.len() * PULSE_SIZE) as u64;
+        Ok(())
+    }
+
+    /// Appends a single aperture, copying its records from another pulses.bin file
+    ///
+    /// `aperture_header` is the header of an aperture in `source`, as returned by
+    /// `get_aperture_header`, with its position and index changed to those it should have in
+    /// the new file. Its records are copied in chunks without being decoded, so memory
+    /// use does not depend on the size of the aperture.
+    pub fn copy_aperture(
+        &mut self,
+        source: &PulseReader,
+        aperture_header: &ApertureHeader,
+    ) -> Result<()> {
+        let new_aperture_header = ApertureHeader {
+            byte_loc: self.offset,
+            ..*aperture_header
+        };
+        new_aperture_header.write_all(&mut self.file)?;
+
+        self.copy_buffer.resize(BUFFER_SIZE, 0);
+        let mut read_offset = aperture_header.byte_loc + READ_HEADER_SIZE as u64;
+        let mut remaining_bytes = aperture_header.num_pulses as usize * PULSE_SIZE;
+        while remaining_bytes > 0 {
+            let chunk = &mut self.copy_buffer[..remaining_bytes.min(BUFFER_SIZE)];
+            source.backend.read_exact_at(chunk, read_offset)?;
+            self.file.write_all(chunk)?;
+            read_offset += chunk.len() as u64;
+            remaining_bytes -= chunk.len();
+        }
+
+        self.index.push((new_aperture_header.well_id, self.offset));
+        self.offset += (READ_HEADER_SIZE + aperture_header.num_pulses as usize * PULSE_SIZE) as u64;
+        Ok(())
+    }
+
+    /// Writes the aperture index and finalizes the file header, then moves the file into place
+    ///
+    /// The index is sorted by aperture index, as required by `PulseFileIndex`. Returns an
+    /// error if no apertures were written or if an aperture index was written more than once.
+    /// The file is synced to disk before it is renamed to its final name, and the rename
+    /// fails if the file was created in the meantime and `overwrite` is `OverwritePolicy::Fail`.
+    pub fn finish(mut self) -> Result<()> {
+        if self.index.is_empty() {
+            return Err(anyhow!("Cannot write a pulse file without any apertures"));
+        }
+        self.index.sort_unstable();
+        if let Some(window) = self.index.windows(2).find(|w| w[0].0 == w[1].0) {
+            return Err(anyhow!(
+                "Aperture index {} was written more than once",
+                window[0].0
+            ));
+        }
+
+        // Write the index magic, followed by the aperture index
+        self.file.write_all(&INDEX_SECTION_MAGIC.to_le_bytes())?;
+        for (ap, byte_loc) in self.index.iter() {
+            self.file.write_all(&ap.to_le_bytes())?;
+            self.file.write_all(&byte_loc.to_le_bytes())?;
+        }
+
+        // Rewrite the header now that the number of reads and the index offset are known
+        let header = PulseFileHeader {
+            num_reads: self.index.len() as u64,
+            index_offset: self.offset,
+            ..self.header
+        };
+        self.file.seek(SeekFrom::Start(0))?;
+        header.write_all(&mut self.file)?;
+        let temp_file = self.file.into_inner().map_err(|e| e.into_error())?;
+        persist(temp_file, &self.file_name, self.overwrite)
+    }
+}
+
+/// Atomically writes `contents` to a file, in the same way as `PulseFileWriter`
+///
+/// The contents are written to a temporary file in the same directory, which is synced to
+/// disk and renamed into place, so the file is either completely written or not at all.
+/// Returns an error if the file already exists and `overwrite` is `OverwritePolicy::Fail`.
+pub fn write_file_atomically<P: AsRef<Path>>(
+    file_name: P,
+    contents: &[u8],
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    let file_name = file_name.as_ref();
+    overwrite.check(file_name)?;
+    let mut temp_file = create_temp_file(file_name)?;
+    temp_file.write_all(contents).map_err(PulseError::Io)?;
+    persist(temp_file, file_name, overwrite)
+}
+
+/// Creates a temporary file in the directory of `file_name`, so that it can be renamed into place
+///
+/// Temporary files are normally only readable by their owner, so the file is created with
+/// the same permissions as `File::create` would give it, i.e. `0o666` less the umask.
+fn create_temp_file(file_name: &Path) -> Result<NamedTempFile, PulseError> {
+    let dir = match file_name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".pulses-").suffix(".tmp");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    builder.tempfile_in(dir).map_err(PulseError::Io)
+}
+
+/// Syncs a completely written temporary file to disk and renames it to `file_name`
+///
+/// A file that is replaced keeps its permissions.
+fn persist(temp_file: NamedTempFile, file_name: &Path, overwrite: OverwritePolicy) -> Result<()> {
+    if overwrite == OverwritePolicy::Overwrite
+        && let Ok(metadata) = std::fs::metadata(file_name)
+    {
+        temp_file
+            .as_file()
+            .set_permissions(metadata.permissions())
+            .map_err(PulseError::Io)?;
+    }
+    temp_file.as_file().sync_all().map_err(PulseError::Io)?;
+
+    // Rename the file into place, and sync the directory so that the rename is durable
+    let persisted = match overwrite {
+        OverwritePolicy::Overwrite => temp_file.persist(file_name),
+        OverwritePolicy::Fail => temp_file.persist_noclobber(file_name),
+    };
+    persisted.map_err(|e| match e.error.kind() {
+        ErrorKind::AlreadyExists => already_exists(file_name),
+        _ => PulseError::Io(e.error),
+    })?;
+    sync_parent_dir(file_name)
+}
+
+/// Syncs the directory containing `file_name`, so that a rename into it is durable
+#[cfg(unix)]
+fn sync_parent_dir(file_name: &Path) -> Result<()> {
+    let dir = match file_name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(PulseError::Io)?;
+    Ok(())
+}
+
+/// Directories can't be synced on Windows, where renames are made durable by the file system
+#[cfg(not(unix))]
+fn sync_parent_dir(_file_name: &Path) -> Result<()> {
+    Ok(())
+}